@@ -0,0 +1,254 @@
+/*
+    What the bootloader tells the kernel, independent of which one it was
+
+    - the BIOS bootloader (bootloader 0.9) hands over a BootInfo with a
+      physical_memory_offset and its own MemoryMap type, and leaves the
+      screen in VGA text mode
+    - a UEFI boot has a different memory map layout and usually a linear
+      graphics framebuffer instead of VGA text mode
+    - BootEnvironment is built from whichever one booted us and gives the
+      rest of the kernel one set of accessors, so kern_main doesn't care
+    - only the BIOS variant has a constructor that reads a real BootInfo
+      for now, anything else builds its MemRegion slice and calls new()
+*/
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use bootloader::BootInfo;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    // free for the frame allocator
+    Usable,
+    // firmware, hardware, or already taken by the bootloader/kernel
+    Reserved,
+}
+
+// physical address range [start, end), page aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+}
+
+impl From<&MemoryRegion> for MemRegion {
+    fn from(region: &MemoryRegion) -> Self {
+        let kind = match region.region_type {
+            MemoryRegionType::Usable => RegionKind::Usable,
+            _ => RegionKind::Reserved,
+        };
+        MemRegion {
+            start: region.range.start_addr(),
+            end: region.range.end_addr(),
+            kind,
+        }
+    }
+}
+
+// a linear 32 bits per pixel framebuffer the firmware left the screen in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    // virtual address of the first pixel
+    pub addr: VirtAddr,
+    pub width: usize,
+    pub height: usize,
+    // pixels per scanline
+    pub stride: usize,
+}
+
+impl FramebufferInfo {
+    /*
+        the framebuffer as something TextConsole can draw on
+
+        unsafe since the caller has to guarantee `addr` really is mapped to
+        `stride * height` pixels and nothing else is using them
+    */
+    pub unsafe fn framebuffer(&self) -> crate::framebuffer::Framebuffer<'static> {
+        let pixels = core::slice::from_raw_parts_mut(
+            self.addr.as_mut_ptr::<u32>(),
+            self.stride * self.height,
+        );
+        crate::framebuffer::Framebuffer::new(pixels, self.width, self.height, self.stride)
+    }
+}
+
+// the memory map in whatever shape the bootloader gave it to us
+#[derive(Clone, Copy)]
+pub enum MemoryRegions<'a> {
+    Bios(&'a MemoryMap),
+    Uniform(&'a [MemRegion]),
+}
+
+impl<'a> MemoryRegions<'a> {
+    pub fn iter(&self) -> Regions<'a> {
+        Regions {
+            regions: *self,
+            idx: 0,
+        }
+    }
+}
+
+impl<'a> From<&'a MemoryMap> for MemoryRegions<'a> {
+    fn from(map: &'a MemoryMap) -> Self {
+        MemoryRegions::Bios(map)
+    }
+}
+
+impl<'a> From<&'a [MemRegion]> for MemoryRegions<'a> {
+    fn from(regions: &'a [MemRegion]) -> Self {
+        MemoryRegions::Uniform(regions)
+    }
+}
+
+// iterator over MemoryRegions, converts BIOS regions as it goes
+pub struct Regions<'a> {
+    regions: MemoryRegions<'a>,
+    idx: usize,
+}
+
+impl Iterator for Regions<'_> {
+    type Item = MemRegion;
+
+    fn next(&mut self) -> Option<MemRegion> {
+        let region = match self.regions {
+            MemoryRegions::Bios(map) => map.get(self.idx).map(MemRegion::from),
+            MemoryRegions::Uniform(regions) => regions.get(self.idx).copied(),
+        }?;
+        self.idx += 1;
+        Some(region)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct BootEnvironment {
+    phys_mem_offset: VirtAddr,
+    regions: MemoryRegions<'static>,
+    framebuffer: Option<FramebufferInfo>,
+}
+
+impl BootEnvironment {
+    pub fn new(
+        phys_mem_offset: VirtAddr,
+        regions: &'static [MemRegion],
+        framebuffer: Option<FramebufferInfo>,
+    ) -> Self {
+        BootEnvironment {
+            phys_mem_offset,
+            regions: MemoryRegions::Uniform(regions),
+            framebuffer,
+        }
+    }
+
+    // BIOS boot, the screen is in VGA text mode so there's no framebuffer
+    pub fn from_bios(boot_info: &'static BootInfo) -> Self {
+        BootEnvironment {
+            phys_mem_offset: VirtAddr::new(boot_info.physical_memory_offset),
+            regions: MemoryRegions::Bios(&boot_info.memory_map),
+            framebuffer: None,
+        }
+    }
+
+    // virtual address all of physical memory is mapped at
+    pub fn physical_memory_offset(&self) -> VirtAddr {
+        self.phys_mem_offset
+    }
+
+    pub fn memory_regions(&self) -> MemoryRegions<'static> {
+        self.regions
+    }
+
+    pub fn framebuffer(&self) -> Option<FramebufferInfo> {
+        self.framebuffer
+    }
+
+    // a BIOS boot is left in VGA text mode, a bootloader that hands over a
+    // framebuffer has put the screen in a graphics mode
+    pub fn vga_text_mode(&self) -> bool {
+        self.framebuffer.is_none()
+    }
+
+    /*
+        send print!/println! to the framebuffer if there's no VGA text mode
+
+        unsafe since every call makes a new TextConsole over the framebuffer's
+        pixels: the caller has to guarantee it's called once (before anything
+        prints), and that the framebuffer (if any) really is mapped where it
+        says, see FramebufferInfo::framebuffer()
+    */
+    pub unsafe fn init_console(&self) {
+        use crate::framebuffer::{default_font, set_console, TextConsole};
+
+        crate::vga_buf::set_vga_present(self.vga_text_mode());
+        if let Some(fb) = self.framebuffer {
+            let fb = fb.framebuffer();
+            set_console(TextConsole::new(fb, default_font(), 0x00ff_ffff, 0));
+        }
+    }
+}
+
+#[cfg(test)]
+static TEST_REGIONS: [MemRegion; 3] = [
+    MemRegion {
+        start: 0x0,
+        end: 0x1000,
+        kind: RegionKind::Reserved,
+    },
+    MemRegion {
+        start: 0x1000,
+        end: 0x5000,
+        kind: RegionKind::Usable,
+    },
+    MemRegion {
+        start: 0x5000,
+        end: 0x8000,
+        kind: RegionKind::Reserved,
+    },
+];
+
+// test that a BootEnvironment from a synthetic map answers like a real one
+#[test_case]
+fn test_boot_environment_accessors() {
+    use crate::mem::{usable_frame_count, BootInfoFrameAllocator};
+    use x86_64::structures::paging::FrameAllocator;
+    use x86_64::PhysAddr;
+
+    let fb = FramebufferInfo {
+        addr: VirtAddr::new(0xb000_0000),
+        width: 640,
+        height: 480,
+        stride: 648,
+    };
+    let env = BootEnvironment::new(VirtAddr::new(0x1000_0000_0000), &TEST_REGIONS, Some(fb));
+    assert_eq!(
+        env.physical_memory_offset(),
+        VirtAddr::new(0x1000_0000_0000)
+    );
+    assert_eq!(env.framebuffer(), Some(fb));
+    assert!(env.memory_regions().iter().eq(TEST_REGIONS.iter().copied()));
+    assert_eq!(usable_frame_count(env.memory_regions()), 4);
+
+    // the frame allocator only hands out the USABLE region
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::from_regions(env.memory_regions()) };
+    let first = frame_alloc.allocate_frame().unwrap();
+    assert_eq!(first.start_address(), PhysAddr::new(0x1000));
+    assert_eq!(frame_alloc.frames_available(), 3);
+}
+
+// test that BIOS memory map regions convert to the same thing
+#[test_case]
+fn test_bios_regions_convert() {
+    use bootloader::bootinfo::FrameRange;
+
+    let mut map = MemoryMap::new();
+    map.add_region(MemoryRegion {
+        range: FrameRange::new(0x1000, 0x5000),
+        region_type: MemoryRegionType::Usable,
+    });
+    map.add_region(MemoryRegion {
+        range: FrameRange::new(0x5000, 0x8000),
+        region_type: MemoryRegionType::Kernel,
+    });
+    let regions = MemoryRegions::from(&map);
+    assert!(regions.iter().eq(TEST_REGIONS[1..].iter().copied()));
+}
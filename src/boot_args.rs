@@ -0,0 +1,83 @@
+/*
+    Kernel boot arguments
+
+    - whitespace separated `key=value` pairs e.g. "loglevel=debug vga=80x50"
+        - a key without `=` is a flag and has an empty value
+        - if a key appears more than once the first one wins
+    - the BIOS bootloader doesn't hand us a command line in BootInfo so for
+      now it comes from the KERNEL_CMDLINE env var at build time, or whatever
+      string is passed to init()
+    - parsing never allocates, values are just slices into the original
+      &'static str so this is safe to use before init_heap()
+*/
+
+use spin::Once;
+
+// command line used if init() is never called
+pub const DEFAULT_CMDLINE: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+static BOOT_ARGS: Once<BootArgs<'static>> = Once::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootArgs<'a> {
+    cmdline: &'a str,
+}
+
+impl<'a> BootArgs<'a> {
+    pub const fn new(cmdline: &'a str) -> Self {
+        BootArgs { cmdline }
+    }
+
+    // iterate over each (key, value) pair in the command line
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.cmdline
+            .split_whitespace()
+            .map(|arg| match arg.find('=') {
+                Some(idx) => (&arg[..idx], &arg[idx + 1..]),
+                None => (arg, ""),
+            })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.iter().find(|&(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+// set the global boot arguments, only the first call has any effect
+pub fn init(cmdline: &'static str) -> &'static BootArgs<'static> {
+    BOOT_ARGS.call_once(|| BootArgs::new(cmdline))
+}
+
+pub fn args() -> &'static BootArgs<'static> {
+    init(DEFAULT_CMDLINE)
+}
+
+// look up a boot argument from the global command line
+pub fn get(key: &str) -> Option<&'static str> {
+    args().get(key)
+}
+
+#[test_case]
+fn test_boot_args_missing_key() {
+    let args = BootArgs::new("loglevel=debug");
+    assert_eq!(args.get("vga"), None);
+    assert_eq!(BootArgs::new("").get("loglevel"), None);
+}
+
+#[test_case]
+fn test_boot_args_empty_value() {
+    let args = BootArgs::new("loglevel= quiet");
+    assert_eq!(args.get("loglevel"), Some(""));
+    assert_eq!(args.get("quiet"), Some(""));
+}
+
+#[test_case]
+fn test_boot_args_multiple() {
+    let args = BootArgs::new("  loglevel=debug   vga=80x50 loglevel=info ");
+    assert_eq!(args.get("loglevel"), Some("debug"));
+    assert_eq!(args.get("vga"), Some("80x50"));
+    assert_eq!(args.iter().count(), 3);
+}
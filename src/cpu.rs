@@ -0,0 +1,60 @@
+use x86_64::registers::model_specific::Msr;
+
+/*
+    Model specific registers (MSRs)
+
+    - 64-bit registers addressed by a u32 and accessed with rdmsr/wrmsr,
+      only from ring 0
+    - reading or writing one the CPU doesn't have raises #GP, as does
+      writing a reserved bit, so the raw accessors are unsafe
+    - the convenience readers only cover MSRs every x86_64 CPU has
+*/
+
+pub const IA32_TSC: u32 = 0x10;
+pub const IA32_APIC_BASE: u32 = 0x1b;
+pub const IA32_EFER: u32 = 0xc000_0080;
+// syscall/sysret segments, entry point and the rflags bits cleared on entry
+pub const IA32_STAR: u32 = 0xc000_0081;
+pub const IA32_LSTAR: u32 = 0xc000_0082;
+pub const IA32_FMASK: u32 = 0xc000_0084;
+
+// caller has to make sure `msr` exists on this CPU
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    Msr::new(msr).read()
+}
+
+// caller has to make sure `msr` exists, `value` is valid for it, and that
+// changing it doesn't break anything relying on the old value
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    Msr::new(msr).write(value)
+}
+
+// time stamp counter, same value rdtsc gives
+pub fn tsc() -> u64 {
+    unsafe { read_msr(IA32_TSC) }
+}
+
+// physical base of the local APIC's registers plus the enable (bit 11) and
+// bootstrap processor (bit 8) flags
+pub fn apic_base() -> u64 {
+    unsafe { read_msr(IA32_APIC_BASE) }
+}
+
+// extended feature enables: long mode, NX, syscall
+pub fn efer() -> u64 {
+    unsafe { read_msr(IA32_EFER) }
+}
+
+#[test_case]
+fn test_tsc_increases() {
+    let first = tsc();
+    let second = tsc();
+    assert!(second > first, "tsc went from {} to {}", first, second);
+}
+
+#[test_case]
+fn test_efer_long_mode() {
+    // LME and LMA, we wouldn't be running otherwise
+    let long_mode = (1 << 8) | (1 << 10);
+    assert_eq!(efer() & long_mode, long_mode);
+}
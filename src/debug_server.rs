@@ -0,0 +1,132 @@
+use crate::{serial_print, task::serial::SerialStream};
+use alloc::string::String;
+use core::fmt::{self, Write};
+use futures_util::stream::StreamExt;
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, OffsetPageTable, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+/*
+    Debug server
+
+    A tiny line based protocol over COM1 so a host can inspect kernel memory:
+
+        R <addr> <len>\n        read <len> bytes starting at <addr>
+        W <addr> <hexbytes>\n   write the hex encoded bytes starting at <addr>
+
+    - <addr> is hex (the 0x prefix is optional), <len> is decimal
+    - replies are a single line:
+        OK <hexbytes>\n   for a read
+        OK\n              for a write
+        ERR <reason>\n    otherwise
+    - every page in the range is checked with the page tables first so a bad
+      request gets an ERR rather than page faulting the kernel, writes also
+      need the pages to be WRITABLE
+*/
+
+// longest read we'll answer and longest line we'll buffer
+const MAX_READ: usize = 4096;
+const MAX_LINE: usize = 2 * MAX_READ + 32;
+
+// serve requests forever, takes ownership of the mapper since it has to
+// outlive kern_main's stack frame
+pub async fn serve(mapper: OffsetPageTable<'static>) {
+    let mut input = SerialStream::new();
+    let mut line = String::new();
+
+    while let Some(byte) = input.next().await {
+        match byte {
+            b'\r' | b'\n' => {
+                if !line.is_empty() {
+                    let mut reply = String::new();
+                    let _ = handle_command(&line, &mapper, &mut reply);
+                    serial_print!("{}", reply);
+                    line.clear();
+                }
+            }
+            _ if line.len() >= MAX_LINE => {
+                serial_print!("ERR line too long\n");
+                line.clear();
+            }
+            byte => line.push(byte as char),
+        }
+    }
+}
+
+// parse and run a single command, writing the reply line to `out`
+pub fn handle_command(line: &str, mapper: &impl Translate, out: &mut impl Write) -> fmt::Result {
+    let mut args = line.split_whitespace();
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (Some("R"), Some(addr), Some(len), None) => {
+            let (addr, len) = match (parse_addr(addr), len.parse::<usize>()) {
+                (Some(addr), Ok(len)) if len > 0 && len <= MAX_READ => (addr, len),
+                _ => return writeln!(out, "ERR bad arguments"),
+            };
+            if !range_accessible(mapper, addr, len, false) {
+                return writeln!(out, "ERR unmapped");
+            }
+            write!(out, "OK ")?;
+            for i in 0..len {
+                let byte = unsafe { core::ptr::read_volatile((addr + i as u64) as *const u8) };
+                write!(out, "{:02x}", byte)?;
+            }
+            writeln!(out)
+        }
+        (Some("W"), Some(addr), Some(hex), None) => {
+            let addr = match parse_addr(addr) {
+                Some(addr) if hex.len() % 2 == 0 => addr,
+                _ => return writeln!(out, "ERR bad arguments"),
+            };
+            // validate every byte before writing any of them
+            if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return writeln!(out, "ERR bad arguments");
+            }
+            let len = hex.len() / 2;
+            if !range_accessible(mapper, addr, len, true) {
+                return writeln!(out, "ERR unmapped");
+            }
+            for i in 0..len {
+                let byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+                unsafe { core::ptr::write_volatile((addr + i as u64) as *mut u8, byte) };
+            }
+            writeln!(out, "OK")
+        }
+        _ => writeln!(out, "ERR unknown command"),
+    }
+}
+
+fn parse_addr(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+// true if every page touched by addr..addr+len is mapped (and writable if
+// `write` is set)
+fn range_accessible(mapper: &impl Translate, addr: u64, len: usize, write: bool) -> bool {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = addr & !0xfff;
+    while page < end {
+        let virt = match VirtAddr::try_new(page) {
+            Ok(virt) => virt,
+            Err(_) => return false,
+        };
+        match mapper.translate(virt) {
+            TranslateResult::Mapped { flags, .. } => {
+                if write && !flags.contains(PageTableFlags::WRITABLE) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+        page = match page.checked_add(0x1000) {
+            Some(next) => next,
+            None => return false,
+        };
+    }
+    true
+}
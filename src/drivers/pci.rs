@@ -0,0 +1,149 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/*
+    PCI configuration space (legacy "mechanism #1")
+
+    - every PCI function has 256 bytes of configuration registers which are
+      reached through 2 I/O ports:
+        - 0xCF8 (CONFIG_ADDRESS): write which register we want
+        - 0xCFC (CONFIG_DATA): then read/write that register 32 bits at a time
+    - CONFIG_ADDRESS layout:
+
+        31 | 30-24    | 23-16 | 15-11  | 10-8     | 7-0
+      -----|----------|-------|--------|----------|------------------------
+      en   | reserved | bus   | device | function | register offset (& 0xfc)
+
+    - a bus has up to 32 devices, each with up to 8 functions, but functions
+      1-7 only exist if the device sets the multifunction bit (bit 7) of its
+      header type
+    - an absent device/function reads back a vendor ID of 0xFFFF
+*/
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const VENDOR_NONE: u16 = 0xffff;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+// header type 0x0 is a general device with 6 BARs, 0x1 a PCI-to-PCI bridge
+// with 2, anything else (e.g. CardBus bridges) we don't read BARs for
+const HEADER_TYPE_GENERAL: u8 = 0x0;
+const HEADER_TYPE_BRIDGE: u8 = 0x1;
+
+// the address/data port pair has to be used as one unit
+struct ConfigSpace {
+    address: Port<u32>,
+    data: Port<u32>,
+}
+
+static CONFIG: Mutex<ConfigSpace> = Mutex::new(ConfigSpace {
+    address: Port::new(CONFIG_ADDRESS),
+    data: Port::new(CONFIG_DATA),
+});
+
+// read the 32-bit register containing `offset` for the given function
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    use x86_64::instructions::interrupts;
+
+    let address = 0x8000_0000
+        | (bus as u32) << 16
+        | (device as u32) << 11
+        | (function as u32) << 8
+        | (offset & 0xfc) as u32;
+
+    // an interrupt handler touching PCI in between our write and read would
+    // change which register we end up reading
+    interrupts::without_interrupts(|| {
+        let mut config = CONFIG.lock();
+        unsafe {
+            config.address.write(address);
+            config.data.read()
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    // raw Base Address Registers, unused ones are left as 0
+    pub bars: [u32; 6],
+}
+
+impl PciDevice {
+    // read the function's header, None if nothing is there
+    fn probe(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+        let id = read_config(bus, device, function, 0x00);
+        let vendor_id = id as u16;
+        if vendor_id == VENDOR_NONE {
+            return None;
+        }
+
+        let class_reg = read_config(bus, device, function, 0x08);
+        let header_reg = read_config(bus, device, function, 0x0c);
+        let header_type = (header_reg >> 16) as u8;
+
+        let bar_count = match header_type & !HEADER_MULTIFUNCTION {
+            HEADER_TYPE_GENERAL => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut bars = [0; 6];
+        for (i, bar) in bars.iter_mut().enumerate().take(bar_count) {
+            *bar = read_config(bus, device, function, 0x10 + 4 * i as u8);
+        }
+
+        Some(PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class_reg >> 24) as u8,
+            subclass: (class_reg >> 16) as u8,
+            prog_if: (class_reg >> 8) as u8,
+            header_type,
+            bars,
+        })
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & HEADER_MULTIFUNCTION != 0
+    }
+}
+
+// brute force scan every bus/device/function, requires the heap
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let func0 = match PciDevice::probe(bus, device, 0) {
+                Some(func0) => func0,
+                None => continue,
+            };
+            let multifunction = func0.is_multifunction();
+            devices.push(func0);
+
+            if multifunction {
+                devices.extend((1..8u8).filter_map(|func| PciDevice::probe(bus, device, func)));
+            }
+        }
+    }
+    devices
+}
+
+// first device with the given class and subclass e.g. (0x01, 0x01) for an
+// IDE controller
+pub fn find(class: u8, subclass: u8) -> Option<PciDevice> {
+    enumerate()
+        .into_iter()
+        .find(|dev| dev.class == class && dev.subclass == subclass)
+}
@@ -0,0 +1,86 @@
+use crate::{gdt, interrupts, serial_println};
+use core::fmt;
+use x86_64::instructions::segmentation::{Segment, CS};
+
+/*
+    Sanity checks for surviving a fault (fault_checks feature)
+
+    - a fault the CPU can't deliver turns into a double fault, and a double
+      fault it can't deliver into a triple fault, i.e. QEMU silently resets
+    - delivering the double fault needs the GDT with our code segment, the
+      TSS with the IST stack, and the IDT with an entry that uses both, these
+      check each of those against what the CPU actually has loaded
+    - meant for catching init ordering bugs (e.g. building the IDT before
+      the GDT is loaded so its entries hold the bootloader's CS)
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    GdtNotLoaded,
+    TssNotLoaded,
+    NoDoubleFaultStack,
+    IdtNotLoaded,
+    // the double fault entry doesn't switch to an IST stack
+    DoubleFaultNoIst,
+    // the double fault entry's code segment isn't the kernel's
+    StaleCodeSelector,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Problem::GdtNotLoaded => "GDTR doesn't point at the kernel GDT",
+            Problem::TssNotLoaded => "task register doesn't hold the kernel TSS",
+            Problem::NoDoubleFaultStack => "TSS has no double fault IST stack",
+            Problem::IdtNotLoaded => "IDTR doesn't point at the kernel IDT",
+            Problem::DoubleFaultNoIst => "double fault IDT entry doesn't use the IST",
+            Problem::StaleCodeSelector => {
+                "double fault IDT entry has a stale code selector (IDT built before GDT?)"
+            }
+        };
+        f.write_str(msg)
+    }
+}
+
+fn has_problem(problem: Problem) -> bool {
+    let double_fault = interrupts::ExceptionVector::DoubleFault.as_usize();
+    match problem {
+        Problem::GdtNotLoaded => !gdt::is_loaded(),
+        Problem::TssNotLoaded => !gdt::tss_loaded(),
+        Problem::NoDoubleFaultStack => gdt::double_fault_stack_top().is_null(),
+        Problem::IdtNotLoaded => !interrupts::is_loaded(),
+        // only look at the entries once the IDT is in use, before that
+        // touching it would build it then and there
+        Problem::DoubleFaultNoIst => {
+            interrupts::is_loaded() && interrupts::IDT.stack_idx(double_fault) == 0
+        }
+        Problem::StaleCodeSelector => {
+            interrupts::is_loaded()
+                && (interrupts::IDT.selector(double_fault) != gdt::code_selector()
+                    || CS::get_reg() != gdt::code_selector())
+        }
+    }
+}
+
+const ALL: [Problem; 6] = [
+    Problem::GdtNotLoaded,
+    Problem::TssNotLoaded,
+    Problem::NoDoubleFaultStack,
+    Problem::IdtNotLoaded,
+    Problem::DoubleFaultNoIst,
+    Problem::StaleCodeSelector,
+];
+
+// every check that currently fails, in the order above
+pub fn problems() -> impl Iterator<Item = Problem> {
+    ALL.iter().copied().filter(|&problem| has_problem(problem))
+}
+
+// log each problem over serial, returns how many there were
+pub fn report() -> usize {
+    let mut count = 0;
+    for problem in problems() {
+        serial_println!("FAULT CHECK: {}", problem);
+        count += 1;
+    }
+    count
+}
@@ -0,0 +1,487 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/*
+    Pixel framebuffer text output
+
+    - the VGA text buffer only exists in text mode, once the screen is in
+      a graphics mode characters have to be drawn pixel by pixel from a
+      bitmap font, that's what TextConsole does
+    - Framebuffer wraps whatever linear 32 bits per pixel buffer the
+      screen is using (`stride` is pixels per scanline, which can be more
+      than the visible width)
+    - bootloader 0.9 doesn't set up a graphics mode, so for now the
+      buffer has to come from somewhere else (or be plain memory)
+*/
+
+// default console font, 8x8 glyphs for printable ASCII
+static DEFAULT_FONT: &[u8] = include_bytes!("font/default8x8.psf");
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    BadMagic,
+    // shorter than its header says the glyphs are
+    Truncated,
+}
+
+/*
+    A PC Screen Font (PSF)
+
+    - PSF1: 4 byte header (magic, mode, glyph height), always 8 pixels
+      wide, 256 or 512 glyphs
+    - PSF2: 32 byte header of little endian u32s (magic, version, header
+      size, flags, glyph count, bytes per glyph, height, width)
+    - each glyph is `height` rows of ceil(width / 8) bytes, the most
+      significant bit is the leftmost pixel
+    - any unicode table is ignored, glyphs are looked up by byte value
+*/
+#[derive(Clone, Copy)]
+pub struct Font<'a> {
+    glyphs: &'a [u8],
+    count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Font<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Font<'a>, FontError> {
+        let (header_size, count, bytes_per_glyph, width, height) =
+            if bytes.len() >= 4 && bytes[..2] == PSF1_MAGIC {
+                let count = if bytes[2] & PSF1_MODE_512 != 0 {
+                    512
+                } else {
+                    256
+                };
+                let height = bytes[3] as usize;
+                (4, count, height, 8, height)
+            } else if bytes.len() >= 32 && bytes[..4] == PSF2_MAGIC {
+                let field = |i: usize| {
+                    let mut le = [0; 4];
+                    le.copy_from_slice(&bytes[i * 4..i * 4 + 4]);
+                    u32::from_le_bytes(le) as usize
+                };
+                (field(2), field(4), field(5), field(7), field(6))
+            } else {
+                return Err(FontError::BadMagic);
+            };
+
+        let size = count
+            .checked_mul(bytes_per_glyph)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(FontError::Truncated)?;
+        if bytes.len() < size || bytes_per_glyph < height * width.div_ceil(8) {
+            return Err(FontError::Truncated);
+        }
+        Ok(Font {
+            glyphs: &bytes[header_size..size],
+            count,
+            bytes_per_glyph,
+            width,
+            height,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // bitmap for `c`, '?' for anything past the end of the font
+    pub fn glyph(&self, c: char) -> &'a [u8] {
+        let idx = match c as usize {
+            idx if idx < self.count => idx,
+            _ => b'?' as usize,
+        };
+        &self.glyphs[idx * self.bytes_per_glyph..(idx + 1) * self.bytes_per_glyph]
+    }
+
+    // whether pixel (x, y) of a glyph bitmap is set
+    fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let row = &glyph[y * self.width.div_ceil(8)..];
+        row[x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+pub fn default_font() -> Font<'static> {
+    Font::parse(DEFAULT_FONT).expect("built in font is broken")
+}
+
+pub struct Framebuffer<'a> {
+    pixels: &'a mut [u32],
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+impl<'a> Framebuffer<'a> {
+    // panics if `pixels` doesn't hold `height` scanlines of `stride` pixels
+    pub fn new(pixels: &'a mut [u32], width: usize, height: usize, stride: usize) -> Self {
+        assert!(width <= stride, "framebuffer wider than its stride");
+        assert!(pixels.len() >= stride * height, "framebuffer too small");
+        Framebuffer {
+            pixels,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // panics if (x, y) is off the screen, like the array would
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        assert!(x < self.width && y < self.height);
+        self.pixels[y * self.stride + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u32) {
+        assert!(x < self.width && y < self.height);
+        self.pixels[y * self.stride + x] = color;
+    }
+
+    // fill scanlines [start, end) with one color
+    fn fill_rows(&mut self, start: usize, end: usize, color: u32) {
+        for y in start..end {
+            let line = y * self.stride;
+            self.pixels[line..line + self.width].fill(color);
+        }
+    }
+
+    // move everything up `rows` scanlines, the bottom ones get `color`
+    fn scroll_up(&mut self, rows: usize, color: u32) {
+        let rows = rows.min(self.height);
+        self.pixels
+            .copy_within(rows * self.stride..self.height * self.stride, 0);
+        self.fill_rows(self.height - rows, self.height, color);
+    }
+}
+
+/*
+    Text on a Framebuffer, behaves like vga_buf::Writer
+
+    - the screen is a grid of font-sized cells, characters go left to right
+      and wrap onto the next row
+    - a newline on the bottom row scrolls everything up by one row of text
+    - no cursor, colors are whole 32-bit pixel values
+*/
+pub struct TextConsole<'a> {
+    fb: Framebuffer<'a>,
+    font: Font<'static>,
+    col: usize,
+    row: usize,
+    fg: u32,
+    bg: u32,
+}
+
+impl<'a> TextConsole<'a> {
+    // clears the screen to `bg`
+    pub fn new(mut fb: Framebuffer<'a>, font: Font<'static>, fg: u32, bg: u32) -> Self {
+        let height = fb.height();
+        fb.fill_rows(0, height, bg);
+        TextConsole {
+            fb,
+            font,
+            col: 0,
+            row: 0,
+            fg,
+            bg,
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.fb.width() / self.font.width()
+    }
+
+    pub fn rows(&self) -> usize {
+        self.fb.height() / self.font.height()
+    }
+
+    // where on the current row the next character goes
+    pub fn column(&self) -> usize {
+        self.col
+    }
+
+    // move within the current row, like vga_buf::Writer::set_column()
+    pub fn set_column(&mut self, col: usize) {
+        assert!(col < self.columns(), "column {} is off the screen", col);
+        self.col = col;
+    }
+
+    pub fn set_colors(&mut self, fg: u32, bg: u32) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer<'a> {
+        &self.fb
+    }
+
+    // draw `c` with its top left corner at pixel (x, y), clipped to the screen
+    pub fn draw_glyph(&mut self, x: usize, y: usize, c: char, fg: u32, bg: u32) {
+        let glyph = self.font.glyph(c);
+        let width = self.font.width().min(self.fb.width().saturating_sub(x));
+        let height = self.font.height().min(self.fb.height().saturating_sub(y));
+        for gy in 0..height {
+            for gx in 0..width {
+                let color = if self.font.is_set(glyph, gx, gy) {
+                    fg
+                } else {
+                    bg
+                };
+                self.fb.set_pixel(x + gx, y + gy, color);
+            }
+        }
+    }
+
+    pub fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            c => {
+                if self.col >= self.columns() {
+                    self.new_line();
+                }
+                let (x, y) = (self.col * self.font.width(), self.row * self.font.height());
+                self.draw_glyph(x, y, c, self.fg, self.bg);
+                self.col += 1;
+            }
+        }
+    }
+
+    // erase the character before the cursor, at the start of a row that's
+    // the last cell of the row above, at the top left corner nothing happens
+    pub fn backspace(&mut self) {
+        if self.col == 0 {
+            if self.row == 0 || self.columns() == 0 {
+                return;
+            }
+            self.row -= 1;
+            self.col = self.columns();
+        }
+        self.col -= 1;
+        let (x, y) = (self.col * self.font.width(), self.row * self.font.height());
+        self.draw_glyph(x, y, ' ', self.fg, self.bg);
+    }
+
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows() {
+            self.row += 1;
+        } else {
+            self.fb.scroll_up(self.font.height(), self.bg);
+        }
+    }
+}
+
+impl fmt::Write for TextConsole<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        Ok(())
+    }
+}
+
+// where print!/println! go when there's no VGA text mode, see
+// vga_buf::vga_present()
+static CONSOLE: Mutex<Option<TextConsole<'static>>> = Mutex::new(None);
+
+// returns the console that was there before
+pub fn set_console(console: TextConsole<'static>) -> Option<TextConsole<'static>> {
+    interrupts::without_interrupts(|| CONSOLE.lock().replace(console))
+}
+
+pub fn take_console() -> Option<TextConsole<'static>> {
+    interrupts::without_interrupts(|| CONSOLE.lock().take())
+}
+
+pub(crate) fn console() -> &'static Mutex<Option<TextConsole<'static>>> {
+    &CONSOLE
+}
+
+#[cfg(test)]
+const TEST_FG: u32 = 0x00ff_ffff;
+#[cfg(test)]
+const TEST_BG: u32 = 0x0000_0080;
+
+// test that 'A' comes out as the font's bitmap
+#[test_case]
+fn test_draw_known_glyph() {
+    let mut pixels = [0u32; 32 * 16];
+    let mut console = TextConsole::new(
+        Framebuffer::new(&mut pixels, 32, 16, 32),
+        default_font(),
+        TEST_FG,
+        TEST_BG,
+    );
+    console.draw_glyph(8, 0, 'A', TEST_FG, TEST_BG);
+
+    // top row ..##.... and the crossbar ######..
+    let fb = console.framebuffer();
+    let row = |y: usize| {
+        let mut bits = 0u8;
+        for x in 0..8 {
+            if fb.pixel(8 + x, y) == TEST_FG {
+                bits |= 0x80 >> x;
+            }
+        }
+        bits
+    };
+    assert_eq!(row(0), 0b0011_0000);
+    assert_eq!(row(4), 0b1111_1100);
+    assert_eq!(row(7), 0);
+    // the cell to the left is untouched
+    assert_eq!(fb.pixel(7, 4), TEST_BG);
+}
+
+// test that write! wraps and a newline on the last row scrolls up
+#[test_case]
+fn test_console_scrolls() {
+    use core::fmt::Write;
+
+    let mut pixels = [0u32; 16 * 16];
+    let mut console = TextConsole::new(
+        Framebuffer::new(&mut pixels, 16, 16, 16),
+        default_font(),
+        TEST_FG,
+        TEST_BG,
+    );
+    assert_eq!((console.columns(), console.rows()), (2, 2));
+    // "ab" fills the top row, "_" wraps onto the bottom one
+    write!(console, "ab_").unwrap();
+    let fb = console.framebuffer();
+    assert_eq!(fb.pixel(0, 15), TEST_FG);
+
+    // moves the underscore up to the top row and clears the bottom one
+    writeln!(console).unwrap();
+    let fb = console.framebuffer();
+    assert_eq!(fb.pixel(0, 7), TEST_FG);
+    assert!((0..16).all(|x| fb.pixel(x, 15) == TEST_BG));
+}
+
+// test that backspace clears the last cell and steps back over a wrap
+#[test_case]
+fn test_console_backspace() {
+    use core::fmt::Write;
+
+    let mut pixels = [0u32; 16 * 16];
+    let mut console = TextConsole::new(
+        Framebuffer::new(&mut pixels, 16, 16, 16),
+        default_font(),
+        TEST_FG,
+        TEST_BG,
+    );
+    // 'A' fills both top cells, the third one wraps onto the bottom row
+    write!(console, "AAA").unwrap();
+    console.backspace();
+    assert!((0..8).all(|x| console.framebuffer().pixel(x, 12) == TEST_BG));
+    console.backspace();
+    let fb = console.framebuffer();
+    assert!((8..16).all(|x| fb.pixel(x, 4) == TEST_BG));
+    // the first 'A' is still there
+    assert_eq!(fb.pixel(0, 4), TEST_FG);
+
+    console.backspace();
+    console.backspace();
+    assert!((0..16).all(|x| console.framebuffer().pixel(x, 4) == TEST_BG));
+}
+
+#[test_case]
+fn test_console_set_column() {
+    use core::fmt::Write;
+
+    let mut pixels = [0u32; 16 * 16];
+    let mut console = TextConsole::new(
+        Framebuffer::new(&mut pixels, 16, 16, 16),
+        default_font(),
+        TEST_FG,
+        TEST_BG,
+    );
+    write!(console, "A").unwrap();
+    assert_eq!(console.column(), 1);
+    // writing again at column 0 stays on the top row instead of wrapping
+    console.set_column(0);
+    write!(console, "AA").unwrap();
+    assert_eq!(console.column(), 2);
+    assert!((0..16).all(|x| console.framebuffer().pixel(x, 12) == TEST_BG));
+}
+
+#[test_case]
+fn test_font_parse_errors() {
+    assert_eq!(Font::parse(&[0; 8]).err(), Some(FontError::BadMagic));
+    // header says 256 glyphs of 8 bytes, only one is there
+    let short = [0x36, 0x04, 0x00, 8, 0, 0, 0, 0, 0, 0, 0, 0];
+    assert_eq!(Font::parse(&short).err(), Some(FontError::Truncated));
+    let font = default_font();
+    assert_eq!((font.width(), font.height()), (8, 8));
+    // not in the font
+    assert_eq!(font.glyph('\u{2603}'), font.glyph('?'));
+}
+
+// test that consoles with one text cell, or less than one, don't panic
+#[test_case]
+fn test_tiny_console() {
+    use core::fmt::Write;
+
+    let mut pixels = [0u32; 8 * 8];
+    let mut console = TextConsole::new(
+        Framebuffer::new(&mut pixels, 8, 8, 8),
+        default_font(),
+        TEST_FG,
+        TEST_BG,
+    );
+    assert_eq!((console.columns(), console.rows()), (1, 1));
+    write!(console, "ab\ncd\n\n").unwrap();
+    assert!((0..8).all(|x| console.framebuffer().pixel(x, 7) == TEST_BG));
+
+    // smaller than a glyph, everything gets clipped
+    let mut pixels = [0u32; 3 * 3];
+    let mut console = TextConsole::new(
+        Framebuffer::new(&mut pixels, 3, 3, 3),
+        default_font(),
+        TEST_FG,
+        TEST_BG,
+    );
+    assert_eq!((console.columns(), console.rows()), (0, 0));
+    write!(console, "xyz\n_").unwrap();
+}
+
+// test that println! draws on the framebuffer console once VGA is gone
+#[test_case]
+fn test_println_without_vga() {
+    use crate::vga_buf::set_vga_present;
+
+    static mut PIXELS: [u32; 64 * 16] = [0; 64 * 16];
+    #[allow(static_mut_refs)]
+    let pixels = unsafe { &mut PIXELS };
+    let console = TextConsole::new(
+        Framebuffer::new(pixels, 64, 16, 64),
+        default_font(),
+        TEST_FG,
+        TEST_BG,
+    );
+    assert!(set_console(console).is_none());
+    set_vga_present(false);
+    crate::println!("A");
+    set_vga_present(true);
+
+    // the crossbar of the 'A' in the first cell, see test_draw_known_glyph
+    let console = take_console().expect("console went missing");
+    let fb = console.framebuffer();
+    assert!((0..6).all(|x| fb.pixel(x, 4) == TEST_FG));
+    assert_eq!(fb.pixel(6, 4), TEST_BG);
+}
@@ -4,6 +4,11 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_IDX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+pub const PAGE_FAULT_IST_IDX: u16 = 1;
+const PAGE_FAULT_STACK_SIZE: usize = 4096 * 5;
+const RECOVERY_STACK_SIZE: usize = 4096 * 5;
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 5;
 
 /*
 
@@ -46,7 +51,7 @@ lazy_static! {
         // memory below it
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_IDX as usize] = {
             // calculate size of the stack
-            const STACK_SIZE: usize = 4096 * 5;
+            const STACK_SIZE: usize = DOUBLE_FAULT_STACK_SIZE;
             // initialize stack memory to all zeroes
             // currently don't have any memory management so need to use `static mut`
             // must be `static mut` otherwise the compiler will map the memory to a
@@ -59,10 +64,42 @@ lazy_static! {
             let stack_start = VirtAddr::from_ptr(unsafe {core::ptr::from_ref(&STACK)} );
             stack_start + STACK_SIZE // top of the stack from where it can grow downward
         };
+        // page faults get their own stack too, a fault on an overflowed or
+        // corrupted stack would otherwise fault again pushing the exception
+        // frame and escalate to a double fault
+        tss.interrupt_stack_table[PAGE_FAULT_IST_IDX as usize] = {
+            const STACK_SIZE: usize = PAGE_FAULT_STACK_SIZE;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            #[allow(static_mut_refs)]
+            let stack_start = VirtAddr::from_ptr(unsafe {core::ptr::from_ref(&STACK)} );
+            stack_start + STACK_SIZE
+        };
+        // what the CPU switches to when an interrupt or exception comes in
+        // while user code is running, it never uses the ring 3 stack
+        tss.privilege_stack_table[0] = {
+            const STACK_SIZE: usize = PRIVILEGE_STACK_SIZE;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            #[allow(static_mut_refs)]
+            let stack_start = VirtAddr::from_ptr(unsafe {core::ptr::from_ref(&STACK)} );
+            stack_start + STACK_SIZE
+        };
         tss
     };
 }
 
+// lowest address of the double fault stack, where a stack_guard canary goes
+pub fn double_fault_stack_bottom() -> usize {
+    TSS.interrupt_stack_table[DOUBLE_FAULT_IST_IDX as usize].as_u64() as usize
+        - DOUBLE_FAULT_STACK_SIZE
+}
+
+// same for the page fault stack
+pub fn page_fault_stack_bottom() -> usize {
+    TSS.interrupt_stack_table[PAGE_FAULT_IST_IDX as usize].as_u64() as usize - PAGE_FAULT_STACK_SIZE
+}
+
 /*
   Global Descriptor Table (GDT)
 
@@ -78,26 +115,97 @@ lazy_static! {
         let mut gdt = GlobalDescriptorTable::new();
         // initialize the code segment of the GDT for the kernel and capture the SegmentSelector for it
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        // syscall/sysret don't read the segments from the GDT, they work out
+        // the selectors from IA32_STAR, which only works with these 4 in
+        // exactly this order (see syscall.rs)
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         // initialize the TSS segment of the GDT and capture the SegmentSelector for it
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, tss_selector})
+        (gdt, Selectors {code_selector, data_selector, user_data_selector, user_code_selector, tss_selector})
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     unsafe {
         // reload the code segment register
         CS::set_reg(GDT.1.code_selector);
+        // and the stack segment, whatever the bootloader left in there
+        // indexes its GDT rather than ours
+        SS::set_reg(GDT.1.data_selector);
         // load the TSS
         load_tss(GDT.1.tss_selector);
     }
 }
+
+// check the CPU's GDTR actually points at our GDT
+pub fn is_loaded() -> bool {
+    use x86_64::instructions::tables::sgdt;
+    let base = sgdt().base;
+    base == VirtAddr::from_ptr(&GDT.0)
+}
+
+// check the task register holds our TSS, i.e. init() got as far as load_tss
+pub fn tss_loaded() -> bool {
+    let tr: u16;
+    unsafe {
+        core::arch::asm!("str {0:x}", out(reg) tr, options(nomem, nostack, preserves_flags));
+    }
+    tr == GDT.1.tss_selector.0
+}
+
+// where the CPU puts rsp on a double fault
+pub fn double_fault_stack_top() -> VirtAddr {
+    TSS.interrupt_stack_table[DOUBLE_FAULT_IST_IDX as usize]
+}
+
+pub fn code_selector() -> SegmentSelector {
+    GDT.1.code_selector
+}
+
+pub fn data_selector() -> SegmentSelector {
+    GDT.1.data_selector
+}
+
+// both with RPL 3, for the frame that drops into user mode
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+// where the CPU puts rsp on a page fault
+pub fn page_fault_stack_top() -> VirtAddr {
+    TSS.interrupt_stack_table[PAGE_FAULT_IST_IDX as usize]
+}
+
+/*
+    stack interrupts::DoubleFaultPolicy::RecoverAndLog resumes on
+
+    - not in the IST, the CPU never switches to it by itself, the double
+      fault handler points the interrupted context's rsp at it
+    - only ever used once (a second double fault halts), so it never has
+      anything on it that needs to be kept
+*/
+pub fn recovery_stack_top() -> VirtAddr {
+    static mut STACK: [u8; RECOVERY_STACK_SIZE] = [0; RECOVERY_STACK_SIZE];
+
+    #[allow(static_mut_refs)]
+    let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::from_ref(&STACK) });
+    stack_start + RECOVERY_STACK_SIZE
+}
@@ -1,19 +1,19 @@
-use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use alloc::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, PageTableFlags, Size4KiB},
     VirtAddr,
 };
+pub mod bump;
+pub mod fixed_block;
 pub mod linked_list;
+#[cfg(feature = "alloc_tracking")]
+pub mod tracked;
+use bump::BumpAlloc;
+use fixed_block::FixedBlockAlloc;
 use linked_list::LinkedListAlloc;
 
-// requires that `align` is some power of 2
-fn align_up(addr: usize, align: usize) -> usize {
-    (addr + align - 1) & !(align - 1)
-}
-
 pub struct Locked<T> {
     inner: spin::Mutex<T>,
 }
@@ -34,49 +34,384 @@ impl<T> Locked<T> {
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 */
 #[global_allocator]
-static ALLOCATOR: Locked<LinkedListAlloc> = Locked::new(LinkedListAlloc::new());
+static ALLOCATOR: SwitchableAlloc = SwitchableAlloc::new(Backend::LinkedList);
+
+/*
+    Picking the global allocator at runtime
+
+    - #[global_allocator] has to be a single static, so SwitchableAlloc
+      forwards every alloc/dealloc to whichever backend is selected
+    - set_backend() only swaps while nothing is allocated: the new backend
+      takes over the whole heap region and the old one's bookkeeping, which
+      lives in that region, is just overwritten
+        - called before init_heap() it only picks the one init_heap() sets up
+    - secure mode and heap_checks are LinkedList features, the other two
+      ignore them
+        - secure mode carries over a switch, so while it's on only
+          LinkedList can be switched to
+    - the usage numbers (allocated_bytes(), peak_usage()) are the current
+      backend's own and start over from 0 after a switch
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    // first fit free list, the default
+    LinkedList,
+    // per size class free lists over a LinkedList
+    FixedBlock,
+    // never reuses memory until everything is freed
+    Bump,
+}
+
+enum AllocBackend {
+    LinkedList(LinkedListAlloc),
+    FixedBlock(FixedBlockAlloc),
+    Bump(BumpAlloc),
+}
+
+impl AllocBackend {
+    const fn new(backend: Backend) -> Self {
+        match backend {
+            Backend::LinkedList => AllocBackend::LinkedList(LinkedListAlloc::new()),
+            Backend::FixedBlock => AllocBackend::FixedBlock(FixedBlockAlloc::new()),
+            Backend::Bump => AllocBackend::Bump(BumpAlloc::new()),
+        }
+    }
+
+    fn kind(&self) -> Backend {
+        match self {
+            AllocBackend::LinkedList(_) => Backend::LinkedList,
+            AllocBackend::FixedBlock(_) => Backend::FixedBlock,
+            AllocBackend::Bump(_) => Backend::Bump,
+        }
+    }
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        match self {
+            AllocBackend::LinkedList(alloc) => alloc.init(heap_start, heap_size),
+            AllocBackend::FixedBlock(alloc) => alloc.init(heap_start, heap_size),
+            AllocBackend::Bump(alloc) => alloc.init(heap_start, heap_size),
+        }
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self {
+            AllocBackend::LinkedList(alloc) => alloc.alloc(layout),
+            AllocBackend::FixedBlock(alloc) => alloc.alloc(layout),
+            AllocBackend::Bump(alloc) => alloc.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match self {
+            AllocBackend::LinkedList(alloc) => alloc.dealloc(ptr, layout),
+            AllocBackend::FixedBlock(alloc) => alloc.dealloc(ptr, layout),
+            AllocBackend::Bump(alloc) => alloc.dealloc(ptr, layout),
+        }
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        match self {
+            AllocBackend::LinkedList(alloc) => alloc.allocated_bytes(),
+            AllocBackend::FixedBlock(alloc) => alloc.allocated_bytes(),
+            AllocBackend::Bump(alloc) => alloc.allocated_bytes(),
+        }
+    }
+
+    fn peak_bytes(&self) -> usize {
+        match self {
+            AllocBackend::LinkedList(alloc) => alloc.peak_bytes(),
+            AllocBackend::FixedBlock(alloc) => alloc.peak_bytes(),
+            AllocBackend::Bump(alloc) => alloc.peak_bytes(),
+        }
+    }
+
+    fn reset_peak(&mut self) {
+        match self {
+            AllocBackend::LinkedList(alloc) => alloc.reset_peak(),
+            AllocBackend::FixedBlock(alloc) => alloc.reset_peak(),
+            AllocBackend::Bump(alloc) => alloc.reset_peak(),
+        }
+    }
+
+    fn set_secure(&mut self, on: bool) {
+        if let AllocBackend::LinkedList(alloc) = self {
+            alloc.set_secure(on);
+        }
+    }
+
+    fn is_secure(&self) -> bool {
+        match self {
+            AllocBackend::LinkedList(alloc) => alloc.is_secure(),
+            _ => false,
+        }
+    }
+}
+
+pub struct SwitchableAlloc {
+    inner: spin::Mutex<AllocBackend>,
+}
+
+impl SwitchableAlloc {
+    const fn new(backend: Backend) -> Self {
+        SwitchableAlloc {
+            inner: spin::Mutex::new(AllocBackend::new(backend)),
+        }
+    }
+
+    fn lock(&self) -> spin::MutexGuard<'_, AllocBackend> {
+        self.inner.lock()
+    }
+}
+
+unsafe impl GlobalAlloc for SwitchableAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the handler may have interrupted code holding the lock, dealloc
+        // isn't checked since a handler waking a task can drop a waker
+        debug_assert!(
+            !crate::interrupts::in_handler(),
+            "allocating in an interrupt handler"
+        );
+        self.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().dealloc(ptr, layout)
+    }
+}
+
+// why set_backend() refused to switch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendError {
+    // the current backend still had memory out
+    InUse { allocated: usize },
+    // secure mode is on and the new backend can't zero freed memory
+    NotSecure,
+}
+
+pub fn set_backend(backend: Backend) -> Result<(), BackendError> {
+    let mut current = ALLOCATOR.lock();
+    let allocated = current.allocated_bytes();
+    if allocated != 0 {
+        return Err(BackendError::InUse { allocated });
+    }
+    let secure = current.is_secure();
+    if secure && backend != Backend::LinkedList {
+        return Err(BackendError::NotSecure);
+    }
+
+    *current = AllocBackend::new(backend);
+    current.set_secure(secure);
+    if is_heap_ready() {
+        let (start, size) = heap_region();
+        // the region is mapped and nothing in it is in use any more
+        unsafe { current.init(start, size) };
+    }
+    Ok(())
+}
+
+pub fn backend() -> Backend {
+    ALLOCATOR.lock().kind()
+}
 
 pub const HEAP_START: usize = 0x_4444_4444_0000; // VirtAddr where heap starts
 pub const HEAP_SIZE: usize = 100 * 1024; // heap size in bytes = 1 MiB
 
+// frames init_heap() may take: one per heap page plus, worst case, a new
+// P3, P2 and P1 table to map them
+pub const fn frames_needed() -> usize {
+    HEAP_SIZE / 4096 + 3
+}
+
+// set once init_heap() succeeds, code that can run during early boot (e.g.
+// anything behind println!) checks this before touching the heap
+static HEAP_READY: AtomicBool = AtomicBool::new(false);
+
+pub fn is_heap_ready() -> bool {
+    HEAP_READY.load(Ordering::Acquire)
+}
+
+/*
+    secure mode for the global allocator, off by default
+
+    - dealloc() zeroes the freed region (apart from the free list node that
+      ends up at its start) so stale data doesn't outlive its owner
+    - alloc() fills new memory with linked_list::POISON (0xaa) so reads of
+      uninitialized or use-after-free memory are easy to spot
+*/
+pub fn set_secure_mode(on: bool) {
+    ALLOCATOR.lock().set_secure(on);
+}
+
+pub fn secure_mode() -> bool {
+    ALLOCATOR.lock().is_secure()
+}
+
+/*
+    usage of the global heap, for sizing HEAP_SIZE
+
+    - counts what the allocator actually hands out, i.e. each allocation
+      rounded up to a multiple of 8 and at least 16 bytes
+    - peak_usage() is the most that was allocated at any one time since
+      boot or the last reset_peak()
+*/
+pub fn allocated_bytes() -> usize {
+    ALLOCATOR.lock().allocated_bytes()
+}
+
+pub fn peak_usage() -> usize {
+    ALLOCATOR.lock().peak_bytes()
+}
+
+pub fn reset_peak() {
+    ALLOCATOR.lock().reset_peak();
+}
+
+/*
+    leak checks for tests
+
+    - checkpoint() remembers allocated_bytes(), assert_no_leaks_since()
+      panics if more is allocated now than back then
+    - only the net amount counts, memory allocated in between is fine as
+      long as the same amount was freed again
+    - anything else allocating in between (e.g. a waker cache filling up
+      the first time) shows up as a leak too, so keep the checked block
+      small and create executors inside it
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    allocated: usize,
+}
+
+pub fn checkpoint() -> Checkpoint {
+    Checkpoint {
+        allocated: allocated_bytes(),
+    }
+}
+
+#[track_caller]
+pub fn assert_no_leaks_since(checkpoint: Checkpoint) {
+    let now = allocated_bytes();
+    if now > checkpoint.allocated {
+        panic!(
+            "heap leak: {} bytes more allocated than at the checkpoint ({} -> {})",
+            now - checkpoint.allocated,
+            checkpoint.allocated,
+            now
+        );
+    }
+}
+
+// the first and last 8 bytes of the heap region hold stack_guard canaries
+// rather than being handed to the allocator
+const GUARD_SIZE: usize = core::mem::size_of::<u64>();
+
+pub fn guard_addrs() -> (usize, usize) {
+    (HEAP_START, HEAP_START + HEAP_SIZE - GUARD_SIZE)
+}
+
+// the part of the heap between the guards the allocator manages
+fn heap_region() -> (usize, usize) {
+    (HEAP_START + GUARD_SIZE, HEAP_SIZE - 2 * GUARD_SIZE)
+}
+
 // maps the heap memory range to some physical memory frames
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_alloc: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
-    // generate page range from HEAP_START and HEAP_SIZE
-    let pg_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + (HEAP_SIZE - 1) as u64;
-        let heap_start_pg = Page::containing_address(heap_start);
-        let heap_end_pg = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_pg, heap_end_pg)
-    };
-
-    // map each page in pg_range to some physical frame
-    for pg in pg_range {
-        // allocate the physical frame (or throw an error if impossible)
-        let frame = frame_alloc
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        // set the page as present and make it writable
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        // map the page to the physical frame allocated
-        unsafe {
-            mapper.map_to(pg, frame, flags, frame_alloc)?.flush();
-        }
-    }
+    init_heap_inner(mapper, frame_alloc, false)
+}
+
+// same as init_heap() but every heap page is zeroed once it's mapped, so
+// memory handed out by the heap never has stale data from the frames in it
+pub fn init_heap_zeroed(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    init_heap_inner(mapper, frame_alloc, true)
+}
+
+fn init_heap_inner(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+    zero: bool,
+) -> Result<(), MapToError<Size4KiB>> {
+    // set the pages as present and make them writable
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    crate::mem::map_range(
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_SIZE,
+        flags,
+        zero,
+        mapper,
+        frame_alloc,
+    )?;
 
     // temporary allocator before making a custom one
     unsafe {
+        let (below, above) = guard_addrs();
+        crate::stack_guard::place_canary(below);
+        crate::stack_guard::place_canary(above);
         // must lock it since the LockedHeap class uses a mutex to guarantee
         // thread safety
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        let (start, size) = heap_region();
+        ALLOCATOR.lock().init(start, size);
     }
+    HEAP_READY.store(true, Ordering::Release);
 
     Ok(())
 }
 
+/*
+    A heap over some other region than [HEAP_START, HEAP_START + HEAP_SIZE)
+
+    - its own LinkedListAlloc, so allocations never come out of (or fall back
+      on) the global heap, e.g. a pool in low memory for DMA buffers
+    - usable directly through alloc_in()/dealloc_in() or as an Allocator for
+      the *_in collections: `Vec::new_in(&heap)`
+*/
+pub struct Heap {
+    inner: Locked<LinkedListAlloc>,
+}
+
+impl Heap {
+    /*
+        the caller has to guarantee that [start, start + size) is mapped,
+        writable and not used by anything else for as long as the Heap is
+
+        - start has to be aligned for (and size big enough to hold) a
+          linked list node, i.e. 8 byte aligned and at least 16 bytes
+    */
+    pub unsafe fn new(start: usize, size: usize) -> Heap {
+        let mut alloc = LinkedListAlloc::new();
+        alloc.init(start, size);
+        Heap {
+            inner: Locked::new(alloc),
+        }
+    }
+
+    // null if the region has no room left
+    pub fn alloc_in(&self, layout: Layout) -> *mut u8 {
+        self.inner.lock().alloc(layout)
+    }
+
+    // `ptr` must have come from alloc_in() on this heap with the same layout
+    pub unsafe fn dealloc_in(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.lock().dealloc(ptr, layout)
+    }
+}
+
+unsafe impl Allocator for Heap {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = NonNull::new(self.alloc_in(layout)).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.dealloc_in(ptr.as_ptr(), layout)
+    }
+}
+
 // TODO: implement a custom allocator rather than using the linked_list_allocator crate
 pub struct CustomAlloc;
 
@@ -0,0 +1,84 @@
+use alloc::alloc::Layout;
+use core::ptr;
+
+/*
+    Bump allocator
+
+    - hands out memory by moving `next` up past each allocation, so alloc()
+      is a couple of additions and dealloc() only decrements a counter
+    - freed memory is never reused on its own, only once every allocation
+      has been freed does `next` go back to the start of the heap
+    - a single long lived allocation therefore pins everything allocated
+      after it, only good for comparisons or short bursts
+*/
+pub struct BumpAlloc {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    // live allocations, the heap starts over once this drops to 0
+    allocations: usize,
+    // bytes asked for by the live allocations (not counting alignment
+    // padding) and the most at once since the last reset_peak()
+    allocated: usize,
+    peak: usize,
+}
+
+impl BumpAlloc {
+    pub const fn new() -> Self {
+        BumpAlloc {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+            allocated: 0,
+            peak: 0,
+        }
+    }
+
+    // the caller has to guarantee [heap_start, heap_start + heap_size) is
+    // mapped, writable and not used by anything else
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn peak_bytes(&self) -> usize {
+        self.peak
+    }
+
+    pub fn reset_peak(&mut self) {
+        self.peak = self.allocated;
+    }
+
+    // null once the rest of the heap is too small
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = match crate::util::checked_align_up(self.next, layout.align()) {
+            Some(start) => start,
+            None => return ptr::null_mut(),
+        };
+        match alloc_start.checked_add(layout.size()) {
+            Some(alloc_end) if alloc_end <= self.heap_end => {
+                self.next = alloc_end;
+                self.allocations += 1;
+                self.allocated += layout.size();
+                self.peak = self.peak.max(self.allocated);
+                alloc_start as *mut u8
+            }
+            _ => ptr::null_mut(),
+        }
+    }
+
+    // `ptr` must have come from alloc() on this allocator with the same layout
+    pub unsafe fn dealloc(&mut self, _ptr: *mut u8, layout: Layout) {
+        self.allocations -= 1;
+        self.allocated -= layout.size();
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use super::linked_list::LinkedListAlloc;
+use alloc::alloc::Layout;
+
+/*
+    Fixed size block allocator
+
+    - small allocations come in BLOCK_SIZES classes, each with its own
+      free list, so alloc()/dealloc() of those are a pop/push on a list
+    - an allocation takes the smallest block that fits both its size and
+      its alignment, blocks are aligned to their size
+    - freed blocks go back on their class's list and are never merged or
+      given back, memory used for 8 byte blocks stays 8 byte blocks
+    - anything bigger than the largest class, and fresh blocks for a class
+      whose list is empty, come from a LinkedListAlloc over the same region
+*/
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// sits at the start of every free block
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedBlockAlloc {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback: LinkedListAlloc,
+    // bytes in live allocations (whole blocks for the small ones) and the
+    // most at once since the last reset_peak()
+    allocated: usize,
+    peak: usize,
+}
+
+impl FixedBlockAlloc {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedBlockAlloc {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback: LinkedListAlloc::new(),
+            allocated: 0,
+            peak: 0,
+        }
+    }
+
+    // the caller has to guarantee [heap_start, heap_start + heap_size) is
+    // mapped, writable and not used by anything else
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback.init(heap_start, heap_size);
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn peak_bytes(&self) -> usize {
+        self.peak
+    }
+
+    pub fn reset_peak(&mut self) {
+        self.peak = self.allocated;
+    }
+
+    // null if neither the block list nor the fallback has room
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (ptr, size) = match list_index(&layout) {
+            Some(idx) => {
+                let ptr = match self.list_heads[idx].take() {
+                    Some(node) => {
+                        self.list_heads[idx] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        let block_size = BLOCK_SIZES[idx];
+                        // only fails for sizes that aren't a power of 2
+                        let block = Layout::from_size_align(block_size, block_size).unwrap();
+                        self.fallback.alloc(block)
+                    }
+                };
+                (ptr, BLOCK_SIZES[idx])
+            }
+            None => (self.fallback.alloc(layout), layout.size()),
+        };
+        if !ptr.is_null() {
+            self.allocated += size;
+            self.peak = self.peak.max(self.allocated);
+        }
+        ptr
+    }
+
+    // `ptr` must have come from alloc() on this allocator with the same layout
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(idx) => {
+                // every block can hold a ListNode, the smallest is 8 bytes
+                let node = ptr as *mut ListNode;
+                node.write(ListNode {
+                    next: self.list_heads[idx].take(),
+                });
+                self.list_heads[idx] = Some(&mut *node);
+                self.allocated -= BLOCK_SIZES[idx];
+            }
+            None => {
+                self.fallback.dealloc(ptr, layout);
+                self.allocated -= layout.size();
+            }
+        }
+    }
+}
+
+// index of the smallest block class `layout` fits in, None if it's too big
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required)
+}
@@ -1,4 +1,5 @@
 use super::*;
+use crate::util::align_up;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::mem;
 use core::ptr;
@@ -22,8 +23,18 @@ impl ListNode {
     }
 }
 
+// byte written over freshly allocated memory in secure mode, so reads of
+// uninitialized memory stand out
+pub const POISON: u8 = 0xaa;
+
 pub struct LinkedListAlloc {
     head: ListNode,
+    // zero regions on free and poison them on alloc
+    secure: bool,
+    // bytes currently handed out (after size_align() padding) and the most
+    // there has been at once since the last reset_peak()
+    allocated: usize,
+    peak: usize,
 }
 
 impl LinkedListAlloc {
@@ -31,30 +42,91 @@ impl LinkedListAlloc {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            secure: false,
+            allocated: 0,
+            peak: 0,
         }
     }
 
+    pub fn set_secure(&mut self, on: bool) {
+        self.secure = on;
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn peak_bytes(&self) -> usize {
+        self.peak
+    }
+
+    // start tracking the peak again from what's allocated right now
+    pub fn reset_peak(&mut self) {
+        self.peak = self.allocated;
+    }
+
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
     }
 
-    // adds freed region in memory to the heap allocator's linked list
+    /*
+        adds freed region in memory to the heap allocator's linked list
+
+        - the list is kept sorted by address so a region can be merged with
+          the free regions right before and after it, otherwise freeing in
+          any order but LIFO leaves the heap in pieces that are each too
+          small for a bigger allocation even though together they'd fit
+        - O(n) in the number of free regions instead of pushing to the front
+    */
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // check if the free region is able to hold a ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // initialize new ListNode
-        let mut node = ListNode::new(size);
-        // set new node->next = head->next
-        node.next = self.head.next.take();
-        // create a pointer to the free memory address
-        let node_ptr = addr as *mut ListNode;
-        // write the node to the memory address
-        node_ptr.write(node);
-        // set head->next = node
-        // places node at the beginning of the list, like pushing onto a stack: LIFO
-        self.head.next = Some(&mut *node_ptr);
+        // the dummy head isn't part of the heap, never merge into it
+        let head_addr = self.head.start_addr();
+        let mut size = size;
+
+        // find the last region that starts before this one
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // swallow the region right after this one
+        let mut next = current.next.take();
+        if next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() == addr + size)
+        {
+            let following = next.unwrap();
+            size += following.size;
+            next = following.next.take();
+            // its header is now in the middle of this region
+            if self.secure {
+                ptr::write_bytes(following as *mut ListNode, 0, 1);
+            }
+        }
+
+        if current.start_addr() != head_addr && current.end_addr() == addr {
+            // or be swallowed by the one right before it
+            current.size += size;
+            current.next = next;
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
     }
 
     // finds a region with the given size and alignment and removes it from the linked list
@@ -99,35 +171,83 @@ impl LinkedListAlloc {
         Ok(alloc_start)
     }
 
-    fn size_align(layout: Layout) -> (usize, usize) {
-        let layout = layout
-            .align_to(mem::align_of::<ListNode>())
-            .expect("alignment failed")
-            .pad_to_align();
-        let size = layout.size().max(mem::size_of::<ListNode>());
-        (size, layout.align())
-    }
-}
-
-unsafe impl GlobalAlloc for Locked<LinkedListAlloc> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let (size, align) = LinkedListAlloc::size_align(layout);
-        let mut allocator = self.lock();
+    // first fit out of the free list, null if nothing fits
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        // an impossible request gets null like any other failed allocation
+        let (size, align) = match Self::size_align(layout) {
+            Some(size_align) => size_align,
+            None => return ptr::null_mut(),
+        };
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             let overhang = region.end_addr() - alloc_end;
             if overhang > 0 {
-                allocator.add_free_region(alloc_end, overhang);
+                unsafe { self.add_free_region(alloc_end, overhang) };
+            }
+            if self.secure {
+                unsafe { ptr::write_bytes(alloc_start as *mut u8, POISON, layout.size()) };
             }
+            self.allocated += size;
+            self.peak = self.peak.max(self.allocated);
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
         }
     }
 
+    // `ptr` must have come from alloc() on this allocator with the same layout
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        // alloc() never hands out a pointer for a layout that overflows
+        if let Some((size, _)) = Self::size_align(layout) {
+            #[cfg(feature = "heap_checks")]
+            self.check_not_free(ptr as usize, size);
+            // zero first, add_free_region() then writes the ListNode over
+            // the start of the region
+            if self.secure {
+                ptr::write_bytes(ptr, 0, size);
+            }
+            self.add_free_region(ptr as usize, size);
+            self.allocated -= size;
+        }
+    }
+
+    /*
+        panic if any of [addr, addr + size) is already on the free list
+
+        - freeing something twice would put the same region on the list
+          twice, two later allocations would then share it
+        - O(n) in the number of free regions, so only with heap_checks
+    */
+    #[cfg(feature = "heap_checks")]
+    fn check_not_free(&self, addr: usize, size: usize) {
+        let mut current = &self.head.next;
+        while let Some(node) = current {
+            if addr < node.end_addr() && node.start_addr() < addr + size {
+                panic!("DOUBLE FREE DETECTED at {:#x}", addr);
+            }
+            current = &node.next;
+        }
+    }
+
+    // bump the layout up so the region can hold a ListNode once it's freed,
+    // returns None if that padding overflows (only for near isize::MAX sizes)
+    fn size_align(layout: Layout) -> Option<(usize, usize)> {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .ok()?
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        Some((size, layout.align()))
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAlloc> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc(layout)
+    }
+
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (size, _) = LinkedListAlloc::size_align(layout);
-        self.lock().add_free_region(ptr as usize, size);
+        self.lock().dealloc(ptr, layout)
     }
 }
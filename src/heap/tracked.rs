@@ -0,0 +1,101 @@
+use crate::serial_println;
+use alloc::alloc::{alloc, handle_alloc_error, Layout};
+use alloc::boxed::Box;
+use core::panic::Location;
+use x86_64::instructions::interrupts;
+
+/*
+    Allocation site tracking (alloc_tracking feature)
+
+    - GlobalAlloc never sees who asked for the memory, so kalloc()/kbox()
+      take the caller's file:line through #[track_caller] and remember the
+      last SITES_LEN of them in a ring buffer before allocating
+    - only allocations made through these two are tracked, Vec/String/etc.
+      still go straight to the global allocator
+    - on failure the recent sites get printed over serial, the failing one
+      is always the newest
+    - the ring is behind a spin lock taken with interrupts off so tracked
+      allocations are fine from an interrupt handler too
+*/
+
+pub const SITES_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Site {
+    pub file: &'static str,
+    pub line: u32,
+    pub size: usize,
+}
+
+struct Ring {
+    sites: [Option<Site>; SITES_LEN],
+    // slot the next site goes in, wraps around over the oldest one
+    next: usize,
+}
+
+static RING: spin::Mutex<Ring> = spin::Mutex::new(Ring {
+    sites: [None; SITES_LEN],
+    next: 0,
+});
+
+fn record(location: &'static Location<'static>, size: usize) {
+    let site = Site {
+        file: location.file(),
+        line: location.line(),
+        size,
+    };
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        let next = ring.next;
+        ring.sites[next] = Some(site);
+        ring.next = (next + 1) % SITES_LEN;
+    });
+}
+
+// the most recent tracked allocations, newest first, None past the end
+// while fewer than SITES_LEN have been made
+pub fn recent_sites() -> [Option<Site>; SITES_LEN] {
+    let ring = interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        (ring.sites, ring.next)
+    });
+    let (sites, next) = ring;
+    let mut recent = [None; SITES_LEN];
+    for (i, slot) in recent.iter_mut().enumerate() {
+        *slot = sites[(next + SITES_LEN - 1 - i) % SITES_LEN];
+    }
+    recent
+}
+
+pub fn print_recent_sites() {
+    serial_println!("recent allocation sites (newest first):");
+    for site in recent_sites().iter().flatten() {
+        serial_println!("    {}:{} ({} bytes)", site.file, site.line, site.size);
+    }
+}
+
+// alloc::alloc::alloc() that records where it was called from, same
+// contract (non zero size, free with alloc::alloc::dealloc())
+#[track_caller]
+pub unsafe fn kalloc(layout: Layout) -> *mut u8 {
+    record(Location::caller(), layout.size());
+    let ptr = alloc(layout);
+    if ptr.is_null() {
+        print_recent_sites();
+    }
+    ptr
+}
+
+// Box::new() that records where it was called from
+#[track_caller]
+pub fn kbox<T>(value: T) -> Box<T> {
+    let layout = Layout::new::<T>();
+    record(Location::caller(), layout.size());
+    match Box::try_new(value) {
+        Ok(boxed) => boxed,
+        Err(_) => {
+            print_recent_sites();
+            handle_alloc_error(layout)
+        }
+    }
+}
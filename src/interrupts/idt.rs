@@ -124,6 +124,43 @@ impl Idt {
         self.0[entry] = Entry::new(segmentation::CS::get_reg(), handler, opt);
     }
 
+    // code segment the CPU switches to for `entry`, whatever CS was when its
+    // handler was set
+    pub fn selector(&self, entry: usize) -> SegmentSelector {
+        self.0[entry].gdt_sel
+    }
+
+    // address the CPU jumps to for `entry`
+    pub fn handler_addr(&self, entry: usize) -> u64 {
+        let Entry {
+            ptr_low,
+            ptr_mid,
+            ptr_high,
+            ..
+        } = self.0[entry];
+        ptr_low as u64 | (ptr_mid as u64) << 16 | (ptr_high as u64) << 32
+    }
+
+    // whether the CPU takes `entry` at all, false for missing() entries
+    pub fn is_present(&self, entry: usize) -> bool {
+        let options = self.0[entry].options;
+        options.0.get_bit(15)
+    }
+
+    // every vector with its handler address, None for a non-present entry
+    pub fn entries(&self) -> impl Iterator<Item = (u8, Option<u64>)> + '_ {
+        (0..self.0.len()).map(move |entry| {
+            let addr = self.is_present(entry).then(|| self.handler_addr(entry));
+            (entry as u8, addr)
+        })
+    }
+
+    // IST slot + 1 the CPU switches stacks to for `entry`, 0 for no switch
+    pub fn stack_idx(&self, entry: usize) -> u16 {
+        let options = self.0[entry].options;
+        options.0.get_bits(0..=2)
+    }
+
     // IDT must be valid until a new IDT is loaded and as long as the kernel runs, thus "'static"
     // this will ensure that the IDT is not overwritten since we initially construct it on the stack
     // before loading. If it was not static then a function call could overwrite the IDT memory location
@@ -1,5 +1,11 @@
-use crate::{gdt::DOUBLE_FAULT_IST_IDX, println, serial_println};
+use crate::mmio::{PortRegister, RegisterAccess};
+use crate::{
+    gdt::{DOUBLE_FAULT_IST_IDX, PAGE_FAULT_IST_IDX},
+    println, serial_println, try_println,
+};
 use core::arch::naked_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use idt::EntryOptions;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
@@ -71,41 +77,97 @@ enum InterruptIndex {
 
 // helper functions for quickly changing their data type
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        return self as u8;
-    }
-
     fn as_usize(self) -> usize {
         return self as usize;
     }
 }
 
+// PIC lines (not IDT vectors) of the devices we handle
+pub const TIMER_IRQ: u8 = InterruptIndex::Timer as u8 - PIC_1_OFFSET;
+pub const KEYBOARD_IRQ: u8 = InterruptIndex::Keyboard as u8 - PIC_1_OFFSET;
+pub const SERIAL1_IRQ: u8 = InterruptIndex::Serial1 as u8 - PIC_1_OFFSET;
+// the secondary PIC is wired into this line of the primary
+const CASCADE_IRQ: u8 = InterruptIndex::SIC as u8 - PIC_1_OFFSET;
+
+/*
+    Interrupt context checks
+
+    - are_enabled() reads RFLAGS.IF, i.e. whether the CPU would take an
+      interrupt right now
+    - every IDT entry is an interrupt gate, which clears IF on the way in,
+      so a handler always runs with it clear, but so does code after a `cli`
+    - in_handler() is only true inside a handler, the handler!() wrappers
+      keep count
+    - debug_assert_in_interrupt!() uses in_handler() to catch interrupt-only
+      code being called from normal context
+    - debug_assert_interrupts_disabled!() is for code that takes a lock an
+      interrupt handler also takes
+    - both compile to nothing in release builds, like debug_assert!()
+*/
+pub fn are_enabled() -> bool {
+    x86_64::instructions::interrupts::are_enabled()
+}
+
+// handlers running right now, the handler!() wrappers count up around the
+// call into the Rust handler and back down once it returns
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+// unlike !are_enabled() this is only true inside a handler, not after a `cli`
+pub fn in_handler() -> bool {
+    HANDLER_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+// run `f` as if it were an interrupt handler, for tests that feed
+// add_scancode() and friends directly instead of through a real IRQ
+pub fn run_as_handler<R>(f: impl FnOnce() -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        HANDLER_DEPTH.fetch_add(1, Ordering::Relaxed);
+        let ret = f();
+        HANDLER_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        ret
+    })
+}
+
+#[macro_export]
+macro_rules! debug_assert_in_interrupt {
+    () => {
+        debug_assert!(
+            $crate::interrupts::in_handler(),
+            "only callable from an interrupt handler"
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! debug_assert_interrupts_disabled {
+    () => {
+        debug_assert!(
+            !$crate::interrupts::are_enabled(),
+            "interrupts must be disabled here"
+        )
+    };
+}
+
+// number of timer interrupts since interrupts were enabled
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
 extern "C" fn timer_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
-    // going to leave this blank for now since it's a bit distracting
-    crate::print!("");
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    // cheap enough (4 reads) to do on every tick, a smashed canary is only
+    // reported once the handler has returned
+    crate::stack_guard::check_all_from_irq();
 
     // sends explicit End Of Interrupt (EOI) signal to PIC so it can receive the next interrupt
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    end_of_interrupt(TIMER_IRQ);
 }
 
 extern "C" fn keyboard_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
     use x86_64::instructions::port::Port;
 
-    // setup a KEYBOARD global object to handle scancode translation
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore
-            ));
-    }
-
     /*
         Setup a port to read the scancode sent by the keyboard
 
@@ -116,24 +178,251 @@ extern "C" fn keyboard_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
           emulate that for now
             - The data port for the PS/2 controller is 0x60
     */
-    let mut keyboard = KEYBOARD.lock();
     let mut p = Port::new(0x60);
 
-    // read, translate, and display the scancode received
+    // read the scancode and hand it off to the async keyboard task, the
+    // translation and printing happen there rather than in the handler
     let scancode: u8 = unsafe { p.read() };
-    if let Ok(Some(key_press)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_press) {
-            match key {
-                DecodedKey::Unicode(character) => crate::print!("{character}"),
-                DecodedKey::RawKey(key) => crate::serial_print!("{:?}", key), // redirect output here to serial so it doesn't crowd the screen
-            }
-        }
+    crate::task::keyboard::add_scancode(scancode);
+
+    end_of_interrupt(KEYBOARD_IRQ);
+}
+
+extern "C" fn serial_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
+    use crate::serial::base;
+    use x86_64::instructions::port::Port;
+
+    // the line status register's bit 0 is set while there is received data
+    let mut line_sts: Port<u8> = Port::new(base() + 5);
+    let mut data: Port<u8> = Port::new(base());
+
+    // the UART has a FIFO so drain everything it has for us
+    while unsafe { line_sts.read() } & 0x1 != 0 {
+        crate::task::serial::add_byte(unsafe { data.read() });
     }
 
+    end_of_interrupt(SERIAL1_IRQ);
+}
+
+/*
+    End Of Interrupt (EOI)
+
+    - a PIC won't raise another IRQ of the same or lower priority until it
+      gets an EOI (0x20 on its command port) for the one in service
+    - IRQs 0-7 only involve the primary PIC: one EOI to it
+    - IRQs 8-15 arrive at the primary through the secondary on the cascade
+      line (IRQ 2), so *both* are in service: EOI the secondary first, then
+      the primary
+        - forgetting the primary's leaves IRQ 2 (so all of 8-15) and every
+          lower priority IRQ blocked
+    - every handler goes through end_of_interrupt() so this is only
+      written down once
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EoiTargets {
+    Primary,
+    SecondaryThenPrimary,
+}
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_EOI: u8 = 0x20;
+
+pub fn eoi_targets(irq: u8) -> EoiTargets {
+    if irq >= 8 {
+        EoiTargets::SecondaryThenPrimary
+    } else {
+        EoiTargets::Primary
+    }
+}
+
+// command register of the PIC that owns `irq`
+fn pic_command(irq: u8) -> PortRegister<u8> {
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        PortRegister::new(if irq < 8 {
+            PIC_1_COMMAND
+        } else {
+            PIC_2_COMMAND
+        })
+    }
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    crate::debug_assert_in_interrupt!();
+    // held so nothing else talks to the PICs in between
+    let _pics = PICS.lock();
+    if eoi_targets(irq) == EoiTargets::SecondaryThenPrimary {
+        pic_command(irq).write(PIC_EOI);
     }
+    pic_command(0).write(PIC_EOI);
+}
+
+/*
+    IRQ routing table
+
+    - every PIC line without a dedicated handler above (all but the timer,
+      keyboard, COM1 and the cascade line) gets a trampoline in the IDT that
+      calls whatever was registered with request_irq() and then sends the
+      EOI, so a driver never has to touch the IDT itself
+        - IRQ >= 8 come in through the secondary PIC and need an EOI sent to
+          both, end_of_interrupt() takes care of that
+    - handlers are kept as plain fn pointers in atomics so the trampoline
+      doesn't need a lock, 0 means nothing registered
+    - IRQ 7 and 15 are also what the PICs raise for spurious interrupts,
+      those are recognized by the line not being in service and don't get
+      an EOI (except the primary's for the cascade line on IRQ 15)
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    OutOfRange,
+    // has a dedicated handler in the IDT, or is the cascade line
+    Reserved,
+    // someone else already registered a handler
+    InUse,
+}
+
+const NO_HANDLER: usize = 0;
+
+static IRQ_HANDLERS: [AtomicUsize; 16] = [const { AtomicUsize::new(NO_HANDLER) }; 16];
+// number of (non-spurious) IRQs that went through the trampoline per line
+static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+fn irq_vector(irq: u8) -> u8 {
+    if irq < 8 {
+        PIC_1_OFFSET + irq
+    } else {
+        PIC_2_OFFSET + irq - 8
+    }
+}
+
+fn is_routable(irq: u8) -> bool {
+    irq < 16 && !matches!(irq, TIMER_IRQ | KEYBOARD_IRQ | CASCADE_IRQ | SERIAL1_IRQ)
+}
+
+// register `handler` for a device IRQ (0-15) and unmask it
+pub fn request_irq(irq: u8, handler: fn()) -> Result<(), IrqError> {
+    if irq >= 16 {
+        return Err(IrqError::OutOfRange);
+    }
+    if !is_routable(irq) {
+        return Err(IrqError::Reserved);
+    }
+    IRQ_HANDLERS[irq as usize]
+        .compare_exchange(
+            NO_HANDLER,
+            handler as usize,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        .map_err(|_| IrqError::InUse)?;
+    unmask_irq(irq);
+    Ok(())
+}
+
+// mask the IRQ again and forget its handler
+pub fn free_irq(irq: u8) {
+    if is_routable(irq) {
+        mask_irq(irq);
+        IRQ_HANDLERS[irq as usize].store(NO_HANDLER, Ordering::SeqCst);
+    }
+}
+
+// None for anything past IRQ 15
+pub fn irq_count(irq: u8) -> Option<u64> {
+    IRQ_COUNTS
+        .get(irq as usize)
+        .map(|count| count.load(Ordering::Relaxed))
+}
+
+// read the In-Service Register of the PIC that owns `irq`
+fn irq_in_service(irq: u8) -> bool {
+    // OCW3: the next read of the command port returns the ISR
+    let mut cmd = pic_command(irq);
+    cmd.write(0x0b);
+    cmd.read_bit((irq % 8) as usize)
+}
+
+fn dispatch_irq(irq: u8) {
+    crate::debug_assert_in_interrupt!();
+    if (irq == 7 || irq == 15) && !irq_in_service(irq) {
+        if irq == 15 {
+            // the primary did see an IRQ on its cascade line
+            end_of_interrupt(CASCADE_IRQ);
+        }
+        return;
+    }
+
+    let handler = IRQ_HANDLERS[irq as usize].load(Ordering::SeqCst);
+    if handler != NO_HANDLER {
+        // only ever set from a fn() in request_irq()
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+
+    end_of_interrupt(irq);
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/*
+    Masking single IRQs
+
+    - each PIC has an 8-bit mask register (written through its data port),
+      a set bit means that IRQ line is ignored
+    - IRQs 8-15 come in through the secondary PIC which is cascaded into
+      line 2 of the primary, so that line has to be unmasked as well
+*/
+pub fn mask_irq(irq: u8) {
+    set_irq_masked(irq, true);
+}
+
+pub fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+}
+
+pub fn is_irq_masked(irq: u8) -> bool {
+    use bit_field::BitField;
+    use x86_64::instructions::interrupts;
+
+    let masks = interrupts::without_interrupts(|| unsafe { PICS.lock().read_masks() });
+    masks[(irq / 8) as usize].get_bit((irq % 8) as usize)
+}
+
+/*
+    run `f` with just this one IRQ masked, finer grained than
+    without_interrupts() since every other interrupt still gets through
+
+    - the IRQ's previous mask bit is put back afterwards rather than
+      blindly unmasking, so nested calls (or an IRQ that was already masked)
+      end up in the same state they started in
+*/
+pub fn with_irq_masked<F, R>(irq: u8, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let was_masked = set_irq_masked(irq, true);
+    let ret = f();
+    set_irq_masked(irq, was_masked);
+    ret
+}
+
+// returns whether the IRQ was masked before
+fn set_irq_masked(irq: u8, masked: bool) -> bool {
+    use bit_field::BitField;
+    use x86_64::instructions::interrupts;
+
+    assert!(irq < 16, "IRQ {} out of range", irq);
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let was_masked = masks[(irq / 8) as usize].get_bit((irq % 8) as usize);
+        masks[(irq / 8) as usize].set_bit((irq % 8) as usize, masked);
+        if irq >= 8 && !masked {
+            // cascade line
+            masks[0].set_bit(2, false);
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+        was_masked
+    })
 }
 
 /* ===== IDT TABLE ===== */
@@ -158,9 +447,102 @@ TABLE_IDX    |    INTERRUPT_TYPE
   0x0D       |    General Protection Fault
   0x0E       |    Page Fault
   0x0F       |    *reserved*
-  ... IDT for x86 continues but we will only worry about these
+  ... up to 0x1F, the ones without a handler of their own get a catch-all
+  (see unhandled_exceptions!)
 */
 
+// CPU exception vectors (0-31) so the IDT setup doesn't use magic numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub enum ExceptionVector {
+    DivideByZero = 0x00,
+    Debug = 0x01,
+    NonMaskableInterrupt = 0x02,
+    Breakpoint = 0x03,
+    Overflow = 0x04,
+    BoundRangeExceeded = 0x05,
+    InvalidOpcode = 0x06,
+    DeviceNotAvailable = 0x07,
+    DoubleFault = 0x08,
+    CoprocessorSegmentOverrun = 0x09,
+    InvalidTss = 0x0a,
+    SegmentNotPresent = 0x0b,
+    StackSegmentFault = 0x0c,
+    GeneralProtection = 0x0d,
+    PageFault = 0x0e,
+    X87FloatingPoint = 0x10,
+    AlignmentCheck = 0x11,
+    MachineCheck = 0x12,
+    SimdFloatingPoint = 0x13,
+    Virtualization = 0x14,
+    ControlProtection = 0x15,
+    HypervisorInjection = 0x1c,
+    VmmCommunication = 0x1d,
+    Security = 0x1e,
+}
+
+impl ExceptionVector {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        self as usize
+    }
+
+    // None for the reserved vectors
+    pub fn from_u8(vector: u8) -> Option<ExceptionVector> {
+        use ExceptionVector::*;
+        const VECTORS: [ExceptionVector; 24] = [
+            DivideByZero,
+            Debug,
+            NonMaskableInterrupt,
+            Breakpoint,
+            Overflow,
+            BoundRangeExceeded,
+            InvalidOpcode,
+            DeviceNotAvailable,
+            DoubleFault,
+            CoprocessorSegmentOverrun,
+            InvalidTss,
+            SegmentNotPresent,
+            StackSegmentFault,
+            GeneralProtection,
+            PageFault,
+            X87FloatingPoint,
+            AlignmentCheck,
+            MachineCheck,
+            SimdFloatingPoint,
+            Virtualization,
+            ControlProtection,
+            HypervisorInjection,
+            VmmCommunication,
+            Security,
+        ];
+        VECTORS.iter().copied().find(|v| v.as_u8() == vector)
+    }
+
+    // whether the CPU pushes an error code for this exception, i.e. whether
+    // its handler has to be wrapped with handler_with_errcode! or handler!
+    pub fn has_error_code(self) -> bool {
+        use ExceptionVector::*;
+        matches!(
+            self,
+            DoubleFault
+                | InvalidTss
+                | SegmentNotPresent
+                | StackSegmentFault
+                | GeneralProtection
+                | PageFault
+                | AlignmentCheck
+                | ControlProtection
+                | VmmCommunication
+                | Security
+        )
+    }
+}
+
 // creates a wrapper function to be passed to our set_handler() Idt method
 // takes a function identifier $name (not a string of the name nor ptr to function location!)
 macro_rules! handler {
@@ -180,7 +562,9 @@ macro_rules! handler {
                     push r11;
                     mov rdi, rsp;
                     add rdi, 9*8;
-                    call {};
+                    lock inc qword ptr [rip + {depth}];
+                    call {handler};
+                    lock dec qword ptr [rip + {depth}];
                     pop r11;
                     pop r10;
                     pop r9;
@@ -190,7 +574,7 @@ macro_rules! handler {
                     pop rdx;
                     pop rcx;
                     pop rax;
-                    iretq", sym $name);
+                    iretq", handler = sym $name, depth = sym HANDLER_DEPTH);
             }
         }
         wrapper
@@ -217,36 +601,151 @@ macro_rules! handler_with_errcode {
                     push r11;
                     mov rdi, rsp;
                     add rdi, 9*8;
-                    call {}
+                    lock inc qword ptr [rip + {depth}];
+                    call {handler};
+                    lock dec qword ptr [rip + {depth}];
+                    pop r11;
+                    pop r10;
+                    pop r9;
+                    pop r8;
+                    pop rdi;
+                    pop rsi;
+                    pop rdx;
+                    pop rcx;
+                    pop rax;
+                    iretq", handler = sym $name, depth = sym HANDLER_DEPTH);
+            }
+        }
+        wrapper
+    }};
+}
+
+// the same as handler! but saves every general purpose register and passes
+// a pointer to them (Registers) as the second argument, the handler can
+// change them and the new values are what the interrupted code resumes with
+// 15 pushes keep the stack 16 byte aligned for the call just like the 9 above
+macro_rules! handler_with_regs {
+    ($name: ident) => {{
+        #[naked]
+        extern "C" fn wrapper() -> ! {
+            unsafe {
+                naked_asm!("
+                    push rax;
+                    push rbx;
+                    push rcx;
+                    push rdx;
+                    push rsi;
+                    push rdi;
+                    push rbp;
+                    push r8;
+                    push r9;
+                    push r10;
+                    push r11;
+                    push r12;
+                    push r13;
+                    push r14;
+                    push r15;
+                    mov rsi, rsp;
+                    mov rdi, rsp;
+                    add rdi, 15*8;
+                    lock inc qword ptr [rip + {depth}];
+                    call {handler};
+                    lock dec qword ptr [rip + {depth}];
+                    pop r15;
+                    pop r14;
+                    pop r13;
+                    pop r12;
                     pop r11;
                     pop r10;
                     pop r9;
                     pop r8;
+                    pop rbp;
                     pop rdi;
                     pop rsi;
                     pop rdx;
                     pop rcx;
+                    pop rbx;
                     pop rax;
-                    iretq", sym $name);
+                    iretq", handler = sym $name, depth = sym HANDLER_DEPTH);
             }
         }
         wrapper
     }};
 }
 
+// install a handler for each IRQ line that just hands off to dispatch_irq()
+macro_rules! irq_trampolines {
+    ($idt: ident; $($irq: literal),*) => {
+        $({
+            extern "C" fn trampoline(_stack_frame: &ExceptionStackFrame) {
+                dispatch_irq($irq);
+            }
+            $idt.set_handler(irq_vector($irq) as usize, handler!(trampoline), None);
+        })*
+    };
+}
+
+/*
+    catch-all for every architectural exception (0-31) without a real
+    handler, so e.g. a #GP or #AC shows up as itself instead of escalating
+    to a double fault (and a triple fault if that goes wrong too)
+
+    - the vectors that push an error code have to be listed under
+      handler_with_errcode or the wrapper would iretq to the error code
+        - test builds also keep the lists as consts, to check them against
+          ExceptionVector::has_error_code()
+    - expands to set_unhandled_exceptions(), which fills in the IDT
+*/
+macro_rules! unhandled_exceptions {
+    (handler: $($vector: literal),*; handler_with_errcode: $($errcode_vector: literal),*) => {
+        #[cfg(test)]
+        const UNHANDLED_VECTORS: &[u8] = &[$($vector),*];
+        #[cfg(test)]
+        const UNHANDLED_ERRCODE_VECTORS: &[u8] = &[$($errcode_vector),*];
+
+        fn set_unhandled_exceptions(idt: &mut idt::Idt) {
+            $({
+                extern "C" fn catchall(stack_frame: &ExceptionStackFrame) -> ! {
+                    unhandled_exception($vector, stack_frame, None)
+                }
+                idt.set_handler($vector, handler!(catchall), None);
+            })*
+            $({
+                extern "C" fn catchall(stack_frame: &ExceptionStackFrame, err_code: u64) -> ! {
+                    unhandled_exception($errcode_vector, stack_frame, Some(err_code))
+                }
+                idt.set_handler($errcode_vector, handler_with_errcode!(catchall), None);
+            })*
+        }
+    };
+}
+
+unhandled_exceptions!(
+    handler: 1, 4, 5, 7, 9, 15, 16, 18, 19, 20, 22, 23, 24, 25, 26, 27, 28, 31;
+    handler_with_errcode: 10, 11, 12, 13, 17, 21, 29, 30
+);
+
 lazy_static! {
     pub static ref IDT: idt::Idt = {
         let mut idt = idt::Idt::new();
-        idt.set_handler(0, handler!(zero_div_handler), None);
-        idt.set_handler(3, handler!(breakpt_handler), None);
-        idt.set_handler(6, handler!(invalid_op_handler), None);
+        idt.set_handler(ExceptionVector::DivideByZero.as_usize(), handler!(zero_div_handler), None);
+        idt.set_handler(ExceptionVector::NonMaskableInterrupt.as_usize(), handler!(nmi_handler), None);
+        idt.set_handler(ExceptionVector::Breakpoint.as_usize(), handler_with_regs!(breakpt_handler), None);
+        idt.set_handler(ExceptionVector::InvalidOpcode.as_usize(), handler!(invalid_op_handler), None);
         // set double fault handler options (IST index)
         let mut double_fault_options = EntryOptions::new();
         double_fault_options.set_stack_idx(DOUBLE_FAULT_IST_IDX + 1);
-        idt.set_handler(8, handler_with_errcode!(double_fault_handler), Some(double_fault_options));
-        idt.set_handler(14, handler_with_errcode!(pg_fault_handler), None);
+        idt.set_handler(ExceptionVector::DoubleFault.as_usize(), handler_with_errcode!(double_fault_handler), Some(double_fault_options));
+        // page faults switch stacks too so one on a blown stack doesn't turn into a double fault
+        let mut page_fault_options = EntryOptions::new();
+        page_fault_options.set_stack_idx(PAGE_FAULT_IST_IDX + 1);
+        idt.set_handler(ExceptionVector::PageFault.as_usize(), handler_with_errcode!(pg_fault_handler), Some(page_fault_options));
         idt.set_handler(InterruptIndex::Timer.as_usize(), handler!(timer_interrupt_handler), None);
         idt.set_handler(InterruptIndex::Keyboard.as_usize(), handler!(keyboard_interrupt_handler), None);
+        idt.set_handler(InterruptIndex::Serial1.as_usize(), handler!(serial_interrupt_handler), None);
+        // everything else goes through request_irq()
+        irq_trampolines!(idt; 3, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+        set_unhandled_exceptions(&mut idt);
         idt
     };
 }
@@ -290,32 +789,396 @@ struct ExceptionStackFrame {
 // since we now need to call from a naked handler function (which only allows for assembly)
 // we need to know the real name of our function since naked_asm prohibits "in(reg)"
 extern "C" fn zero_div_handler(stack_frame: &ExceptionStackFrame) -> ! {
-    println!("EXCEPTION: DIVSION BY ZERO\n{:#x?}", &*stack_frame);
+    try_println!("EXCEPTION: DIVSION BY ZERO\n{:#x?}", &*stack_frame);
     crate::hlt_loop();
 }
 
-extern "C" fn breakpt_handler(stack_frame: &ExceptionStackFrame) {
-    println!("EXCEPTION: BREAKPOINT (INT3)\n{:#x?}", &*stack_frame);
+/*
+    Non-Maskable Interrupt (NMI)
+
+    - raised by the chipset for hardware errors (and watchdogs), `cli` does
+      not block it, which means it can land while the WRITER or SERIAL1
+      locks are held, so the handler can't print unless it is about to halt
+    - System Control Port B (0x61) says where it came from:
+        - bit 7: memory parity error (PCI SERR#)
+        - bit 6: I/O channel check (IOCHK#)
+        - neither set: something else (watchdog, `int 2`, ...)
+    - the CPU holds off further NMIs until the next iretq, but a fault inside
+      the handler would iretq early and let a second NMI nest, NMI_ACTIVE
+      catches that and the nested one is just counted
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmiCause {
+    MemoryParity,
+    IoChannelCheck,
+    Unknown,
+}
+
+const SYS_CTRL_PORT_B: u16 = 0x61;
+
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+static NMI_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// number of NMIs taken, including nested ones
+pub fn nmi_count() -> u64 {
+    NMI_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn nmi_cause(port_b: u8) -> NmiCause {
+    use bit_field::BitField;
+    if port_b.get_bit(7) {
+        NmiCause::MemoryParity
+    } else if port_b.get_bit(6) {
+        NmiCause::IoChannelCheck
+    } else {
+        NmiCause::Unknown
+    }
+}
+
+extern "C" fn nmi_handler(stack_frame: &ExceptionStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    NMI_COUNT.fetch_add(1, Ordering::Relaxed);
+    if NMI_ACTIVE.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut port_b: Port<u8> = Port::new(SYS_CTRL_PORT_B);
+    let cause = nmi_cause(unsafe { port_b.read() });
+    if cause != NmiCause::Unknown {
+        // the hardware is broken, memory can't be trusted, so stop here.
+        // we never return so whoever held the lock won't get it back anyway
+        unsafe { crate::vga_buf::WRITER.force_unlock() };
+        println!("NMI: {:?}, halting\n{:#x?}", cause, &*stack_frame);
+        crate::hlt_loop();
+    }
+
+    NMI_ACTIVE.store(false, Ordering::SeqCst);
+}
+
+// general purpose registers as pushed by handler_with_regs!, last push first
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl Registers {
+    // look a register up by its lowercase name, e.g. "rax" or "r12"
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.named()
+            .iter()
+            .find(|&&(n, _)| n == name)
+            .map(|&(_, v)| v)
+    }
+
+    fn named(&self) -> [(&'static str, u64); 15] {
+        [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+        ]
+    }
+}
+
+// 3 registers per row so the whole set fits in 5 rows of the VGA buffer
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, value)) in self.named().iter().enumerate() {
+            let sep = if i % 3 == 2 { "\n" } else { " " };
+            write!(f, "{:>3}={:016x}{}", name, value, sep)?;
+        }
+        Ok(())
+    }
+}
+
+/*
+    with a host debugger (QEMU + GDB) attached its software breakpoints are
+    int3s patched into the code, the debugger takes over from its own trap
+    handling so in debug mode the handler has to stay out of the way and
+    return without printing anything
+*/
+static DEBUG_MODE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_debug_mode(enabled: bool) {
+    DEBUG_MODE.store(enabled, Ordering::SeqCst);
+}
+
+pub fn debug_mode() -> bool {
+    DEBUG_MODE.load(Ordering::SeqCst)
+}
+
+// turn on the serial inspection prompt in the breakpoint handler
+static BREAKPOINT_PROMPT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_breakpoint_prompt(enabled: bool) {
+    BREAKPOINT_PROMPT.store(enabled, Ordering::SeqCst);
+}
+
+/*
+    Breakpoint (int3)
+
+    - a trap, so the saved instruction pointer is already past the int3 and
+      returning just carries on
+    - silent in debug mode (see set_debug_mode()), otherwise prints the
+      stack frame and every general purpose register
+    - with set_breakpoint_prompt(true) it then waits on COM1 for register
+      names (plus rip, rsp and rflags) to print, `c` continues
+        - interrupts are off in here so COM1 is polled directly rather than
+          going through the serial IRQ + task::serial
+*/
+extern "C" fn breakpt_handler(stack_frame: &ExceptionStackFrame, regs: &mut Registers) {
+    if debug_mode() {
+        return;
+    }
+    try_println!(
+        "EXCEPTION: BREAKPOINT (INT3)\n{:#x?}\n{}",
+        &*stack_frame,
+        regs
+    );
+    if !BREAKPOINT_PROMPT.load(Ordering::SeqCst) {
+        return;
+    }
+
+    serial_println!("BREAKPOINT: enter a register name to print it, c to continue");
+    let mut buf = [0u8; 16];
+    loop {
+        crate::serial_print!("(bp) ");
+        crate::serial::flush();
+        let value = match serial_read_line(&mut buf) {
+            "c" => break,
+            "rip" => Some(stack_frame.instr_ptr),
+            "rsp" => Some(stack_frame.stack_ptr),
+            "rflags" => Some(stack_frame.rflags),
+            name => regs.get(name),
+        };
+        match value {
+            Some(value) => serial_println!("{:#018x}", value),
+            None => serial_println!("unknown register"),
+        }
+    }
+}
+
+// read one line from COM1 by polling, anything past the end of buf is dropped
+fn serial_read_line(buf: &mut [u8]) -> &str {
+    use crate::serial::base;
+    use x86_64::instructions::port::Port;
+
+    let mut line_sts: Port<u8> = Port::new(base() + 5);
+    let mut data: Port<u8> = Port::new(base());
+    let mut len = 0;
+    loop {
+        while unsafe { line_sts.read() } & 0x1 == 0 {
+            core::hint::spin_loop();
+        }
+        match unsafe { data.read() } {
+            b'\r' | b'\n' => break,
+            byte @ 0x20..=0x7e if len < buf.len() => {
+                buf[len] = byte;
+                len += 1;
+                crate::serial_print!("{}", byte as char);
+                crate::serial::flush();
+            }
+            _ => {}
+        }
+    }
+    crate::serial_println!();
+    // only printable ASCII went in
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/*
+    Invalid Opcode
+
+    - Rust's abort (core::intrinsics::abort, panic=abort fallbacks, ...) is
+      an intentional `ud2` (0x0f 0x0b), so check the bytes at the faulting
+      instruction to tell that apart from really bad code
+    - it's a fault so instr_ptr is the offending instruction itself, but
+      only its first byte is sure to be readable: an invalid one byte opcode
+      can be the last byte of a page with nothing mapped after it
+        - so the second byte is only read for a 0x0f on the same page, a
+          ud2 split across two pages comes out as Other rather than risking
+          a page fault in here
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidOp {
+    Ud2,
+    Other,
+}
+
+const UD2: [u8; 2] = [0x0f, 0x0b];
+
+pub fn classify_invalid_op(instr: [u8; 2]) -> InvalidOp {
+    if instr == UD2 {
+        InvalidOp::Ud2
+    } else {
+        InvalidOp::Other
+    }
+}
+
+// the first two bytes at `addr`, the second one is 0 if it wasn't read
+fn read_opcode(addr: u64) -> [u8; 2] {
+    let first = unsafe { core::ptr::read_volatile(addr as *const u8) };
+    let same_page = addr % 4096 != 4095;
+    let second = if first == UD2[0] && same_page {
+        unsafe { core::ptr::read_volatile((addr + 1) as *const u8) }
+    } else {
+        0
+    };
+    [first, second]
+}
+
+fn faulting_instr(stack_frame: &ExceptionStackFrame) -> InvalidOp {
+    classify_invalid_op(read_opcode(stack_frame.instr_ptr))
 }
 
 extern "C" fn invalid_op_handler(stack_frame: &ExceptionStackFrame) -> ! {
-    println!("EXCEPTION: INVALID OPCODE\n{:#x?}", &*stack_frame);
+    match faulting_instr(stack_frame) {
+        InvalidOp::Ud2 => try_println!("EXCEPTION: RUST ABORT / ud2\n{:#x?}", &*stack_frame),
+        InvalidOp::Other => try_println!("EXCEPTION: INVALID OPCODE\n{:#x?}", &*stack_frame),
+    }
+    crate::hlt_loop();
+}
+
+// fn(vector, error code) stored as a plain usize, 0 means none
+static UNHANDLED_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+// called by the catch-all handlers right before they halt
+pub fn set_unhandled_exception_hook(hook: fn(u8, Option<u64>)) {
+    UNHANDLED_HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
+fn unhandled_exception(vector: u8, stack_frame: &ExceptionStackFrame, err_code: Option<u64>) -> ! {
+    let name = ExceptionVector::from_u8(vector);
+    match err_code {
+        Some(code) => try_println!(
+            "EXCEPTION: UNHANDLED VECTOR {} ({:?}) with error code: {:#x}\n{:#x?}",
+            vector,
+            name,
+            code,
+            &*stack_frame
+        ),
+        None => try_println!(
+            "EXCEPTION: UNHANDLED VECTOR {} ({:?})\n{:#x?}",
+            vector,
+            name,
+            &*stack_frame
+        ),
+    }
+
+    let hook = UNHANDLED_HOOK.load(Ordering::SeqCst);
+    if hook != 0 {
+        // only ever set from a fn(u8, Option<u64>) in set_unhandled_exception_hook()
+        let hook: fn(u8, Option<u64>) = unsafe { core::mem::transmute(hook) };
+        hook(vector, err_code);
+    }
     crate::hlt_loop();
 }
 
 // disabling this for now until the double-fault handler is finished for testing
 #[allow(dead_code)]
 extern "C" fn overflow_handler(stack_frame: &ExceptionStackFrame) -> ! {
-    println!("EXCEPTION: OVERFLOW\n{:#x?}", &*stack_frame);
+    try_println!("EXCEPTION: OVERFLOW\n{:#x?}", &*stack_frame);
     crate::hlt_loop();
 }
 
-extern "C" fn double_fault_handler(stack_frame: &ExceptionStackFrame, err_code: u64) -> ! {
-    println!(
+/*
+    What the double fault handler does once it has printed the fault
+
+    - Halt (default): hlt_loop(), everything is left as it was for a
+      debugger to look at
+    - RecoverAndLog: iretq into the function given to
+      set_double_fault_recovery() on gdt::recovery_stack_top() instead
+        - DANGEROUS: whatever was running is abandoned wherever it was, any
+          lock it held stays locked and anything it was half way through
+          changing stays half changed, the recovery function should do
+          little more than report and shut down or reboot
+        - only tried once, a double fault after that halts
+        - with no recovery function set it halts just like Halt
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DoubleFaultPolicy {
+    Halt,
+    RecoverAndLog,
+}
+
+static DOUBLE_FAULT_POLICY: AtomicU8 = AtomicU8::new(DoubleFaultPolicy::Halt as u8);
+// fn() -> ! stored as a plain usize, 0 means none
+static DOUBLE_FAULT_RECOVERY: AtomicUsize = AtomicUsize::new(0);
+static RECOVERED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_double_fault_policy(policy: DoubleFaultPolicy) {
+    DOUBLE_FAULT_POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+pub fn double_fault_policy() -> DoubleFaultPolicy {
+    match DOUBLE_FAULT_POLICY.load(Ordering::SeqCst) {
+        x if x == DoubleFaultPolicy::RecoverAndLog as u8 => DoubleFaultPolicy::RecoverAndLog,
+        _ => DoubleFaultPolicy::Halt,
+    }
+}
+
+// where RecoverAndLog resumes, runs with interrupts as they were at the fault
+pub fn set_double_fault_recovery(recovery: fn() -> !) {
+    DOUBLE_FAULT_RECOVERY.store(recovery as usize, Ordering::SeqCst);
+}
+
+// point the faulting context at the recovery function and a fresh stack,
+// returns false if the policy says to halt (or recovery isn't possible)
+fn recover_double_fault(stack_frame: &mut ExceptionStackFrame) -> bool {
+    let recovery = DOUBLE_FAULT_RECOVERY.load(Ordering::SeqCst);
+    if double_fault_policy() != DoubleFaultPolicy::RecoverAndLog
+        || recovery == 0
+        || RECOVERED.swap(true, Ordering::SeqCst)
+    {
+        return false;
+    }
+    stack_frame.instr_ptr = recovery as u64;
+    // as if the recovery function had been called: 16 byte aligned before
+    // the (never used) return address
+    stack_frame.stack_ptr = (crate::gdt::recovery_stack_top().as_u64() & !0xf) - 8;
+    true
+}
+
+extern "C" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, err_code: u64) {
+    try_println!(
         "EXCEPTION: DOUBLE FAULT with error code: {:#x}\n{:#x?}",
-        err_code, &*stack_frame
+        err_code,
+        &*stack_frame
     );
-    crate::hlt_loop();
+    if !recover_double_fault(stack_frame) {
+        crate::hlt_loop();
+    }
+    // copied out, the frame is packed
+    let resume_at = stack_frame.instr_ptr;
+    try_println!("RECOVERING FROM DOUBLE FAULT at {:#x}", resume_at);
 }
 
 /*
@@ -329,14 +1192,14 @@ extern "C" fn double_fault_handler(stack_frame: &ExceptionStackFrame, err_code:
 */
 extern "C" fn pg_fault_handler(stack_frame: &ExceptionStackFrame, err_code: u64) -> ! {
     use x86_64::registers::control::Cr2;
-    let error = match err_code {
-        0x1 => "PROTECTION_VIOLATION",
-        0x2 => "CAUSED_BY_WRITE",
-        0x4 => "USER_MODE",
-        0x8 => "MALFORMED_TABLE",
-        0x10 => "INSTRUCTION_FETCH",
-        _ => "UNKNOWN",
-    };
+    let error = PageFaultErrorCode::new(err_code);
+
+    // read_raw() since Cr2::read() would panic building a VirtAddr from a
+    // non-canonical address
+    let addr = Cr2::read_raw();
+    if let Some(msg) = fault_addr_message(addr) {
+        try_println!("{}", msg);
+    }
 
     /*
         Get the physical address of the top level page table by reading the
@@ -346,27 +1209,179 @@ extern "C" fn pg_fault_handler(stack_frame: &ExceptionStackFrame, err_code: u64)
                 - some support up to 5 levels but they are still compatible
                   with 4-level page tables
     */
-    println!(
+    try_println!(
         "EXCEPTION: PAGE FAULT\nAddr: {:#x}\nError Code: {}\n{:#x?}",
-        Cr2::read().as_u64(),
+        addr,
         error,
         &*stack_frame
     );
     crate::hlt_loop();
 }
 
+/*
+    The error code the CPU pushes for a page fault
+
+    - each bit is a separate fact about the access, any combination can be
+      set at once e.g. 0x3 is a write to a present read-only page
+    - bit 0 clear means the page wasn't present at all
+    - Display always starts with NOT_PRESENT or PROTECTION_VIOLATION (bit 0),
+      then lists every other set flag plus any bits it doesn't know about
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultErrorCode(u64);
+
+impl PageFaultErrorCode {
+    // bit 0 isn't here, Display always prints one of its two meanings
+    const FLAGS: [(u64, &'static str); 4] = [
+        (0x2, "CAUSED_BY_WRITE"),
+        (0x4, "USER_MODE"),
+        (0x8, "MALFORMED_TABLE"),
+        (0x10, "INSTRUCTION_FETCH"),
+    ];
+
+    pub fn new(err_code: u64) -> Self {
+        PageFaultErrorCode(err_code)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    // the page was present, so it was the access that wasn't allowed
+    pub fn protection_violation(&self) -> bool {
+        self.0 & 0x1 != 0
+    }
+
+    // a write rather than a read
+    pub fn caused_by_write(&self) -> bool {
+        self.0 & 0x2 != 0
+    }
+
+    // the access came from ring 3
+    pub fn user_mode(&self) -> bool {
+        self.0 & 0x4 != 0
+    }
+
+    // a reserved bit was set in one of the page table entries
+    pub fn malformed_table(&self) -> bool {
+        self.0 & 0x8 != 0
+    }
+
+    // fetching an instruction, e.g. jumping into a NO_EXECUTE page
+    pub fn instruction_fetch(&self) -> bool {
+        self.0 & 0x10 != 0
+    }
+}
+
+impl fmt::Display for PageFaultErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.protection_violation() {
+            f.write_str("PROTECTION_VIOLATION")?;
+        } else {
+            f.write_str("NOT_PRESENT")?;
+        }
+        let mut rest = self.0 & !0x1;
+        for &(bit, name) in Self::FLAGS.iter() {
+            if self.0 & bit != 0 {
+                write!(f, " | {}", name)?;
+                rest &= !bit;
+            }
+        }
+        if rest != 0 {
+            write!(f, " | UNKNOWN({:#x})", rest)?;
+        }
+        Ok(())
+    }
+}
+
+// the kind of address a fault happened at, used to call out the usual bugs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAddr {
+    // anywhere in the first (never mapped) page, e.g. a field of a null struct ptr
+    Null,
+    // bits 48..63 aren't copies of bit 47
+    // note: the CPU raises a #GP rather than a #PF when *accessing* one of
+    // these, but a corrupted pointer can still end up in CR2 via other paths
+    NonCanonical,
+    Other,
+}
+
+// the line pg_fault_handler prints before the fault itself, if any
+pub fn fault_addr_message(addr: u64) -> Option<&'static str> {
+    match classify_fault_addr(addr) {
+        FaultAddr::Null => Some("NULL POINTER DEREFERENCE"),
+        FaultAddr::NonCanonical => Some("NON-CANONICAL ADDRESS"),
+        FaultAddr::Other => None,
+    }
+}
+
+pub fn classify_fault_addr(addr: u64) -> FaultAddr {
+    // bits 47..63 must be all 0s or all 1s
+    let sign_bits = addr >> 47;
+    if addr < 0x1000 {
+        FaultAddr::Null
+    } else if sign_bits != 0 && sign_bits != 0x1ffff {
+        FaultAddr::NonCanonical
+    } else {
+        FaultAddr::Other
+    }
+}
+
 /* ===== INIT ===== */
 pub fn init() {
     IDT.load();
 }
 
+// check the CPU's IDTR actually points at our IDT
+pub fn is_loaded() -> bool {
+    use x86_64::instructions::tables::sidt;
+    use x86_64::VirtAddr;
+    let base = sidt().base;
+    base == VirtAddr::from_ptr(&*IDT)
+}
+
+// write the PICs' mask registers and read them back, a missing or broken
+// PIC won't hold on to the value
+pub fn pics_respond() -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let masks = pics.read_masks();
+            pics.write_masks(!masks[0], !masks[1]);
+            let flipped = pics.read_masks();
+            pics.write_masks(masks[0], masks[1]);
+            flipped == [!masks[0], !masks[1]] && pics.read_masks() == masks
+        }
+    })
+}
+
 /* ===== TESTING ===== */
 
 // IDT to be used in integration tests where we can install test handlers
 lazy_static! {
     pub static ref TEST_IDT: idt::Idt = {
         let mut idt = idt::Idt::new();
-        idt.set_handler(0, handler!(test_zero_div_handler), None);
+        idt.set_handler(
+            ExceptionVector::DivideByZero.as_usize(),
+            handler!(test_zero_div_handler),
+            None,
+        );
+        idt.set_handler(
+            ExceptionVector::InvalidOpcode.as_usize(),
+            handler!(test_invalid_op_handler),
+            None,
+        );
+        // same stack as IDT so tests can fault with a broken rsp, which
+        // needs gdt::init() to have loaded the TSS
+        let mut page_fault_options = EntryOptions::new();
+        page_fault_options.set_stack_idx(PAGE_FAULT_IST_IDX + 1);
+        idt.set_handler(
+            ExceptionVector::PageFault.as_usize(),
+            handler_with_errcode!(test_pg_fault_handler),
+            Some(page_fault_options),
+        );
         idt
     };
 }
@@ -377,6 +1392,504 @@ extern "C" fn test_zero_div_handler(_stack_frame: &ExceptionStackFrame) -> ! {
     crate::hlt_loop();
 }
 
+// only a ud2 counts as a pass
+extern "C" fn test_invalid_op_handler(stack_frame: &ExceptionStackFrame) -> ! {
+    match faulting_instr(stack_frame) {
+        InvalidOp::Ud2 => {
+            serial_println!("RUST ABORT / ud2");
+            serial_println!("[ok]");
+            crate::exit_qemu(crate::QEMUExitCode::Success);
+        }
+        InvalidOp::Other => {
+            serial_println!("[failed]\nINVALID OPCODE, expected a ud2");
+            crate::exit_qemu(crate::QEMUExitCode::Failure);
+        }
+    }
+    crate::hlt_loop();
+}
+
+// the page fault TEST_IDT's handler counts as a pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExpectedPgFault {
+    // any access in the first page
+    NullDeref,
+    // a write to a present, read-only page
+    WriteProtect,
+    // pushing onto an unmapped stack, the handler has to be running on the
+    // page fault IST stack
+    BadStack,
+}
+
+static EXPECTED_PG_FAULT: AtomicU8 = AtomicU8::new(ExpectedPgFault::NullDeref as u8);
+
+pub fn expect_pg_fault(kind: ExpectedPgFault) {
+    EXPECTED_PG_FAULT.store(kind as u8, Ordering::SeqCst);
+}
+
+extern "C" fn test_pg_fault_handler(stack_frame: &ExceptionStackFrame, err_code: u64) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    let expected = match EXPECTED_PG_FAULT.load(Ordering::SeqCst) {
+        x if x == ExpectedPgFault::NullDeref as u8 => ExpectedPgFault::NullDeref,
+        x if x == ExpectedPgFault::WriteProtect as u8 => ExpectedPgFault::WriteProtect,
+        _ => ExpectedPgFault::BadStack,
+    };
+    let err = PageFaultErrorCode::new(err_code);
+    let addr = Cr2::read_raw();
+    let fault_addr = classify_fault_addr(addr);
+
+    let passed = match expected {
+        ExpectedPgFault::NullDeref => fault_addr == FaultAddr::Null,
+        ExpectedPgFault::WriteProtect => err.protection_violation() && err.caused_by_write(),
+        ExpectedPgFault::BadStack => {
+            // the faulting push was to just below the old rsp
+            let here = &err as *const _ as usize;
+            let stack_top = crate::gdt::page_fault_stack_top().as_u64() as usize;
+            err.caused_by_write()
+                && addr == stack_frame.stack_ptr.wrapping_sub(8)
+                && here < stack_top
+                && here >= crate::gdt::page_fault_stack_bottom()
+        }
+    };
+    if passed {
+        // the same line pg_fault_handler would have printed
+        if let Some(msg) = fault_addr_message(addr) {
+            serial_println!("{}", msg);
+        }
+        serial_println!("[ok]");
+        crate::exit_qemu(crate::QEMUExitCode::Success);
+    } else {
+        serial_println!(
+            "[failed]\nexpected {:?}, got {:?} at a {:?} address",
+            expected,
+            err,
+            fault_addr
+        );
+        crate::exit_qemu(crate::QEMUExitCode::Failure);
+    }
+    crate::hlt_loop();
+}
+
+#[cfg(test)]
+fn fmt_err_code(err_code: u64) -> crate::util::StackBuf<96> {
+    use core::fmt::Write;
+
+    let mut buf = crate::util::StackBuf::new();
+    write!(buf, "{}", PageFaultErrorCode::new(err_code)).unwrap();
+    buf
+}
+
+#[test_case]
+fn test_decode_pg_fault_error_code() {
+    // write to a present read-only page, used to come out as UNKNOWN
+    let err = PageFaultErrorCode::new(0x3);
+    assert!(err.protection_violation() && err.caused_by_write());
+    assert!(!err.user_mode() && !err.malformed_table() && !err.instruction_fetch());
+    assert_eq!(
+        fmt_err_code(0x3).as_str(),
+        "PROTECTION_VIOLATION | CAUSED_BY_WRITE"
+    );
+
+    let err = PageFaultErrorCode::new(0x7);
+    assert!(err.protection_violation() && err.caused_by_write() && err.user_mode());
+    assert_eq!(
+        fmt_err_code(0x7).as_str(),
+        "PROTECTION_VIOLATION | CAUSED_BY_WRITE | USER_MODE"
+    );
+
+    // ring 3 jumping into a page that isn't there
+    let err = PageFaultErrorCode::new(0x14);
+    assert!(err.user_mode() && err.instruction_fetch());
+    assert!(!err.protection_violation() && !err.caused_by_write());
+    assert_eq!(
+        fmt_err_code(0x14).as_str(),
+        "NOT_PRESENT | USER_MODE | INSTRUCTION_FETCH"
+    );
+
+    assert_eq!(fmt_err_code(0).as_str(), "NOT_PRESENT");
+    assert_eq!(
+        fmt_err_code(0x22).as_str(),
+        "NOT_PRESENT | CAUSED_BY_WRITE | UNKNOWN(0x20)"
+    );
+}
+
+#[test_case]
+fn test_classify_fault_addr() {
+    assert_eq!(classify_fault_addr(0), FaultAddr::Null);
+    assert_eq!(classify_fault_addr(0x8), FaultAddr::Null);
+    assert_eq!(classify_fault_addr(0x1000), FaultAddr::Other);
+    assert_eq!(classify_fault_addr(0x0000_7fff_ffff_ffff), FaultAddr::Other);
+    assert_eq!(classify_fault_addr(0xffff_8000_0000_0000), FaultAddr::Other);
+    assert_eq!(
+        classify_fault_addr(0x0000_8000_0000_0000),
+        FaultAddr::NonCanonical
+    );
+    assert_eq!(
+        classify_fault_addr(0xdead_beef_0000_0000),
+        FaultAddr::NonCanonical
+    );
+}
+
+// what pg_fault_handler actually prints for each kind of address
+#[test_case]
+fn test_fault_addr_message() {
+    assert_eq!(fault_addr_message(0), Some("NULL POINTER DEREFERENCE"));
+    assert_eq!(
+        fault_addr_message(0x0000_8000_0000_0000),
+        Some("NON-CANONICAL ADDRESS")
+    );
+    assert_eq!(fault_addr_message(0x1000), None);
+}
+
 pub fn init_test() {
     TEST_IDT.load();
 }
+
+#[test_case]
+fn test_are_enabled_follows_cli_sti() {
+    use x86_64::instructions::interrupts;
+
+    // the test runner runs with interrupts on
+    assert!(are_enabled());
+    interrupts::disable();
+    assert!(!are_enabled());
+    crate::debug_assert_interrupts_disabled!();
+    interrupts::enable();
+    assert!(are_enabled());
+    interrupts::without_interrupts(|| crate::debug_assert_interrupts_disabled!());
+}
+
+// test that a `cli` isn't mistaken for being in a handler
+#[test_case]
+fn test_in_handler() {
+    use x86_64::instructions::interrupts;
+
+    assert!(!in_handler());
+    interrupts::without_interrupts(|| assert!(!in_handler()));
+    run_as_handler(|| {
+        assert!(in_handler() && !are_enabled());
+        crate::debug_assert_in_interrupt!();
+    });
+    assert!(!in_handler());
+}
+
+#[test_case]
+fn test_with_irq_masked_restores() {
+    let before = is_irq_masked(KEYBOARD_IRQ);
+    let ret = with_irq_masked(KEYBOARD_IRQ, || {
+        assert!(is_irq_masked(KEYBOARD_IRQ));
+        // nested scope must not unmask it on the way out
+        with_irq_masked(KEYBOARD_IRQ, || assert!(is_irq_masked(KEYBOARD_IRQ)));
+        assert!(is_irq_masked(KEYBOARD_IRQ));
+        42
+    });
+    assert_eq!(ret, 42);
+    assert_eq!(is_irq_masked(KEYBOARD_IRQ), before);
+
+    // an already masked IRQ stays masked afterwards
+    mask_irq(KEYBOARD_IRQ);
+    with_irq_masked(KEYBOARD_IRQ, || {});
+    assert!(is_irq_masked(KEYBOARD_IRQ));
+    if !before {
+        unmask_irq(KEYBOARD_IRQ);
+    }
+}
+
+#[test_case]
+fn test_nmi_cause() {
+    assert_eq!(nmi_cause(0x80), NmiCause::MemoryParity);
+    assert_eq!(nmi_cause(0xc0), NmiCause::MemoryParity);
+    assert_eq!(nmi_cause(0x40), NmiCause::IoChannelCheck);
+    // refresh toggle and timer 2 output bits don't count
+    assert_eq!(nmi_cause(0x30), NmiCause::Unknown);
+}
+
+// a software NMI leaves port 0x61 alone so it has to be treated as
+// spurious, i.e. the handler runs and then returns
+#[test_case]
+fn test_synthetic_nmi_continues() {
+    let before = nmi_count();
+    unsafe { core::arch::asm!("int 2") };
+    assert_eq!(nmi_count(), before + 1);
+    assert!(!NMI_ACTIVE.load(Ordering::SeqCst));
+}
+
+#[cfg(test)]
+static REQUESTED_IRQ_CALLS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+fn count_requested_irq() {
+    REQUESTED_IRQ_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+// a software interrupt stands in for the device, IRQ 5 is on the primary
+// PIC and IRQ 10 on the secondary
+#[test_case]
+fn test_request_irq_routes_and_eois() {
+    use core::arch::asm;
+
+    assert_eq!(
+        request_irq(TIMER_IRQ, count_requested_irq),
+        Err(IrqError::Reserved)
+    );
+    assert_eq!(
+        request_irq(16, count_requested_irq),
+        Err(IrqError::OutOfRange)
+    );
+
+    for &irq in &[5, 10] {
+        let calls = REQUESTED_IRQ_CALLS.load(Ordering::SeqCst);
+        let handled = irq_count(irq).unwrap();
+        request_irq(irq, count_requested_irq).unwrap();
+        assert_eq!(request_irq(irq, count_requested_irq), Err(IrqError::InUse));
+        assert!(!is_irq_masked(irq));
+
+        unsafe {
+            if irq == 5 {
+                asm!("int 0x25");
+            } else {
+                asm!("int 0x2a");
+            }
+        }
+        assert_eq!(REQUESTED_IRQ_CALLS.load(Ordering::SeqCst), calls + 1);
+        // irq_count only goes up once the EOI has been sent
+        assert_eq!(irq_count(irq), Some(handled + 1));
+
+        free_irq(irq);
+        assert!(is_irq_masked(irq));
+    }
+    assert_eq!(irq_count(16), None);
+}
+
+// CMOS register `reg`, bit 7 of the index port is left clear so NMIs stay on
+#[cfg(test)]
+fn cmos_port(reg: u8) -> PortRegister<u8> {
+    unsafe {
+        PortRegister::new(0x70).write(reg);
+        PortRegister::new(0x71)
+    }
+}
+
+/*
+    a software int never goes through the PICs, so for the EOI this uses a
+    real device: the RTC's periodic interrupt on IRQ 8
+
+    - the secondary PIC won't pass on another IRQ 8 (and the primary won't
+      pass on anything from the cascade line) until the last one got its
+      EOI, so a second delivery shows both EOIs went out
+    - the RTC itself only raises the next one once register C was read
+*/
+#[test_case]
+fn test_request_irq_eoi_lets_next_irq_in() {
+    static RTC_IRQS: AtomicU64 = AtomicU64::new(0);
+    fn rtc_irq() {
+        cmos_port(0x0c).read();
+        RTC_IRQS.fetch_add(1, Ordering::SeqCst);
+    }
+    use x86_64::instructions::{hlt, interrupts::without_interrupts};
+
+    const RTC_IRQ: u8 = 8;
+    request_irq(RTC_IRQ, rtc_irq).unwrap();
+    // register B bit 6 turns the periodic interrupt on, at the rate in
+    // register A (1024 Hz out of reset)
+    without_interrupts(|| {
+        let reg_b = cmos_port(0x0b).read();
+        cmos_port(0x0b).write(reg_b | 0x40);
+        cmos_port(0x0c).read();
+    });
+
+    let start = ticks();
+    while RTC_IRQS.load(Ordering::SeqCst) < 2 && ticks() - start < 100 {
+        hlt();
+    }
+
+    without_interrupts(|| {
+        let reg_b = cmos_port(0x0b).read();
+        cmos_port(0x0b).write(reg_b & !0x40);
+        cmos_port(0x0c).read();
+    });
+    free_irq(RTC_IRQ);
+    assert!(
+        RTC_IRQS.load(Ordering::SeqCst) >= 2,
+        "only {} RTC interrupts arrived",
+        RTC_IRQS.load(Ordering::SeqCst)
+    );
+}
+
+// nothing new should reach the screen while in debug mode
+#[test_case]
+fn test_debug_mode_silences_breakpoint() {
+    use crate::vga_buf::{BUFFER_HEIGHT, WRITER};
+    use x86_64::instructions::interrupts::{int3, without_interrupts};
+
+    let marker_row = || without_interrupts(|| WRITER.lock().snapshot()[BUFFER_HEIGHT - 2]);
+    println!("test_debug_mode_silences_breakpoint marker");
+    let before = marker_row();
+
+    set_debug_mode(true);
+    int3();
+    set_debug_mode(false);
+    assert!(marker_row() == before);
+
+    int3();
+    assert!(marker_row() != before);
+}
+
+#[test_case]
+fn test_exception_vector_from_u8() {
+    for vector in 0..32u8 {
+        if let Some(exception) = ExceptionVector::from_u8(vector) {
+            assert_eq!(exception.as_u8(), vector);
+        }
+    }
+    assert_eq!(
+        ExceptionVector::from_u8(17),
+        Some(ExceptionVector::AlignmentCheck)
+    );
+    assert_eq!(ExceptionVector::from_u8(15), None);
+    assert_eq!(ExceptionVector::from_u8(0x20), None);
+}
+
+#[cfg(test)]
+extern "C" fn test_idt_entry() -> ! {
+    loop {}
+}
+
+// test that entries() reports set vectors with their handler and None otherwise
+#[test_case]
+fn test_idt_entries() {
+    let mut idt = idt::Idt::new();
+    idt.set_handler(3, test_idt_entry, None);
+    let mut not_present = EntryOptions::new();
+    not_present.set_present(false);
+    idt.set_handler(0x40, test_idt_entry, Some(not_present));
+
+    let addr = test_idt_entry as idt::HandlerFunc as u64;
+    assert_eq!(idt.entries().count(), 256);
+    for (vector, handler) in idt.entries() {
+        match vector {
+            3 => assert_eq!(handler, Some(addr)),
+            _ => assert_eq!(handler, None, "vector {}", vector),
+        }
+    }
+
+    // and on the real one
+    let (_, timer) = IDT.entries().nth(InterruptIndex::Timer.as_usize()).unwrap();
+    assert_eq!(
+        timer,
+        Some(IDT.handler_addr(InterruptIndex::Timer.as_usize()))
+    );
+    assert_eq!(IDT.entries().nth(0x80).unwrap(), (0x80, None));
+}
+
+// a vector in the wrong list would have its handler pop an error code the
+// CPU never pushed (or not pop one it did) and iretq to garbage
+#[test_case]
+fn test_unhandled_exception_lists() {
+    // the reserved vectors don't push one
+    let has_error_code =
+        |vector: u8| ExceptionVector::from_u8(vector).map_or(false, |v| v.has_error_code());
+    for &vector in UNHANDLED_VECTORS {
+        assert!(
+            !has_error_code(vector),
+            "vector {} pushes an error code",
+            vector
+        );
+    }
+    for &vector in UNHANDLED_ERRCODE_VECTORS {
+        assert!(
+            has_error_code(vector),
+            "vector {} has no error code",
+            vector
+        );
+    }
+}
+
+#[test_case]
+fn test_classify_invalid_op() {
+    assert_eq!(classify_invalid_op([0x0f, 0x0b]), InvalidOp::Ud2);
+    // ud1 and a reversed ud2
+    assert_eq!(classify_invalid_op([0x0f, 0xb9]), InvalidOp::Other);
+    assert_eq!(classify_invalid_op([0x0b, 0x0f]), InvalidOp::Other);
+}
+
+// test that the byte after the last one of a page is left alone
+#[test_case]
+fn test_read_opcode_stays_on_page() {
+    #[repr(align(4096))]
+    struct TwoPages([u8; 8192]);
+
+    static PAGES: TwoPages = TwoPages({
+        let mut bytes = [0; 8192];
+        bytes[100] = 0x0f;
+        bytes[101] = 0x0b;
+        bytes[4095] = 0x0f;
+        bytes[4096] = 0x0b;
+        bytes[200] = 0x90;
+        bytes[201] = 0x0b;
+        bytes
+    });
+
+    let addr = |i: usize| &PAGES.0[i] as *const u8 as u64;
+    assert_eq!(read_opcode(addr(100)), UD2);
+    assert_eq!(read_opcode(addr(4095)), [0x0f, 0]);
+    // not a 0x0f, so not worth reading on
+    assert_eq!(read_opcode(addr(200)), [0x90, 0]);
+}
+
+#[test_case]
+fn test_eoi_targets() {
+    for irq in 0..8 {
+        assert_eq!(eoi_targets(irq), EoiTargets::Primary);
+    }
+    for irq in 8..16 {
+        assert_eq!(eoi_targets(irq), EoiTargets::SecondaryThenPrimary);
+    }
+}
+
+#[test_case]
+fn test_exception_vector_error_codes() {
+    assert!(ExceptionVector::DoubleFault.has_error_code());
+    assert!(ExceptionVector::GeneralProtection.has_error_code());
+    assert!(ExceptionVector::PageFault.has_error_code());
+    assert!(!ExceptionVector::Breakpoint.has_error_code());
+    assert!(!ExceptionVector::DivideByZero.has_error_code());
+    assert_eq!(ExceptionVector::PageFault.as_u8(), 14);
+}
+
+// test that the handler only redirects the faulting context when told to
+#[test_case]
+fn test_double_fault_policy_consulted() {
+    fn recovery() -> ! {
+        crate::hlt_loop();
+    }
+    let fresh_frame = || ExceptionStackFrame {
+        instr_ptr: 0x1234,
+        code_seg: 0x8,
+        rflags: 0x2,
+        stack_ptr: 0x5678,
+        stack_seg: 0,
+    };
+
+    // Halt by default, even with a recovery function
+    assert_eq!(double_fault_policy(), DoubleFaultPolicy::Halt);
+    set_double_fault_recovery(recovery);
+    let mut frame = fresh_frame();
+    assert!(!recover_double_fault(&mut frame));
+    assert_eq!((frame.instr_ptr, frame.stack_ptr), (0x1234, 0x5678));
+
+    set_double_fault_policy(DoubleFaultPolicy::RecoverAndLog);
+    assert!(recover_double_fault(&mut frame));
+    assert_eq!({ frame.instr_ptr }, recovery as fn() -> ! as u64);
+    let top = crate::gdt::recovery_stack_top().as_u64();
+    assert!(frame.stack_ptr < top && frame.stack_ptr % 16 == 8);
+
+    // only once
+    let mut frame = fresh_frame();
+    assert!(!recover_double_fault(&mut frame));
+    assert_eq!({ frame.instr_ptr }, 0x1234);
+
+    set_double_fault_policy(DoubleFaultPolicy::Halt);
+    DOUBLE_FAULT_RECOVERY.store(0, Ordering::SeqCst);
+}
@@ -0,0 +1,87 @@
+/*
+    Kernel assertions
+
+    - kassert!/kassert_eq! are for invariants inside the kernel, on failure
+      they print what failed and where over serial and then stop, without
+      going through a panic
+        - the message is always "KASSERT FAILED at <file>:<line>: ..." so it
+          is easy to grep for in the serial log
+        - stopping means hlt_loop(), or whatever set_panic_action() picked
+          (e.g. exiting QEMU)
+    - the check itself is just the comparison, all of the formatting lives
+      in the #[cold] _failed() so it stays out of hot paths
+    - set_hook() lets a test see the message before the kernel stops
+*/
+
+use crate::serial_println;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::kassert::_failed(format_args!("`{}`", stringify!($cond)), file!(), line!());
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::kassert::_failed(
+                        format_args!(
+                            "`{} == {}`\n  left: {:?}\n right: {:?}",
+                            stringify!($left),
+                            stringify!($right),
+                            left,
+                            right
+                        ),
+                        file!(),
+                        line!(),
+                    );
+                }
+            }
+        }
+    };
+}
+
+// fn(&fmt::Arguments) stored as a plain usize so _failed() needs no lock
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+// called with the full failure message right before the kernel stops
+pub fn set_hook(hook: fn(&fmt::Arguments)) {
+    HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
+#[doc(hidden)]
+#[cold]
+pub fn _failed(what: fmt::Arguments, file: &str, line: u32) -> ! {
+    let report = |msg: fmt::Arguments| {
+        serial_println!("{}", msg);
+        let hook = HOOK.load(Ordering::SeqCst);
+        if hook != 0 {
+            // only ever set from a fn(&fmt::Arguments) in set_hook()
+            let hook: fn(&fmt::Arguments) = unsafe { core::mem::transmute(hook) };
+            hook(&msg);
+        }
+    };
+    report(format_args!(
+        "KASSERT FAILED at {}:{}: {}",
+        file, line, what
+    ));
+
+    #[cfg(test)]
+    crate::exit_qemu(crate::QEMUExitCode::Failure);
+    crate::run_panic_action();
+}
+
+#[test_case]
+fn test_kassert_passes() {
+    kassert!(core::hint::black_box(1) + 1 == 2);
+    kassert_eq!(2 * 2, 4);
+    kassert_eq!(concat!("k", "assert"), "kassert",);
+}
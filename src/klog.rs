@@ -0,0 +1,125 @@
+/*
+    Kernel log levels
+
+    - log_error!/log_warn!/log_info!/log_debug! print one line prefixed with
+      its level, e.g. "WARNING: keyboard queue full"
+        - always over serial, so the host log has everything
+        - on screen (VGA or the framebuffer console, wherever print! goes)
+          only if the level is at or above max_level()
+    - max_level() is Info unless the `loglevel=` boot argument says
+      otherwise (error, warn, info or debug), read by init()
+*/
+
+use crate::{boot_args, println, serial_println};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::klog::_log($crate::klog::Level::Error, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::klog::_log($crate::klog::Level::Warn, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::klog::_log($crate::klog::Level::Info, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::klog::_log($crate::klog::Level::Debug, format_args!($($arg)*)));
+}
+
+// most to least severe, so a level is shown if it's <= max_level()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    // the value of a `loglevel=` boot argument
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARNING",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::ALL[MAX_LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+// whether a line at `level` makes it to the screen
+pub fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+// pick up `loglevel=` from the boot arguments, an unknown value is ignored
+pub fn init() {
+    if let Some(level) = boot_args::get("loglevel").and_then(Level::parse) {
+        set_max_level(level);
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    serial_println!("{}: {}", level.prefix(), args);
+    if enabled(level) {
+        println!("{}: {}", level.prefix(), args);
+    }
+}
+
+#[test_case]
+fn test_level_parse() {
+    assert_eq!(Level::parse("debug"), Some(Level::Debug));
+    assert_eq!(Level::parse("warning"), Some(Level::Warn));
+    assert_eq!(Level::parse("warn"), Some(Level::Warn));
+    assert_eq!(Level::parse(""), None);
+    assert_eq!(Level::parse("loud"), None);
+}
+
+// test that only levels at least as severe as max_level() are shown
+#[test_case]
+fn test_max_level_filters() {
+    let before = max_level();
+
+    set_max_level(Level::Warn);
+    assert!(enabled(Level::Error));
+    assert!(enabled(Level::Warn));
+    assert!(!enabled(Level::Info));
+    assert!(!enabled(Level::Debug));
+
+    set_max_level(Level::Debug);
+    assert!(Level::ALL.iter().all(|&level| enabled(level)));
+
+    set_max_level(before);
+}
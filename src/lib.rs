@@ -2,6 +2,8 @@
 // condition attribute no_main on if the tests are running
 #![cfg_attr(test, no_main)]
 #![feature(naked_functions)]
+// lets heap::Heap back Vec::new_in() and friends
+#![feature(allocator_api)]
 // custom test frameworks requires no external libraries thus works in a #![no_std] environment
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
@@ -11,11 +13,35 @@ extern crate alloc;
 extern crate bit_field;
 use core::arch::asm;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+pub mod boot;
+pub mod boot_args;
+pub mod cpu;
+#[cfg(feature = "debug_server")]
+pub mod debug_server;
+pub mod drivers;
+#[cfg(feature = "fault_checks")]
+pub mod fault_check;
+pub mod framebuffer;
 pub mod gdt;
 pub mod heap;
 pub mod interrupts;
+pub mod kassert;
+pub mod klog;
+pub mod loader;
 pub mod mem;
+pub mod mmio;
+pub mod power;
+pub mod qemu;
+pub mod rand;
 pub mod serial;
+pub mod stack_guard;
+pub mod subsystem;
+pub mod syscall;
+pub mod task;
+pub mod time;
+pub mod util;
 pub mod vga_buf;
 
 /* EXCEPTION HANDLER TESTING FUNCTIONS */
@@ -33,6 +59,12 @@ pub fn page_fault() {
     unsafe { *(0xdeadbee8 as *mut u64) = 12 }
 }
 
+// write through a null pointer in asm, debug builds would otherwise catch a
+// null deref in Rust and panic before the CPU ever faults
+pub fn null_deref() {
+    unsafe { asm!("mov qword ptr [{}], 42", in(reg) 0u64) }
+}
+
 pub fn breakpoint() {
     x86_64::instructions::interrupts::int3();
 }
@@ -58,12 +90,76 @@ const QEMU_PORT: u16 = 0xf4;
 pub fn exit_qemu(exit_code: QEMUExitCode) {
     use x86_64::instructions::port::Port;
 
+    // don't lose a buffered partial line
+    serial::flush();
     unsafe {
         let mut port = Port::new(QEMU_PORT);
         port.write(exit_code as u32);
     }
 }
 
+/* PANIC HANDLING */
+
+// what the (non-test) panic handler does once it has printed the panic message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    // sit in hlt_loop() forever (default)
+    Halt,
+    // reset the machine with power::reboot()
+    Reboot,
+    // exit QEMU with the given code, handy for fuzzing
+    ExitQemu(QEMUExitCode),
+}
+
+impl PanicAction {
+    // Halt and Reboot can't collide with the exit codes (0x10, 0x11)
+    const HALT: u32 = 0;
+    const REBOOT: u32 = 1;
+
+    fn to_u32(self) -> u32 {
+        match self {
+            PanicAction::Halt => Self::HALT,
+            PanicAction::Reboot => Self::REBOOT,
+            PanicAction::ExitQemu(code) => code as u32,
+        }
+    }
+
+    // never fails so reading the action from the panic handler can't panic
+    // again, anything unexpected falls back to Halt
+    fn from_u32(value: u32) -> Self {
+        match value {
+            Self::REBOOT => PanicAction::Reboot,
+            x if x == QEMUExitCode::Success as u32 => PanicAction::ExitQemu(QEMUExitCode::Success),
+            x if x == QEMUExitCode::Failure as u32 => PanicAction::ExitQemu(QEMUExitCode::Failure),
+            _ => PanicAction::Halt,
+        }
+    }
+}
+
+// stored as a plain atomic so the panic handler never has to take a lock
+static PANIC_ACTION: AtomicU32 = AtomicU32::new(PanicAction::HALT);
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action.to_u32(), Ordering::SeqCst);
+}
+
+pub fn panic_action() -> PanicAction {
+    PanicAction::from_u32(PANIC_ACTION.load(Ordering::SeqCst))
+}
+
+// carry out the configured PanicAction, called at the end of a panic handler
+pub fn run_panic_action() -> ! {
+    match panic_action() {
+        PanicAction::Halt => hlt_loop(),
+        PanicAction::Reboot => power::reboot(),
+        PanicAction::ExitQemu(code) => {
+            exit_qemu(code);
+            // in case the QEMU exit device isn't attached
+            hlt_loop();
+        }
+    }
+}
+
 /* TESTING FRAMEWORK */
 pub trait Testable {
     fn run(&self);
@@ -74,32 +170,257 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        let name = core::any::type_name::<T>();
+        test_log!("test:start:{}", name);
+        serial_print!("{}...\t", name);
+        // so a test that hangs still shows up
+        serial::flush();
         self();
         serial_println!("[ok]");
+        test_log!("test:ok:{}", name);
     }
 }
 
+/*
+    Isolated test runs
+
+    - normally every test in a binary runs in the same boot and shares the
+      kernel's global state (WRITER, PICS, heap, ...)
+    - with the boot argument `test_index=N` only test N (0 based) runs and
+      then QEMU exits, so a host side loop can boot once per test:
+        - run the binary once with test_index=0, the first line of output
+          says how many tests there are ("Running test 0 of N")
+        - then again for 1..N, each with a fresh machine
+        - the command line comes from KERNEL_CMDLINE at build time (see
+          boot_args) so each index is its own build of the test binary
+    - an index past the end is a failure so a typo doesn't pass silently
+
+    Besides the usual output each test is bracketed by test_log! records
+    for host tools: "test:start:<name>", then "test:ok:<name>" or
+    "test:failed" from the panic handler, after a "tests:<count>" one
+*/
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
+    let index = boot_args::get("test_index").and_then(|idx| idx.parse().ok());
+    let code = if run_tests(tests, index) {
+        QEMUExitCode::Success
+    } else {
+        QEMUExitCode::Failure
+    };
+    exit_qemu(code);
+}
+
+// run all of `tests`, or just the one at `index`, false if there isn't one
+pub fn run_tests(tests: &[&dyn Testable], index: Option<usize>) -> bool {
+    match index {
+        None => {
+            test_log!("tests:{}", tests.len());
+            serial_println!("Running {} tests", tests.len());
+            for test in tests {
+                test.run();
+            }
+            true
+        }
+        Some(idx) => {
+            test_log!("tests:1");
+            serial_println!("Running test {} of {}", idx, tests.len());
+            match tests.get(idx) {
+                Some(test) => {
+                    test.run();
+                    true
+                }
+                None => {
+                    serial_println!("[failed]\n\nError: no test {}\n", idx);
+                    test_log!("test:failed");
+                    false
+                }
+            }
+        }
     }
-    exit_qemu(QEMUExitCode::Success);
+}
+
+// set by the panic handlers before they print anything, so e.g. print
+// capture doesn't swallow the message
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_panicking() {
+    PANICKING.store(true, Ordering::SeqCst);
+}
+
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    set_panicking();
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    test_log!("test:failed");
     exit_qemu(QEMUExitCode::Failure);
     hlt_loop();
 }
 
+/*
+    Expected panics
+
+    - a harness = false test that passes by panicking (stack_guard_test,
+      double_free_test, ...) uses expected_panic_handler() as its panic
+      handler, so some other panic on the way there still fails it
+    - the message is checked as it's formatted, the heap may be locked by
+      whatever panicked (e.g. the allocator itself)
+*/
+pub fn expected_panic_handler(info: &PanicInfo, prefix: &str) -> ! {
+    set_panicking();
+    if formats_with_prefix(info.message(), prefix) {
+        serial_println!("[ok]");
+        exit_qemu(QEMUExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: {}\n", info);
+        exit_qemu(QEMUExitCode::Failure);
+    }
+    hlt_loop();
+}
+
+// compares the bytes against `prefix` as they come in
+struct PrefixCheck<'a> {
+    prefix: &'a [u8],
+    pos: usize,
+    matches: bool,
+}
+
+impl core::fmt::Write for PrefixCheck<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if self.prefix.get(self.pos).map_or(false, |&b| b != byte) {
+                self.matches = false;
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+}
+
+fn formats_with_prefix(value: impl core::fmt::Display, prefix: &str) -> bool {
+    use core::fmt::Write;
+
+    let mut check = PrefixCheck {
+        prefix: prefix.as_bytes(),
+        pos: 0,
+        matches: true,
+    };
+    let _ = write!(check, "{}", value);
+    check.matches && check.pos >= prefix.len()
+}
+
+/*
+    Integration test boilerplate
+
+    - integration_test!() expands to a test binary's entry point and panic
+      handler: init(), then test_main() which exits QEMU once the tests are
+      done, with any failure going through test_panic_handler()
+    - integration_test!(heap) maps the heap too, right after init()
+    - the crate attributes still have to be at the top of the file, a macro
+      can't add those:
+
+        #![no_std]
+        #![no_main]
+        #![feature(custom_test_frameworks)]
+        #![test_runner(os_practice::test_runner)]
+        #![reexport_test_harness_main = "test_main"]
+
+        os_practice::integration_test!(heap);
+
+    - tests that need anything else (no init(), their own panic handler,
+      harness = false) still write kern_main out by hand
+*/
+#[macro_export]
+macro_rules! integration_test {
+    () => {
+        $crate::integration_test!(@entry false);
+    };
+    (heap) => {
+        $crate::integration_test!(@entry true);
+    };
+    (@entry $heap:expr) => {
+        $crate::__bootloader::entry_point!(integration_kern_main);
+
+        fn integration_kern_main(boot_info: &'static $crate::__bootloader::BootInfo) -> ! {
+            $crate::init_integration_test(boot_info, $heap);
+            test_main();
+            $crate::hlt_loop();
+        }
+
+        #[panic_handler]
+        fn panic(info: &core::panic::PanicInfo) -> ! {
+            $crate::test_panic_handler(info)
+        }
+    };
+}
+
+// so integration_test!() works without the test naming bootloader itself
+#[doc(hidden)]
+pub use bootloader as __bootloader;
+
+#[doc(hidden)]
+pub fn init_integration_test(boot_info: &'static bootloader::BootInfo, heap: bool) {
+    init().expect("kernel initialization failed");
+    if heap {
+        let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+        let mut mapper = unsafe { mem::init(phys_mem_offset) };
+        let mut frame_alloc = unsafe { mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        heap::init_heap(&mut mapper, &mut frame_alloc).expect("Heap initialization failed");
+    }
+}
+
 #[test_case]
 fn trivial_assertion() {
     assert_eq!(1, 1);
 }
 
+#[test_case]
+fn test_formats_with_prefix() {
+    assert!(formats_with_prefix(
+        format_args!("heap leak: {} bytes", 8),
+        "heap leak"
+    ));
+    assert!(formats_with_prefix("heap leak", "heap leak"));
+    // too short, or a mismatch in a later write_str() call
+    assert!(!formats_with_prefix("heap", "heap leak"));
+    assert!(!formats_with_prefix(
+        format_args!("heap {}", "lock"),
+        "heap leak"
+    ));
+}
+
+// test that a selected index runs that test and nothing else
+#[test_case]
+fn test_run_single_index() {
+    static RAN: [AtomicU32; 3] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+    let first = || {
+        RAN[0].fetch_add(1, Ordering::SeqCst);
+    };
+    let second = || {
+        RAN[1].fetch_add(1, Ordering::SeqCst);
+    };
+    let third = || {
+        RAN[2].fetch_add(1, Ordering::SeqCst);
+    };
+    let tests: [&dyn Testable; 3] = [&first, &second, &third];
+
+    // the nested runs' lines and test_log! records would get mixed up with
+    // this test's own
+    let (found, missing) =
+        serial::muted(|| (run_tests(&tests, Some(1)), run_tests(&tests, Some(3))));
+    assert!(found);
+    assert!(!missing);
+    let counts = [
+        RAN[0].load(Ordering::SeqCst),
+        RAN[1].load(Ordering::SeqCst),
+        RAN[2].load(Ordering::SeqCst),
+    ];
+    assert_eq!(counts, [0, 1, 0]);
+}
+
 // move the testing function from main.rs to lib.rs, now the entire function
 // _start is only run when testing here
 #[cfg(test)]
@@ -110,7 +431,7 @@ entry_point!(test_kern_main);
 
 #[cfg(test)]
 fn test_kern_main(_boot_info: &'static BootInfo) -> ! {
-    init();
+    init().expect("kernel initialization failed");
     test_main();
     hlt_loop();
 }
@@ -131,17 +452,102 @@ fn panic(info: &PanicInfo) -> ! {
 // efficient
 pub fn hlt_loop() -> ! {
     loop {
+        // the panic handler ends up here too, don't panic again from it
+        if !panicking() {
+            stack_guard::check_pending();
+        }
         x86_64::instructions::hlt();
     }
 }
 
-pub fn init() {
-    // init the GDT before so the IST is setup for our handlers
-    gdt::init();
-    interrupts::init();
-    // initialize the PICs to handle hardware interrupts
-    unsafe { interrupts::PICS.lock().initialize() };
+// which part of kernel initialization went wrong
+#[derive(Debug)]
+pub enum InitError {
+    Gdt,
+    Idt,
+    Pic,
+    Heap(MapToError<Size4KiB>),
+    // the memory map doesn't have enough USABLE frames for the heap
+    InsufficientMemory { need: usize, have: usize },
+    // any other subsystem, with what went wrong
+    Subsystem(&'static str),
+}
+
+impl core::fmt::Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            InitError::Gdt => write!(f, "GDT failed to load"),
+            InitError::Idt => write!(f, "IDT failed to load"),
+            InitError::Pic => write!(f, "PICs did not respond"),
+            InitError::Heap(err) => write!(f, "heap initialization failed: {:?}", err),
+            InitError::InsufficientMemory { need, have } => write!(
+                f,
+                "INSUFFICIENT MEMORY: need {} frames, have {}",
+                need, have
+            ),
+            InitError::Subsystem(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<MapToError<Size4KiB>> for InitError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        InitError::Heap(err)
+    }
+}
+
+// the kernel's subsystems, kept around so shutdown() can take down what
+// init() brought up
+static SUBSYSTEMS: spin::Mutex<subsystem::Registry> = spin::Mutex::new(subsystem::Registry::new());
+
+// returns which subsystem failed rather than panicking so the caller can
+// decide whether to carry on without it
+// see subsystem::KERNEL_SUBSYSTEMS for what gets brought up, and in what order
+pub fn init() -> Result<(), InitError> {
+    let mut registry = SUBSYSTEMS.lock();
+    // a second init() only brings back up whatever isn't
+    if registry.is_empty() {
+        for &subsystem in subsystem::KERNEL_SUBSYSTEMS.iter() {
+            registry
+                .register(subsystem)
+                .expect("too many kernel subsystems");
+        }
+    }
+    registry.init_all().map_err(|err| match err {
+        subsystem::RegistryError::Failed(_, err) => err,
+        // the built in dependencies are fixed, this is a bug not a boot failure
+        err => panic!("kernel subsystems misconfigured: {}", err),
+    })?;
+    drop(registry);
     // enable CPU interrupts
     // executes `sti` ("set interrupts") instruction to enable external interrupts
     x86_64::instructions::interrupts::enable();
+    Ok(())
+}
+
+// take down everything init() brought up, last up first down
+pub fn shutdown() {
+    x86_64::instructions::interrupts::disable();
+    SUBSYSTEMS.lock().shutdown_all();
+}
+
+// run before init_heap() so a low memory boot gets a clear message rather
+// than a FrameAllocationFailed out of the middle of mapping the heap
+pub fn check_memory(regions: boot::MemoryRegions) -> Result<(), InitError> {
+    let need = heap::frames_needed();
+    let have = mem::usable_frame_count(regions);
+    if have < need {
+        return Err(InitError::InsufficientMemory { need, have });
+    }
+    Ok(())
+}
+
+// the heap needs the memory mapper from the bootloader's BootInfo so it is
+// set up separately, after init()
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), InitError> {
+    heap::init_heap(mapper, frame_alloc)?;
+    Ok(())
 }
@@ -0,0 +1,236 @@
+/*
+    ELF loader for user programs
+
+    - only statically linked 64-bit little endian x86_64 executables
+      (ET_EXEC), no relocations or dynamic linking
+    - every PT_LOAD segment gets fresh frames mapped at its p_vaddr with
+      USER_ACCESSIBLE set, WRITABLE only for PF_W segments and NO_EXECUTE
+      unless PF_X
+    - the file data is copied in and the rest of the segment (the BSS, where
+      p_memsz > p_filesz) is left zeroed
+    - the frames are written through the physical memory mapping rather than
+      the new user mapping since read-only pages can't be written to with
+      CR0.WP set
+    - segments must live in the lower half and can't share a page, on an
+      error the pages mapped so far are left mapped
+*/
+
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+pub(crate) const PT_LOAD: u32 = 1;
+pub(crate) const PF_X: u32 = 1 << 0;
+pub(crate) const PF_W: u32 = 1 << 1;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+// first address past the lower (user) half of the address space
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+#[derive(Debug)]
+pub enum LoadError {
+    // shorter than a header or a table it points to
+    Truncated,
+    NotElf,
+    // wrong class, byte order, type or machine
+    Unsupported,
+    // a segment's file data or addresses don't make sense
+    BadSegment,
+    // the entry point isn't inside an executable segment
+    BadEntry,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        LoadError::Map(err)
+    }
+}
+
+// ELF64 program header, all 56 bytes of it
+#[repr(C)]
+pub(crate) struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<ProgramHeader>() == PHDR_SIZE);
+
+impl ProgramHeader {
+    pub(crate) fn parse(bytes: &[u8], at: usize) -> Result<Self, LoadError> {
+        Ok(ProgramHeader {
+            p_type: read_u32(bytes, at)?,
+            p_flags: read_u32(bytes, at + 0x4)?,
+            p_offset: read_u64(bytes, at + 0x8)?,
+            p_vaddr: read_u64(bytes, at + 0x10)?,
+            p_paddr: read_u64(bytes, at + 0x18)?,
+            p_filesz: read_u64(bytes, at + 0x20)?,
+            p_memsz: read_u64(bytes, at + 0x28)?,
+            p_align: read_u64(bytes, at + 0x30)?,
+        })
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.p_vaddr && addr - self.p_vaddr < self.p_memsz
+    }
+
+    fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.p_flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.p_flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+fn read_bytes<const N: usize>(bytes: &[u8], at: usize) -> Result<[u8; N], LoadError> {
+    let end = at.checked_add(N).ok_or(LoadError::Truncated)?;
+    let mut buf = [0; N];
+    buf.copy_from_slice(bytes.get(at..end).ok_or(LoadError::Truncated)?);
+    Ok(buf)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Result<u16, LoadError> {
+    read_bytes(bytes, at).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, LoadError> {
+    read_bytes(bytes, at).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Result<u64, LoadError> {
+    read_bytes(bytes, at).map(u64::from_le_bytes)
+}
+
+// map and fill in every PT_LOAD segment, returns the program's entry point
+pub fn load_elf(
+    bytes: &[u8],
+    mapper: &mut OffsetPageTable,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, LoadError> {
+    if bytes.len() < EHDR_SIZE {
+        return Err(LoadError::Truncated);
+    }
+    if bytes[..4] != ELF_MAGIC {
+        return Err(LoadError::NotElf);
+    }
+    if bytes[4] != ELFCLASS64
+        || bytes[5] != ELFDATA2LSB
+        || read_u16(bytes, 0x10)? != ET_EXEC
+        || read_u16(bytes, 0x12)? != EM_X86_64
+    {
+        return Err(LoadError::Unsupported);
+    }
+
+    let entry = read_u64(bytes, 0x18)?;
+    let phoff = read_u64(bytes, 0x20)? as usize;
+    let phentsize = read_u16(bytes, 0x36)? as usize;
+    let phnum = read_u16(bytes, 0x38)? as usize;
+    if phentsize < PHDR_SIZE {
+        return Err(LoadError::Unsupported);
+    }
+
+    let phdr_at = |i: usize| phoff.checked_add(i * phentsize).ok_or(LoadError::Truncated);
+
+    // check everything before mapping anything
+    let mut entry_ok = false;
+    for i in 0..phnum {
+        let phdr = ProgramHeader::parse(bytes, phdr_at(i)?)?;
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+        check_segment(bytes, &phdr)?;
+        entry_ok |= phdr.p_flags & PF_X != 0 && phdr.contains(entry);
+    }
+    if !entry_ok {
+        return Err(LoadError::BadEntry);
+    }
+
+    for i in 0..phnum {
+        let phdr = ProgramHeader::parse(bytes, phdr_at(i)?)?;
+        if phdr.p_type == PT_LOAD && phdr.p_memsz > 0 {
+            load_segment(bytes, &phdr, mapper, frame_alloc)?;
+        }
+    }
+
+    Ok(VirtAddr::new(entry))
+}
+
+fn check_segment(bytes: &[u8], phdr: &ProgramHeader) -> Result<(), LoadError> {
+    let file_end = phdr
+        .p_offset
+        .checked_add(phdr.p_filesz)
+        .ok_or(LoadError::BadSegment)?;
+    let mem_end = phdr
+        .p_vaddr
+        .checked_add(phdr.p_memsz)
+        .ok_or(LoadError::BadSegment)?;
+    if file_end > bytes.len() as u64 || phdr.p_filesz > phdr.p_memsz || mem_end > USER_END {
+        return Err(LoadError::BadSegment);
+    }
+    Ok(())
+}
+
+fn load_segment(
+    bytes: &[u8],
+    phdr: &ProgramHeader,
+    mapper: &mut OffsetPageTable,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), LoadError> {
+    let start = VirtAddr::new(phdr.p_vaddr);
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (phdr.p_memsz - 1));
+    let file_end = phdr.p_vaddr + phdr.p_filesz;
+
+    let flags = phdr.flags();
+    // the user has to be able to get through the higher level tables too
+    let parent_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    for page in Page::range_inclusive(first, last) {
+        let frame = frame_alloc
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let dest: *mut u8 = (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+
+        // part of the file data that lands on this page, if any
+        let page_start = page.start_address().as_u64();
+        let copy_start = page_start.max(phdr.p_vaddr);
+        let copy_end = (page_start + page.size()).min(file_end);
+        unsafe {
+            core::ptr::write_bytes(dest, 0, page.size() as usize);
+            if copy_start < copy_end {
+                let src = (phdr.p_offset + (copy_start - phdr.p_vaddr)) as usize;
+                let len = (copy_end - copy_start) as usize;
+                core::ptr::copy_nonoverlapping(
+                    bytes[src..src + len].as_ptr(),
+                    dest.add((copy_start - page_start) as usize),
+                    len,
+                );
+            }
+            mapper
+                .map_to_with_table_flags(page, frame, flags, parent_flags, frame_alloc)?
+                .flush();
+        }
+    }
+    Ok(())
+}
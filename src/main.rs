@@ -9,16 +9,19 @@
 #![reexport_test_harness_main = "test_main"]
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use os_practice::println;
-use x86_64::VirtAddr;
+use os_practice::boot::BootEnvironment;
+use os_practice::task::{exec::Exec, keyboard, Task};
+use os_practice::{println, serial_println};
 
 // function called in the event of a panic
 /// return type = ! ("never" type) as it will just loop and never return
 #[cfg(not(test))] // set this as the panic handler when not testing
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    os_practice::set_panicking();
     println!("{}\n", info);
-    os_practice::hlt_loop();
+    // halts by default, see os_practice::set_panic_action()
+    os_practice::run_panic_action();
 }
 
 #[cfg(test)]
@@ -65,17 +68,32 @@ fn panic(info: &PanicInfo) -> ! {
 entry_point!(kern_main);
 
 fn kern_main(boot_info: &'static BootInfo) -> ! {
-    os_practice::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    // everything below only goes through `env`, not the BIOS BootInfo
+    let env = BootEnvironment::from_bios(boot_info);
+    // the only call, and the BIOS boot has no framebuffer to get wrong
+    unsafe { env.init_console() };
+    os_practice::init().expect("kernel initialization failed");
+    let mut mapper = unsafe { os_practice::mem::init(env.physical_memory_offset()) };
+    if let Err(err) = os_practice::check_memory(env.memory_regions()) {
+        println!("{}", err);
+        serial_println!("{}", err);
+        os_practice::hlt_loop();
+    }
     let mut frame_alloc =
-        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
-        .expect("Heap initialization failed");
+        unsafe { os_practice::mem::BootInfoFrameAllocator::from_regions(env.memory_regions()) };
+    os_practice::init_heap(&mut mapper, &mut frame_alloc).expect("Heap initialization failed");
+    // read-only .text/.rodata, no-execute data and heap
+    os_practice::mem::protect_kernel_sections(&mut mapper)
+        .expect("Kernel section protection failed");
 
     println!("Hello Kernel!");
 
     #[cfg(test)]
     test_main();
-    os_practice::hlt_loop();
+
+    let mut exec = Exec::new();
+    exec.spawn(Task::new(keyboard::print_keypresses()));
+    #[cfg(feature = "debug_server")]
+    exec.spawn(Task::new(os_practice::debug_server::serve(mapper)));
+    exec.run();
 }
@@ -1,6 +1,14 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::boot::{MemoryRegions, RegionKind};
+use crate::loader::{ProgramHeader, PF_W, PF_X, PT_LOAD};
+use crate::serial_println;
+use alloc::vec::Vec;
+use bootloader::bootinfo::MemoryMap;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex,
+        PhysFrame, RecursivePageTable, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
 
@@ -15,11 +23,38 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+// a frame allocator that can tell whether it has already handed out a frame
+pub trait FrameTracker {
+    fn is_allocated(&self, frame: PhysFrame) -> bool;
+}
+
+// never hands anything out
+impl FrameTracker for EmptyFrameAllocator {
+    fn is_allocated(&self, _frame: PhysFrame) -> bool {
+        false
+    }
+}
+
+// number of USABLE frames in the whole memory map, for checking there's
+// enough memory to boot before anything is allocated
+pub fn usable_frame_count(regions: MemoryRegions) -> usize {
+    regions
+        .iter()
+        .filter(|r| r.kind == RegionKind::Usable)
+        .map(|r| ((r.end - r.start) / 4096) as usize)
+        .sum()
+}
+
 // A FrameAllocator that can return usable addresses from the bootloader's
 // memory map
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
+    regions: MemoryRegions<'static>,
     next: usize,
+    // number of allocate_frame calls that came back empty handed
+    failed: usize,
+    // usable frames from `cache_start` onward, once cache_frames() is called
+    cache: Option<Vec<PhysFrame>>,
+    cache_start: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -31,21 +66,104 @@ impl BootInfoFrameAllocator {
         marked USABLE are truly unused and not taken up already
     */
     pub unsafe fn init(mem_map: &'static MemoryMap) -> Self {
+        Self::from_regions(mem_map.into())
+    }
+
+    // same as init() but for any bootloader's memory map, see BootEnvironment
+    pub unsafe fn from_regions(regions: MemoryRegions<'static>) -> Self {
         BootInfoFrameAllocator {
-            memory_map: mem_map,
+            regions,
             next: 0,
+            failed: 0,
+            cache: None,
+            cache_start: 0,
+        }
+    }
+
+    /*
+        materialize the remaining usable frames into a heap Vec so that
+        allocate_frame() can just index into it rather than rebuilding the
+        usable_frames() iterator and walking it to `next` every call
+
+        - needs the heap to be initialized
+        - costs 8 bytes of heap per remaining frame, if that doesn't fit the
+          allocator is left uncached and this returns false
+    */
+    pub fn cache_frames(&mut self) -> bool {
+        let mut cache = Vec::new();
+        if cache.try_reserve_exact(self.frames_available()).is_err() {
+            return false;
+        }
+        cache.extend(self.usable_frames().skip(self.next));
+        self.cache = Some(cache);
+        self.cache_start = self.next;
+        true
+    }
+
+    // number of frames handed out so far
+    pub fn frames_allocated(&self) -> usize {
+        self.next
+    }
+
+    // number of USABLE frames left between `next` and the end of the memory map
+    pub fn frames_available(&self) -> usize {
+        match &self.cache {
+            Some(cache) => cache.len() - (self.next - self.cache_start),
+            None => self.usable_frames().skip(self.next).count(),
         }
     }
 
+    /*
+        hand out `count` physically consecutive frames, returns the first
+
+        - this is still a bump allocator so any frames between `next` and the
+          start of the run (e.g. the tail end of a region too short for it)
+          are skipped over for good
+        - counts as a single failed allocation if no run is long enough
+    */
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut run: Option<(usize, PhysFrame)> = None;
+        let mut run_len = 0;
+        let mut prev: Option<PhysFrame> = None;
+        for (idx, frame) in self.usable_frames().enumerate().skip(self.next) {
+            match prev {
+                Some(prev) if prev + 1 == frame => run_len += 1,
+                _ => {
+                    run = Some((idx, frame));
+                    run_len = 1;
+                }
+            }
+            prev = Some(frame);
+
+            if run_len == count {
+                let (start_idx, first) = run?;
+                self.next = start_idx + count;
+                return Some(first);
+            }
+        }
+
+        self.failed += 1;
+        None
+    }
+
+    // number of allocations that failed because the usable frames ran out
+    pub fn failed_allocations(&self) -> usize {
+        self.failed
+    }
+
     // get an iterator over all of the frames in the memory map currently
     // marked USABLE
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // first get usable regions
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+        let regions = self.regions.iter();
+        let usable_regions = regions.filter(|r| r.kind == RegionKind::Usable);
 
         // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        let addr_ranges = usable_regions.map(|r| r.start..r.end);
 
         // transform into an iterator of frame start addrs by flattening
         // nested structure from Iterator<Item = Iterator<Item = u64>> to
@@ -58,14 +176,37 @@ impl BootInfoFrameAllocator {
     }
 }
 
+// frames are handed out in order so the first `next` usable ones are taken
+impl FrameTracker for BootInfoFrameAllocator {
+    fn is_allocated(&self, frame: PhysFrame) -> bool {
+        self.usable_frames().take(self.next).any(|f| f == frame)
+    }
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     // inefficient since it technically re-generates the Iterator<PhysFrame>
     // on every call, so it would be better to make a 'static one however it
     // isn't possible to store an impl Trait type in a struct currently
     // may work one day with _named existential types_ (READ MORE)
+    // see cache_frames() for an O(1) version
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
+        let frame = match &self.cache {
+            Some(cache) => cache.get(self.next - self.cache_start).copied(),
+            None => self.usable_frames().nth(self.next),
+        };
+        match frame {
+            Some(_) => self.next += 1,
+            None => {
+                // report over serial so a true physical exhaustion can be told
+                // apart from a failure to map the virtual page
+                self.failed += 1;
+                serial_println!(
+                    "WARNING: out of physical frames ({} allocated, {} available)",
+                    self.frames_allocated(),
+                    self.frames_available()
+                );
+            }
+        }
         frame
     }
 }
@@ -87,6 +228,39 @@ pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
     OffsetPageTable::new(lvl4_table, phys_mem_offset)
 }
 
+/*
+    initialize a RecursivePageTable, for a setup where P4 entry
+    `recursive_index` points back at the P4 table itself (e.g. the
+    bootloader's recursive_page_table feature) instead of or as well as
+    physical memory being mapped at an offset
+
+    - unsafe for the same reasons as init(), plus the caller has to make
+      sure that entry really is installed, otherwise just looking at the
+      table through it page faults (see recursive_entry_installed())
+*/
+pub unsafe fn init_recursive(recursive_index: u16) -> RecursivePageTable<'static> {
+    let idx = PageTableIndex::new(recursive_index);
+    // going through the recursive entry 4 times lands on the P4 table
+    let page: Page = Page::from_page_table_indices(idx, idx, idx, idx);
+    let lvl4_table: *mut PageTable = page.start_address().as_mut_ptr();
+    RecursivePageTable::new(&mut *lvl4_table).expect("P4 entry isn't recursive")
+}
+
+// check whether P4 entry `recursive_index` points at the P4 table itself,
+// looking through the physical memory offset rather than the entry
+pub unsafe fn recursive_entry_installed(recursive_index: u16, phys_mem_offset: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+
+    let (lvl4_table_frame, _) = Cr3::read();
+    let virt = phys_mem_offset + lvl4_table_frame.start_address().as_u64();
+    let lvl4_table: &PageTable = &*virt.as_ptr();
+    let entry = &lvl4_table[PageTableIndex::new(recursive_index)];
+    entry
+        .flags()
+        .contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        && entry.frame().ok() == Some(lvl4_table_frame)
+}
+
 // returns a mutable reference to the active top level (level 4) table
 // fn needs to be unsafe b/c the caller needs to gurantee that the complete
 // physical memory is mapped to virtual memory at the passed phys_mem_offset
@@ -105,6 +279,425 @@ unsafe fn get_top_pg_table(phys_mem_offset: VirtAddr) -> &'static mut PageTable
     &mut *pg_table
 }
 
+// a physically contiguous buffer, e.g. for a device to DMA into
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    // what gets programmed into the device
+    pub phys: PhysAddr,
+    // where the kernel reads and writes it
+    pub virt: VirtAddr,
+    pub frames: usize,
+}
+
+pub fn alloc_contiguous(
+    frame_count: usize,
+    frame_alloc: &mut BootInfoFrameAllocator,
+) -> Option<PhysFrame> {
+    frame_alloc.allocate_contiguous(frame_count)
+}
+
+/*
+    allocate `frame_count` contiguous frames and hand back both addresses
+
+    - the bootloader maps all of physical memory at phys_offset() so there's
+      already a (contiguous) virtual mapping, no new pages needed
+    - the memory is zeroed so nothing left over leaks to the device
+*/
+pub fn dma_alloc(
+    frame_count: usize,
+    mapper: &OffsetPageTable,
+    frame_alloc: &mut BootInfoFrameAllocator,
+) -> Option<DmaRegion> {
+    let first = alloc_contiguous(frame_count, frame_alloc)?;
+    let phys = first.start_address();
+    let virt = mapper.phys_offset() + phys.as_u64();
+    unsafe {
+        core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frame_count * 4096);
+    }
+    Some(DmaRegion {
+        phys,
+        virt,
+        frames: frame_count,
+    })
+}
+
+/*
+    map every page of [start, start + size) to a fresh frame
+
+    - with `zero` each page is cleared through its new mapping right after
+      it's mapped, frames come straight from the bootloader's memory map so
+      they can still hold whatever was there before
+        - writing through the mapping means `flags` has to include
+          WRITABLE for this (CR0.WP makes the kernel respect it too)
+    - on an error the pages mapped so far stay mapped
+*/
+pub fn map_range(
+    start: VirtAddr,
+    size: usize,
+    flags: PageTableFlags,
+    zero: bool,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    debug_assert!(
+        !zero || flags.contains(PageTableFlags::WRITABLE),
+        "can't zero pages mapped read only"
+    );
+    if size == 0 {
+        return Ok(());
+    }
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size - 1) as u64);
+    for page in Page::range_inclusive(first, last) {
+        let frame = frame_alloc
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_alloc)?.flush();
+            if zero {
+                core::ptr::write_bytes(
+                    page.start_address().as_mut_ptr::<u8>(),
+                    0,
+                    page.size() as usize,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum MapPageError {
+    // the page already maps to some frame
+    AlreadyMapped,
+    // the frame allocator has already handed this frame out to someone
+    FrameInUse,
+    Map(MapToError<Size4KiB>),
+}
+
+/*
+    map `page` to `frame`, but check first instead of trusting the caller
+
+    - the page can't already be mapped (including as part of a huge page)
+    - the frame can't be one frame_alloc has already handed out, mapping it
+      would alias whatever it was allocated for
+        - i.e. this is for frames outside of the allocator (e.g. MMIO like
+          the VGA buffer at 0xb8000) or ones it hasn't reached yet, it can't
+          see frames that were mapped without going through the allocator
+*/
+pub fn try_map_page<A>(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_alloc: &mut A,
+) -> Result<(), MapPageError>
+where
+    A: FrameAllocator<Size4KiB> + FrameTracker,
+{
+    if is_mapped(page.start_address(), mapper) {
+        return Err(MapPageError::AlreadyMapped);
+    }
+    if frame_alloc.is_allocated(frame) {
+        return Err(MapPageError::FrameInUse);
+    }
+    // both checked above, nothing else can be using this page or frame
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, frame_alloc)
+            .map_err(MapPageError::Map)?
+            .flush();
+    }
+    Ok(())
+}
+
+// check whether `addr` currently maps to some physical frame, works for
+// huge pages too since it goes through the Translate trait
+pub fn is_mapped(addr: VirtAddr, mapper: &impl Translate) -> bool {
+    mapper.translate_addr(addr).is_some()
+}
+
+// why translate_verbose() couldn't hand back a plain 4 KiB translation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslateFault {
+    // the entry for the address in the level `n` table (4 is the P4, 1 the
+    // P1) isn't PRESENT
+    NotPresentAtLevel(u8),
+    // mapped by a 1 GiB or 2 MiB page, with where the address ends up in it
+    HugePage(PhysAddr),
+    // bits 48-63 aren't copies of bit 47, no page table can map it
+    NonCanonical,
+}
+
+/*
+    translate_addr() for debugging page table bugs, says where the walk
+    stopped instead of just None
+
+    - takes a plain u64 so a non-canonical address can be reported rather
+      than panicking in VirtAddr::new()
+    - a huge page is an Err but still carries the translated address, the
+      caller asked for a 4 KiB mapping and didn't get one
+    - the tables are read through the physical memory offset like
+      iter_mappings()
+*/
+pub fn translate_verbose(
+    addr: u64,
+    mapper: &mut OffsetPageTable,
+) -> Result<PhysAddr, TranslateFault> {
+    let addr = VirtAddr::try_new(addr).map_err(|_| TranslateFault::NonCanonical)?;
+    let phys_offset = mapper.phys_offset();
+    let mut table: &PageTable = mapper.level_4_table();
+
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    for (level, &idx) in (2..=4u8).rev().zip(indices.iter()) {
+        let entry = &table[idx];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(TranslateFault::NotPresentAtLevel(level));
+        }
+        // HUGE_PAGE is reserved in a P4 entry
+        if level < 4 && flags.contains(PageTableFlags::HUGE_PAGE) {
+            // the low 30 (1 GiB) or 21 (2 MiB) bits are the offset into it
+            let offset = addr.as_u64() & ((1 << (12 + 9 * (level - 1))) - 1);
+            return Err(TranslateFault::HugePage(entry.addr() + offset));
+        }
+        let virt = phys_offset + entry.addr().as_u64();
+        table = unsafe { &*virt.as_ptr::<PageTable>() };
+    }
+
+    let entry = &table[addr.p1_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return Err(TranslateFault::NotPresentAtLevel(1));
+    }
+    Ok(entry.addr() + u64::from(addr.page_offset()))
+}
+
+/*
+    every present mapping in the address space `mapper` manages, lowest
+    virtual address first
+
+    - a P1 entry is a 4 KiB page, a P3 or P2 entry with HUGE_PAGE set is a
+      1 GiB or 2 MiB page, for those the frame is the first 4 KiB of it and
+      the flags include HUGE_PAGE
+    - the tables are read through the physical memory offset, a recursive
+      P4 entry (see init_recursive()) just shows up as the page tables
+      mapped into its part of the address space
+    - the walk borrows the mapper so nothing can change the tables under it
+*/
+pub fn iter_mappings<'a>(mapper: &'a mut OffsetPageTable) -> Mappings<'a> {
+    let phys_offset = mapper.phys_offset();
+    let lvl4_table: &'a PageTable = mapper.level_4_table();
+    Mappings {
+        phys_offset,
+        tables: [lvl4_table; 4],
+        next: [0; 4],
+        level: 0,
+    }
+}
+
+pub struct Mappings<'a> {
+    phys_offset: VirtAddr,
+    // the table being walked at each level, P4 first, and the index of the
+    // next entry to look at in it
+    tables: [&'a PageTable; 4],
+    next: [usize; 4],
+    // how deep the walk is right now, 0 is the P4 table
+    level: usize,
+}
+
+impl Mappings<'_> {
+    // virtual address of the entry just taken at the current level
+    fn virt_addr(&self) -> VirtAddr {
+        let addr = (0..=self.level).fold(0, |addr, level| {
+            addr | ((self.next[level] as u64 - 1) << (39 - 9 * level))
+        });
+        // sign extends bit 47
+        VirtAddr::new_truncate(addr)
+    }
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = (VirtAddr, PhysFrame, PageTableFlags);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let idx = self.next[self.level];
+            if idx == 512 {
+                if self.level == 0 {
+                    return None;
+                }
+                self.level -= 1;
+                continue;
+            }
+            self.next[self.level] += 1;
+
+            let entry = &self.tables[self.level][idx];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            // HUGE_PAGE is reserved in a P4 entry
+            if self.level == 3 || (self.level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                let frame = PhysFrame::containing_address(entry.addr());
+                return Some((self.virt_addr(), frame, flags));
+            }
+
+            // a present non-leaf entry points at the next level's table,
+            // which the bootloader mapped at phys_offset like all of memory
+            let virt = self.phys_offset + entry.addr().as_u64();
+            self.level += 1;
+            self.tables[self.level] = unsafe { &*virt.as_ptr::<PageTable>() };
+            self.next[self.level] = 0;
+        }
+    }
+}
+
+/*
+    W^X for the kernel image
+
+    - the linker puts .rodata, .text and .data/.bss into separate PT_LOAD
+      segments of the kernel ELF, each with its own R/W/X flags, and starts
+      each one on a new page
+    - the first segment also holds the ELF header + program headers and the
+      linker defines __ehdr_start at it, so the program headers can be read
+      straight out of memory without the bootloader telling us anything
+    - each segment's pages keep WRITABLE only if the segment is W and get
+      NO_EXECUTE unless it is X, the heap gets NO_EXECUTE as well
+    - NO_EXECUTE is a reserved bit unless EFER.NXE is set and ring 0 ignores
+      a missing WRITABLE unless CR0.WP is set, so both are turned on here
+    - note: the same frames are still writable through the physical memory
+      mapping at physical_memory_offset
+*/
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+// the headers are e_phentsize apart, which can be more than the 56 bytes
+// ProgramHeader knows about, so each one is parsed rather than cast
+fn program_headers() -> impl Iterator<Item = ProgramHeader> {
+    let ehdr = unsafe { &__ehdr_start as *const u8 };
+    // e_phoff, e_phentsize and e_phnum from the ELF header
+    let (phoff, phentsize, phnum) = unsafe {
+        (
+            (ehdr.add(0x20) as *const u64).read_unaligned() as usize,
+            (ehdr.add(0x36) as *const u16).read_unaligned() as usize,
+            (ehdr.add(0x38) as *const u16).read_unaligned() as usize,
+        )
+    };
+    let bytes = unsafe { core::slice::from_raw_parts(ehdr, phoff + phnum * phentsize) };
+    (0..phnum).filter_map(move |i| ProgramHeader::parse(bytes, phoff + i * phentsize).ok())
+}
+
+// returns the number of pages whose flags were changed
+pub fn protect_kernel_sections(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+) -> Result<usize, FlagUpdateError> {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let mut updated = 0;
+    for phdr in program_headers().filter(|p| p.p_type == PT_LOAD) {
+        let start = VirtAddr::new(phdr.p_vaddr);
+        updated += set_range_flags(mapper, start, phdr.p_memsz, |flags| {
+            let mut flags = flags;
+            flags.set(PageTableFlags::WRITABLE, phdr.p_flags & PF_W != 0);
+            flags.set(PageTableFlags::NO_EXECUTE, phdr.p_flags & PF_X == 0);
+            flags
+        })?;
+    }
+
+    let heap_start = VirtAddr::new(crate::heap::HEAP_START as u64);
+    updated += set_range_flags(mapper, heap_start, crate::heap::HEAP_SIZE as u64, |flags| {
+        flags | PageTableFlags::NO_EXECUTE
+    })?;
+
+    Ok(updated)
+}
+
+// apply `f` to the flags of every mapped 4KiB page in [start, start + len),
+// unmapped pages (e.g. the heap before init_heap) and huge pages are skipped
+fn set_range_flags(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    start: VirtAddr,
+    len: u64,
+    f: impl Fn(PageTableFlags) -> PageTableFlags,
+) -> Result<usize, FlagUpdateError> {
+    if len == 0 {
+        return Ok(0);
+    }
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (len - 1));
+
+    // the pages to invlpg, until there are too many and the whole TLB gets
+    // flushed instead: one invlpg per page stops paying off well before the
+    // whole TLB is worth refilling
+    let mut to_flush = [first; FLUSH_ALL_THRESHOLD];
+    let mut updated = 0;
+    for page in Page::range_inclusive(first, last) {
+        let flags = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                flags,
+                ..
+            } => flags,
+            _ => continue,
+        };
+        let new_flags = f(flags);
+        if new_flags != flags {
+            unsafe { mapper.update_flags(page, new_flags)?.ignore() };
+            if let Some(slot) = to_flush.get_mut(updated) {
+                *slot = page;
+            }
+            updated += 1;
+        }
+    }
+
+    if updated > FLUSH_ALL_THRESHOLD {
+        flush_all();
+    } else {
+        for page in &to_flush[..updated] {
+            flush_page(page.start_address());
+        }
+    }
+    Ok(updated)
+}
+
+/*
+    TLB flushing
+
+    - the CPU caches translations (and their flags) in the TLB and never
+      notices the page tables changing underneath it, so after changing an
+      entry that's already in use the old one can stay in effect until it's
+      flushed, e.g. a page made read-only stays writable
+    - needed after unmapping a page, remapping it to another frame, or
+      taking permissions away (WRITABLE, USER, clearing NO_EXECUTE)
+    - not needed when mapping a page that wasn't present, non-present
+      entries are never cached (a spurious fault at worst)
+    - Mapper methods return a MapperFlush for the one page they touched,
+      these are for everything else
+    - only affects this CPU, there's no shootdown since nothing else runs
+*/
+
+// past this many pages set_range_flags() reloads CR3 instead
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+// drop the TLB entry for the page containing `addr` (invlpg)
+pub fn flush_page(addr: VirtAddr) {
+    x86_64::instructions::tlb::flush(addr);
+}
+
+// drop every TLB entry by reloading CR3, except GLOBAL pages which survive
+// a CR3 write (nothing maps pages GLOBAL right now)
+pub fn flush_all() {
+    x86_64::instructions::tlb::flush_all();
+}
+
 // adding in #[allow(dead_code)] since we will use the OffsetPageTable type
 // created in the init() function to handle translation as it has support
 // for huge frames and better error checking going forward
@@ -153,3 +746,46 @@ fn translate_addr_priv(addr: VirtAddr, phys_mem_offset: VirtAddr) -> Option<Phys
     // physical page frame address
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
+
+// build a small synthetic memory map with a single USABLE region of 4 frames
+#[cfg(test)]
+fn test_memory_map() -> &'static MemoryMap {
+    use bootloader::bootinfo::{FrameRange, MemoryRegion, MemoryRegionType};
+    use lazy_static::lazy_static;
+
+    lazy_static! {
+        static ref MAP: MemoryMap = {
+            let mut map = MemoryMap::new();
+            map.add_region(MemoryRegion {
+                range: FrameRange::new(0x1000, 0x5000),
+                region_type: MemoryRegionType::Usable,
+            });
+            map.add_region(MemoryRegion {
+                range: FrameRange::new(0x5000, 0x8000),
+                region_type: MemoryRegionType::Reserved,
+            });
+            map
+        };
+    }
+    &MAP
+}
+
+#[test_case]
+fn test_frame_alloc_exhaustion() {
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(test_memory_map()) };
+    assert_eq!(frame_alloc.frames_allocated(), 0);
+    assert_eq!(frame_alloc.frames_available(), 4);
+
+    for i in 0..4 {
+        assert!(frame_alloc.allocate_frame().is_some());
+        assert_eq!(frame_alloc.frames_allocated(), i + 1);
+    }
+    assert_eq!(frame_alloc.frames_available(), 0);
+    assert_eq!(frame_alloc.failed_allocations(), 0);
+
+    // the map is exhausted, so the warning fires and the counts stay put
+    assert!(frame_alloc.allocate_frame().is_none());
+    assert_eq!(frame_alloc.failed_allocations(), 1);
+    assert_eq!(frame_alloc.frames_allocated(), 4);
+    assert_eq!(frame_alloc.frames_available(), 0);
+}
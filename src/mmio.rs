@@ -0,0 +1,199 @@
+use bit_field::BitField;
+use core::ops::Range;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use x86_64::VirtAddr;
+
+/*
+    Typed device registers
+
+    - Register<T>: memory mapped, every access is a volatile read/write
+      through the pointer so none get merged or optimized out
+    - PortRegister<T>: an x86 I/O port, T picks the access width (in/out
+      with al, ax or eax)
+    - creating one is the unsafe part, the caller vouches that the address
+      really is that register, after that reads and writes are safe
+    - RegisterAccess gives both the same interface, modify() and the bit
+      helpers are all built on read() + write()
+        - they're separate accesses, for a register that is also written
+          from an interrupt handler the caller has to hold off interrupts
+*/
+pub trait RegisterAccess<T: BitField + Copy> {
+    fn read(&mut self) -> T;
+    fn write(&mut self, value: T);
+
+    // read-modify-write: `f` gets the current value and returns the new one
+    fn modify(&mut self, f: impl FnOnce(T) -> T) {
+        let value = self.read();
+        self.write(f(value));
+    }
+
+    fn read_bit(&mut self, bit: usize) -> bool {
+        self.read().get_bit(bit)
+    }
+
+    fn set_bit(&mut self, bit: usize, on: bool) {
+        self.modify(|mut value| {
+            value.set_bit(bit, on);
+            value
+        });
+    }
+
+    // `bits` shifted down to bit 0
+    fn read_bits(&mut self, bits: Range<usize>) -> T {
+        self.read().get_bits(bits)
+    }
+
+    // replace just `bits` with the low bits of `field`
+    fn set_bits(&mut self, bits: Range<usize>, field: T) {
+        self.modify(|mut value| {
+            value.set_bits(bits, field);
+            value
+        });
+    }
+}
+
+pub struct Register<T> {
+    ptr: *mut T,
+}
+
+impl<T> Register<T> {
+    // `addr` has to be mapped (uncached for real device memory) and
+    // aligned for T
+    pub unsafe fn new(addr: VirtAddr) -> Register<T> {
+        Register {
+            ptr: addr.as_mut_ptr(),
+        }
+    }
+}
+
+impl<T: BitField + Copy> RegisterAccess<T> for Register<T> {
+    fn read(&mut self) -> T {
+        unsafe { core::ptr::read_volatile(self.ptr) }
+    }
+
+    fn write(&mut self, value: T) {
+        unsafe { core::ptr::write_volatile(self.ptr, value) }
+    }
+}
+
+pub struct PortRegister<T> {
+    port: Port<T>,
+}
+
+impl<T> PortRegister<T> {
+    // reading or writing `port` with width T can't break memory safety,
+    // e.g. it isn't a DMA address register
+    pub const unsafe fn new(port: u16) -> PortRegister<T> {
+        PortRegister {
+            port: Port::new(port),
+        }
+    }
+}
+
+impl<T: BitField + Copy + PortRead + PortWrite> RegisterAccess<T> for PortRegister<T> {
+    fn read(&mut self) -> T {
+        unsafe { self.port.read() }
+    }
+
+    fn write(&mut self, value: T) {
+        unsafe { self.port.write(value) }
+    }
+}
+
+/*
+    One-off port accesses, for when keeping a PortRegister around isn't
+    worth it
+
+    - the function picks the instruction width (in/out al, ax or eax), and
+      a register has to be accessed with the width the device expects:
+
+        device                      | ports               | width
+        ----------------------------|---------------------|------------
+        PIC command/data            | 0x20-0x21 0xa0-0xa1 | 8
+        PS/2 data/command           | 0x60 0x64           | 8
+        COM1 UART                   | 0x3f8-0x3ff         | 8
+        VGA registers               | 0x3c0-0x3df         | 8
+        ATA data (primary bus)      | 0x1f0               | 16
+        ATA task file / status      | 0x1f1-0x1f7         | 8
+        PCI CONFIG_ADDRESS          | 0xcf8               | 32
+        PCI CONFIG_DATA             | 0xcfc-0xcff         | 32 (8/16 for
+                                    |                     | part of one)
+        QEMU exit device            | 0xf4                | 32
+
+    - unsafe for the same reason as PortRegister::new()
+*/
+pub unsafe fn read_port_u8(port: u16) -> u8 {
+    PortRegister::new(port).read()
+}
+
+pub unsafe fn write_port_u8(port: u16, value: u8) {
+    PortRegister::new(port).write(value)
+}
+
+pub unsafe fn read_port_u16(port: u16) -> u16 {
+    PortRegister::new(port).read()
+}
+
+pub unsafe fn write_port_u16(port: u16, value: u16) {
+    PortRegister::new(port).write(value)
+}
+
+pub unsafe fn read_port_u32(port: u16) -> u32 {
+    PortRegister::new(port).read()
+}
+
+pub unsafe fn write_port_u32(port: u16, value: u32) {
+    PortRegister::new(port).write(value)
+}
+
+// remembers every access so tests can check what actually hit the register
+#[cfg(test)]
+struct MockRegister {
+    value: u8,
+    reads: usize,
+    writes: usize,
+}
+
+#[cfg(test)]
+impl RegisterAccess<u8> for MockRegister {
+    fn read(&mut self) -> u8 {
+        self.reads += 1;
+        self.value
+    }
+
+    fn write(&mut self, value: u8) {
+        self.writes += 1;
+        self.value = value;
+    }
+}
+
+#[test_case]
+fn test_modify_reads_once_writes_once() {
+    let mut reg = MockRegister {
+        value: 0b1010_0000,
+        reads: 0,
+        writes: 0,
+    };
+    reg.modify(|value| value | 0b0000_0101);
+    assert_eq!(reg.value, 0b1010_0101);
+    assert_eq!((reg.reads, reg.writes), (1, 1));
+
+    // bit helpers keep everything outside the field
+    reg.set_bit(7, false);
+    assert_eq!(reg.value, 0b0010_0101);
+    reg.set_bits(4..7, 0b101);
+    assert_eq!(reg.value, 0b0101_0101);
+    assert_eq!(reg.read_bits(4..7), 0b101);
+    assert!(reg.read_bit(0));
+    assert_eq!((reg.reads, reg.writes), (5, 3));
+}
+
+#[test_case]
+fn test_register_volatile_memory() {
+    let mut backing: u32 = 0xdead_0000;
+    let mut reg = unsafe { Register::<u32>::new(VirtAddr::from_ptr(&mut backing)) };
+    reg.modify(|value| value | 0xbeef);
+    assert_eq!(reg.read(), 0xdead_beef);
+    reg.set_bits(16..32, 0);
+    assert_eq!(unsafe { core::ptr::read_volatile(&backing) }, 0xbeef);
+}
@@ -0,0 +1,45 @@
+use crate::util::poll_until;
+use core::arch::asm;
+use x86_64::instructions::port::Port;
+
+// PS/2 (8042) controller command/status port
+const PS2_CMD_PORT: u16 = 0x64;
+// 8042 command that pulses the CPU reset line
+const PS2_CMD_RESET: u8 = 0xfe;
+// status reads to wait for the 8042 to take a command
+const PS2_POLLS: usize = 0x10000;
+
+/*
+    Reboot the machine
+
+    1. ask the 8042 keyboard controller to pulse the CPU reset line, this is
+       the classic way to reset a PC and QEMU emulates it
+    2. if that didn't work, force a triple fault by loading an empty IDT and
+       raising an interrupt, the CPU can't find any handler (not even the
+       double fault one) so it resets
+*/
+pub fn reboot() -> ! {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    interrupts::disable();
+
+    let mut port: Port<u8> = Port::new(PS2_CMD_PORT);
+    // wait for the controller's input buffer to be empty, a controller that
+    // never gets there just gets the command anyway, the triple fault below
+    // still resets
+    let _ = poll_until(|| unsafe { port.read() } & 0x2 == 0, PS2_POLLS);
+    unsafe { port.write(PS2_CMD_RESET) };
+
+    let empty_idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty_idt);
+        asm!("int3");
+    }
+
+    crate::hlt_loop();
+}
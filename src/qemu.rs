@@ -0,0 +1,56 @@
+/*
+    Requests for a host side harness running the kernel under QEMU
+
+    - both are framed serial records like test_log! ("\x1e<payload>\x1e\n",
+      see serial::write_framed) so they can be picked out of the rest of
+      the serial output with the same regex
+    - log_marker(name) sends "qemu:marker:<name>", a phase marker the host
+      can grep for or time the run by, nothing is expected to happen
+    - request_snapshot(name) sends "qemu:snapshot:<name>", the host is
+      expected to run `savevm <name>` on the QEMU monitor (e.g. started
+      with `-monitor unix:qemu.sock,server,nowait`) when it reads it
+        - the guest doesn't wait for it, the snapshot is of some point
+          shortly after the record went out, so follow it with something
+          slow (or a hlt loop) if the exact state matters
+        - stick to tags savevm accepts (no spaces), the kernel doesn't check
+    - without a host that understands them they are just more serial output
+*/
+
+use core::fmt;
+
+pub const MARKER: &str = "qemu:marker:";
+pub const SNAPSHOT: &str = "qemu:snapshot:";
+
+pub fn log_marker(name: &str) {
+    emit(MARKER, name);
+}
+
+pub fn request_snapshot(name: &str) {
+    emit(SNAPSHOT, name);
+}
+
+fn emit(kind: &str, name: &str) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(serial) = crate::serial::serial1() {
+            write_request(&mut *serial.lock(), kind, name).expect("Serial printing failed");
+        }
+    });
+}
+
+fn write_request(out: &mut impl fmt::Write, kind: &str, name: &str) -> fmt::Result {
+    crate::serial::write_framed(out, format_args!("{}{}", kind, name))
+}
+
+// test the exact bytes a host harness has to match for both kinds of request
+#[test_case]
+fn test_request_records() {
+    let mut out = crate::util::StackBuf::<64>::new();
+    write_request(&mut out, SNAPSHOT, "after_heap").unwrap();
+    assert_eq!(out.as_bytes(), b"\x1eqemu:snapshot:after_heap\x1e\n");
+
+    out.clear();
+    write_request(&mut out, MARKER, "phase\x1e2").unwrap();
+    assert_eq!(out.as_bytes(), b"\x1eqemu:marker:phase\\x1e2\x1e\n");
+}
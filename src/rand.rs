@@ -0,0 +1,121 @@
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+
+/*
+    Random numbers
+
+    - random_u64() prefers the CPU's `rdrand` instruction (hardware RNG),
+      RdRand::new() checks CPUID for support so older CPUs just get None
+    - otherwise it falls back to a global xorshift64* PRNG seeded from the
+      timestamp counter (`rdtsc`) the first time it's needed
+        - NOT cryptographically secure, just good enough for hash map
+          randomization, test data etc.
+    - RngState can also be used on its own with a fixed seed when a test
+      needs a reproducible sequence
+*/
+
+#[derive(Debug, Clone)]
+pub struct RngState {
+    state: u64,
+}
+
+impl RngState {
+    pub const fn new(seed: u64) -> Self {
+        // xorshift gets stuck on an all zero state
+        let state = if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        };
+        RngState { state }
+    }
+
+    // seed from the timestamp counter
+    pub fn from_tsc() -> Self {
+        Self::new(unsafe { core::arch::x86_64::_rdtsc() })
+    }
+
+    // xorshift64*
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // uniformly distributed in lo..hi
+    pub fn random_range(&mut self, lo: u64, hi: u64) -> u64 {
+        range_from(lo, hi, || self.next_u64())
+    }
+}
+
+static FALLBACK: Mutex<Option<RngState>> = Mutex::new(None);
+
+pub fn random_u64() -> u64 {
+    if let Some(value) = RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        return value;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FALLBACK
+            .lock()
+            .get_or_insert_with(RngState::from_tsc)
+            .next_u64()
+    })
+}
+
+// uniformly distributed in lo..hi
+pub fn random_range(lo: u64, hi: u64) -> u64 {
+    range_from(lo, hi, random_u64)
+}
+
+/*
+    map random u64s onto lo..hi without modulo bias
+
+    - `x % span` favours the low values when span doesn't evenly divide
+      2^64, so throw away anything below 2^64 % span (at most span - 1
+      values out of 2^64, so this almost never loops)
+*/
+fn range_from(lo: u64, hi: u64, mut next: impl FnMut() -> u64) -> u64 {
+    assert!(lo < hi, "empty range {}..{}", lo, hi);
+    let span = hi - lo;
+    // 2^64 % span without needing a u128
+    let threshold = span.wrapping_neg() % span;
+    loop {
+        let x = next();
+        if x >= threshold {
+            return lo + x % span;
+        }
+    }
+}
+
+#[test_case]
+fn test_rng_reproducible() {
+    let mut a = RngState::new(42);
+    let mut b = RngState::new(42);
+    assert_eq!(a.next_u64(), 0x56ce_4ab7_719b_a3a0);
+    assert_eq!(a.next_u64(), 0xc841_eb53_ebbb_2dda);
+    assert_eq!(a.next_u64(), 0xca46_6be0_c998_0276);
+    for _ in 0..3 {
+        b.next_u64();
+    }
+    for _ in 0..100 {
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+}
+
+#[test_case]
+fn test_random_range_bounds() {
+    let mut rng = RngState::new(7);
+    for _ in 0..1000 {
+        let x = rng.random_range(10, 17);
+        assert!((10..17).contains(&x));
+    }
+    assert_eq!(rng.random_range(5, 6), 5);
+    for _ in 0..100 {
+        let x = random_range(1, 4);
+        assert!((1..4).contains(&x));
+    }
+}
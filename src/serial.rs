@@ -1,15 +1,283 @@
-use lazy_static::lazy_static;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
 use spin::Mutex;
-use uart_16550::SerialPort;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        // SerialPort::new(PortNum) takes the first I/O port of the UART to calculate addresses
-        // of all the needed ports
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+
+// first I/O port of COM1, the rest of the UART's registers follow it
+pub const COM1_BASE: u16 = 0x3f8;
+
+/*
+    The kernel's serial port
+
+    - set up the first time anything prints (or by init()) at COM1_BASE,
+      unless init_with_base() got there first with some other base, e.g.
+      when the machine has COM1 somewhere else or output should go to COM2
+    - only ever initialized once, after that the base is fixed
+*/
+static SERIAL1: OnceCell<Mutex<BufferedSerial<PortUart>>> = OnceCell::uninit();
+static BASE: AtomicU16 = AtomicU16::new(COM1_BASE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyInitialized;
+
+pub fn init_with_base(base: u16) -> Result<(), AlreadyInitialized> {
+    SERIAL1
+        .try_init_once(|| {
+            BASE.store(base, Ordering::Relaxed);
+            // init() also enables the UART's "received data available"
+            // interrupt and its 16 byte FIFOs
+            let mut uart = PortUart::new(base, HwPorts);
+            uart.init();
+            Mutex::new(BufferedSerial::new(uart))
+        })
+        .map_err(|_| AlreadyInitialized)
+}
+
+// at whatever base was set, COM1_BASE if none was
+pub fn init() {
+    let _ = init_with_base(base());
+}
+
+// first I/O port of the UART in use
+pub fn base() -> u16 {
+    BASE.load(Ordering::Relaxed)
+}
+
+// None while the first init() is still running, i.e. something printed from
+// inside PortUart::init() (a fault, a try_print!), that output is dropped
+// rather than panicking, which would only try to print again
+pub fn serial1() -> Option<&'static Mutex<BufferedSerial<PortUart>>> {
+    init();
+    SERIAL1.get()
+}
+
+// the transmit half of a UART, split out so BufferedSerial can be tested
+// against a mock
+pub trait Uart {
+    // whether the transmit FIFO is completely empty
+    fn tx_fifo_empty(&mut self) -> bool;
+    fn write_data(&mut self, byte: u8);
+}
+
+// byte wide port I/O, so PortUart can be pointed at a mock
+pub trait PortIo {
+    fn read(&mut self, port: u16) -> u8;
+    fn write(&mut self, port: u16, value: u8);
+}
+
+pub struct HwPorts;
+
+impl PortIo for HwPorts {
+    fn read(&mut self, port: u16) -> u8 {
+        unsafe { crate::mmio::read_port_u8(port) }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        unsafe { crate::mmio::write_port_u8(port, value) }
+    }
+}
+
+// registers of a 16550 UART, as offsets from its base port
+const DATA: u16 = 0;
+const INT_EN: u16 = 1;
+const FIFO_CTRL: u16 = 2;
+const LINE_CTRL: u16 = 3;
+const MODEM_CTRL: u16 = 4;
+const LINE_STS: u16 = 5;
+
+// MODEM_CTRL bit 4: transmit straight into the receiver, nothing on the line
+const MCR_LOOPBACK: u8 = 1 << 4;
+// LINE_STS bit 0: a received byte is waiting in DATA
+const LSR_DATA_READY: u8 = 1 << 0;
+// LINE_STS bit 6: transmit FIFO and shift register both empty
+const LSR_TX_IDLE: u8 = 1 << 6;
+// polls of LINE_STS before giving up on the UART
+const LOOPBACK_POLLS: usize = 100_000;
+
+// a 16550 compatible UART at some base port
+pub struct PortUart<P: PortIo = HwPorts> {
+    base: u16,
+    io: P,
+}
+
+impl<P: PortIo> PortUart<P> {
+    pub const fn new(base: u16, io: P) -> Self {
+        PortUart { base, io }
+    }
+
+    // 38400 baud 8N1, FIFOs on, receive interrupt enabled
+    pub fn init(&mut self) {
+        // interrupts off while it's being set up
+        self.io.write(self.base + INT_EN, 0x00);
+        // DLAB on so DATA/INT_EN are the divisor latch, divisor 3 = 38400
+        self.io.write(self.base + LINE_CTRL, 0x80);
+        self.io.write(self.base + DATA, 0x03);
+        self.io.write(self.base + INT_EN, 0x00);
+        // DLAB off, 8 bit words
+        self.io.write(self.base + LINE_CTRL, 0x03);
+        // enable and clear the FIFOs, interrupt at 14 bytes
+        self.io.write(self.base + FIFO_CTRL, 0xc7);
+        // DTR, RTS and OUT2 (which gates the IRQ line)
+        self.io.write(self.base + MODEM_CTRL, 0x0b);
+        // received data available interrupt
+        self.io.write(self.base + INT_EN, 0x01);
+    }
+}
+
+impl<P: PortIo> PortUart<P> {
+    /*
+        send `byte` to ourselves in loopback mode, true if it came back
+
+        - covers the whole transmit and receive path of the UART without
+          anything wired to it
+        - waits for everything already sent to be fully out on the line,
+          in loopback mode it would go to our own receiver instead
+        - anything already waiting in the receive FIFO is thrown away first
+          so it isn't mistaken for the echo
+        - MODEM_CTRL is put back how it was afterwards
+    */
+    pub fn loopback_test(&mut self, byte: u8) -> bool {
+        if !self.poll_line_sts(LSR_TX_IDLE) {
+            return false;
+        }
+        let modem_ctrl = self.io.read(self.base + MODEM_CTRL);
+        for _ in 0..UART_FIFO_SIZE {
+            if self.io.read(self.base + LINE_STS) & LSR_DATA_READY == 0 {
+                break;
+            }
+            self.io.read(self.base + DATA);
+        }
+        self.io
+            .write(self.base + MODEM_CTRL, modem_ctrl | MCR_LOOPBACK);
+
+        self.write_data(byte);
+        let echoed = self.poll_line_sts(LSR_DATA_READY) && self.io.read(self.base + DATA) == byte;
+
+        self.io.write(self.base + MODEM_CTRL, modem_ctrl);
+        echoed
+    }
+
+    // wait for any of `bits` in LINE_STS, false if they never show up
+    fn poll_line_sts(&mut self, bits: u8) -> bool {
+        let line_sts = self.base + LINE_STS;
+        crate::util::poll_until(|| self.io.read(line_sts) & bits != 0, LOOPBACK_POLLS).is_ok()
+    }
+}
+
+impl<P: PortIo> Uart for PortUart<P> {
+    fn tx_fifo_empty(&mut self) -> bool {
+        // line status register, bit 5: transmitter holding register empty
+        self.io.read(self.base + LINE_STS) & (1 << 5) != 0
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        self.io.write(self.base + DATA, byte);
+    }
+}
+
+const SERIAL_BUF_SIZE: usize = 128;
+// bytes the 16550 can take in one go once it reports its FIFO empty
+const UART_FIFO_SIZE: usize = 16;
+// polls of the transmit FIFO before flush() gives up on the UART
+const TX_POLLS: usize = 100_000;
+
+/*
+    Line buffered serial output
+
+    - bytes collect in `buf` until a newline or until it fills up, then
+      flush() hands them to the UART
+    - the UART is only polled once per 16 bytes rather than once per byte
+      since an empty transmit FIFO can take a full 16 straight away
+    - a partial line stays in the buffer until something flushes it,
+      exit_qemu() and the test runner do so output isn't lost
+    - a UART whose FIFO never drains (missing or stuck port) would hang
+      every print, so after TX_POLLS the rest of the buffer is dropped and
+      counted instead
+    - only ever used behind serial1()'s lock with interrupts off, so a
+      handler can't see a half written buffer
+*/
+pub struct BufferedSerial<U: Uart> {
+    uart: U,
+    buf: [u8; SERIAL_BUF_SIZE],
+    len: usize,
+    dropped: usize,
+}
+
+impl<U: Uart> BufferedSerial<U> {
+    pub const fn new(uart: U) -> Self {
+        BufferedSerial {
+            uart,
+            buf: [0; SERIAL_BUF_SIZE],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let uart = &mut self.uart;
+        for (i, chunk) in self.buf[..self.len].chunks(UART_FIFO_SIZE).enumerate() {
+            if crate::util::poll_until(|| uart.tx_fifo_empty(), TX_POLLS).is_err() {
+                self.dropped += self.len - i * UART_FIFO_SIZE;
+                break;
+            }
+            for &byte in chunk {
+                uart.write_data(byte);
+            }
+        }
+        self.len = 0;
+    }
+
+    // bytes flush() threw away because the UART never took them
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl<U: Uart> fmt::Write for BufferedSerial<U> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+            if byte == b'\n' || self.len == SERIAL_BUF_SIZE {
+                self.flush();
+            }
+        }
+        Ok(())
+    }
+}
+
+// push out whatever partial line is still buffered
+// uses try_lock() since this also runs on the way out (exit_qemu()), where
+// waiting on a lock the interrupted code holds would hang instead of exiting
+pub fn flush() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(mut serial) = serial1().and_then(|serial| serial.try_lock()) {
+            serial.flush();
+        }
+    });
+}
+
+/*
+    self test of the serial port's UART, see PortUart::loopback_test()
+
+    - interrupts are off the whole time so the COM1 handler can't take the
+      echoed byte (or any input the test throws away) out of the FIFO
+    - buffered output goes out first, anything printed during the test
+      would only have reached the receiver anyway
+*/
+pub fn loopback_test() -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut serial = match serial1() {
+            Some(serial) => serial.lock(),
+            None => return false,
+        };
+        serial.flush();
+        serial.uart.loopback_test(0xa5)
+    })
 }
 
 // already implemented in vga_buf plus used the default macros as a guide so a simple copy and
@@ -26,6 +294,25 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+// set by muted(), the panic handler still gets through
+#[cfg(test)]
+static MUTED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(test)]
+fn is_muted() -> bool {
+    MUTED.load(Ordering::Relaxed) && !crate::panicking()
+}
+
+// run `f` with serial_print! and test_log! output thrown away, for tests
+// that run other tests
+#[cfg(test)]
+pub(crate) fn muted<R>(f: impl FnOnce() -> R) -> R {
+    MUTED.store(true, Ordering::Relaxed);
+    let ret = f();
+    MUTED.store(false, Ordering::Relaxed);
+    ret
+}
+
 // use doc(hidden) to hide function from generated documentation
 // as it is a private implementation detail
 #[doc(hidden)]
@@ -33,11 +320,289 @@ pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    #[cfg(test)]
+    if is_muted() {
+        return;
+    }
+
     // prevents deadlocks by making sure the Mutex runs without interruption
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Serial printing failed");
+        if let Some(serial) = serial1() {
+            serial
+                .lock()
+                .write_fmt(args)
+                .expect("Serial printing failed");
+        }
+    });
+}
+
+/*
+    Framed records for host tools
+
+    - test_log! wraps its message in RECORD_SEP (ASCII record separator,
+      0x1e) on both sides and ends the line: "\x1e<payload>\x1e\n"
+    - so a host script can pull the records out of the serial stream with
+      a regex no matter what else the kernel prints around them
+    - a 0x1e inside the payload is written as the text "\x1e" so the
+      markers only ever show up at the ends
+*/
+pub const RECORD_SEP: char = '\x1e';
+
+// RECORD_SEP in a payload would end the record early
+struct EscapeSep<'a, W: fmt::Write>(&'a mut W);
+
+impl<W: fmt::Write> fmt::Write for EscapeSep<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, part) in s.split(RECORD_SEP).enumerate() {
+            if i > 0 {
+                self.0.write_str("\\x1e")?;
+            }
+            self.0.write_str(part)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_framed(out: &mut impl fmt::Write, args: fmt::Arguments) -> fmt::Result {
+    out.write_char(RECORD_SEP)?;
+    fmt::write(&mut EscapeSep(out), args)?;
+    out.write_char(RECORD_SEP)?;
+    out.write_char('\n')
+}
+
+#[macro_export]
+macro_rules! test_log {
+    ($($arg:tt)*) => ($crate::serial::_log(format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    use x86_64::instructions::interrupts;
+
+    #[cfg(test)]
+    if is_muted() {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        if let Some(serial) = serial1() {
+            write_framed(&mut *serial.lock(), args).expect("Serial printing failed");
+        }
+    });
+}
+
+// records what was sent and how often the FIFO was polled
+#[cfg(test)]
+struct MockUart {
+    out: [u8; 512],
+    len: usize,
+    polls: usize,
+}
+
+#[cfg(test)]
+impl Uart for MockUart {
+    fn tx_fifo_empty(&mut self) -> bool {
+        self.polls += 1;
+        true
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        self.out[self.len] = byte;
+        self.len += 1;
+    }
+}
+
+// test that a large block comes out in order with one poll per FIFO load
+#[test_case]
+fn test_buffered_serial_block() {
+    use core::fmt::Write;
+
+    let mut serial = BufferedSerial::new(MockUart {
+        out: [0; 512],
+        len: 0,
+        polls: 0,
+    });
+    let mut expected = [0u8; 400];
+    for (i, byte) in expected.iter_mut().enumerate() {
+        *byte = b'a' + (i % 26) as u8;
+    }
+    expected[399] = b'\n';
+    for chunk in expected.chunks(7) {
+        serial
+            .write_str(core::str::from_utf8(chunk).unwrap())
+            .unwrap();
+    }
+
+    // the newline at the end flushed everything
+    assert_eq!(serial.len, 0);
+    assert_eq!(&serial.uart.out[..serial.uart.len], &expected[..]);
+    // 3 full buffers of 128 + the last 16 bytes, 8 polls each + 1
+    assert_eq!(serial.uart.polls, 3 * 8 + 1);
+}
+
+// a partial line sits in the buffer until flushed
+#[test_case]
+fn test_buffered_serial_partial_line() {
+    use core::fmt::Write;
+
+    let mut serial = BufferedSerial::new(MockUart {
+        out: [0; 512],
+        len: 0,
+        polls: 0,
     });
+    serial.write_str("no newline").unwrap();
+    assert_eq!(serial.uart.len, 0);
+    serial.flush();
+    assert_eq!(&serial.uart.out[..serial.uart.len], b"no newline");
+}
+
+// a transmit FIFO that never empties
+#[cfg(test)]
+struct StuckUart {
+    polls: usize,
+}
+
+#[cfg(test)]
+impl Uart for StuckUart {
+    fn tx_fifo_empty(&mut self) -> bool {
+        self.polls += 1;
+        false
+    }
+
+    fn write_data(&mut self, _byte: u8) {
+        panic!("wrote to a full FIFO");
+    }
+}
+
+// test that a stuck UART costs one bounded wait and the line is dropped
+#[test_case]
+fn test_buffered_serial_stuck_uart() {
+    use core::fmt::Write;
+
+    let mut serial = BufferedSerial::new(StuckUart { polls: 0 });
+    serial.write_str("lost line\n").unwrap();
+    assert_eq!(serial.len, 0);
+    assert_eq!(serial.dropped(), 10);
+    assert_eq!(serial.uart.polls, TX_POLLS);
+}
+
+// test that the markers show up once at each end, even if the payload has one
+#[test_case]
+fn test_framed_record() {
+    let mut serial = BufferedSerial::new(MockUart {
+        out: [0; 512],
+        len: 0,
+        polls: 0,
+    });
+    write_framed(&mut serial, format_args!("test:ok:{}", "a\x1eb")).unwrap();
+
+    let out = &serial.uart.out[..serial.uart.len];
+    assert_eq!(out, b"\x1etest:ok:a\\x1eb\x1e\n");
+    assert_eq!(out.iter().filter(|&&b| b == 0x1e).count(), 2);
+}
+
+// remembers every port write, and says the transmit FIFO is always empty
+#[cfg(test)]
+struct MockPorts {
+    writes: [(u16, u8); 16],
+    len: usize,
+    last_read: u16,
+}
+
+#[cfg(test)]
+impl PortIo for MockPorts {
+    fn read(&mut self, port: u16) -> u8 {
+        self.last_read = port;
+        1 << 5
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        self.writes[self.len] = (port, value);
+        self.len += 1;
+    }
+}
+
+// test that a UART at COM2's base only touches COM2's ports
+#[test_case]
+fn test_port_uart_alternate_base() {
+    const COM2_BASE: u16 = 0x2f8;
+    let mut uart = PortUart::new(
+        COM2_BASE,
+        MockPorts {
+            writes: [(0, 0); 16],
+            len: 0,
+            last_read: 0,
+        },
+    );
+    uart.init();
+    let expected = [
+        (0x2f9, 0x00),
+        (0x2fb, 0x80),
+        (0x2f8, 0x03),
+        (0x2f9, 0x00),
+        (0x2fb, 0x03),
+        (0x2fa, 0xc7),
+        (0x2fc, 0x0b),
+        (0x2f9, 0x01),
+    ];
+    assert_eq!(&uart.io.writes[..uart.io.len], &expected[..]);
+
+    assert!(uart.tx_fifo_empty());
+    assert_eq!(uart.io.last_read, 0x2fd);
+    uart.write_data(b'x');
+    assert_eq!(uart.io.writes[uart.io.len - 1], (0x2f8, b'x'));
+}
+
+// by the time tests run serial output has long since set the port up
+#[test_case]
+fn test_serial_init_once() {
+    assert_eq!(init_with_base(0x2f8), Err(AlreadyInitialized));
+    assert_eq!(base(), COM1_BASE);
+}
+
+// a UART that receives what it sends only in loopback mode, or never if
+// its receiver is `dead`
+#[cfg(test)]
+struct LoopbackPorts {
+    modem_ctrl: u8,
+    rx: Option<u8>,
+    dead: bool,
+}
+
+#[cfg(test)]
+impl PortIo for LoopbackPorts {
+    fn read(&mut self, port: u16) -> u8 {
+        match port - COM1_BASE {
+            DATA => self.rx.take().unwrap_or(0),
+            MODEM_CTRL => self.modem_ctrl,
+            LINE_STS => LSR_TX_IDLE | (1 << 5) | self.rx.is_some() as u8,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        match port - COM1_BASE {
+            DATA if self.modem_ctrl & MCR_LOOPBACK != 0 && !self.dead => self.rx = Some(value),
+            MODEM_CTRL => self.modem_ctrl = value,
+            _ => {}
+        }
+    }
+}
+
+// test that stale input is drained, the echo checked and loopback undone
+#[test_case]
+fn test_loopback_echo() {
+    let ports = LoopbackPorts {
+        modem_ctrl: 0x0b,
+        rx: Some(b'?'),
+        dead: false,
+    };
+    let mut uart = PortUart::new(COM1_BASE, ports);
+    assert!(uart.loopback_test(0xa5));
+    assert_eq!(uart.io.modem_ctrl, 0x0b);
+    assert_eq!(uart.io.rx, None);
+
+    uart.io.dead = true;
+    assert!(!uart.loopback_test(0xa5));
+    assert_eq!(uart.io.modem_ctrl, 0x0b);
 }
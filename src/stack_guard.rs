@@ -0,0 +1,118 @@
+use crate::{gdt, heap, rand};
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/*
+    Stack canaries
+
+    - a random u64 written at the edge of a memory region, anything that
+      runs off the end of the region (e.g. a stack overflowing downward or a
+      heap block overrun) has to trample it first
+    - check_canary() compares it to the expected value and panics if it
+      changed, check_all() does that for every canary the kernel places:
+        - the bottom of the double fault and page fault IST stacks (they
+          have no guard page)
+        - just below and just above the heap region
+    - the timer handler uses check_all_from_irq() instead, which only
+      compares and remembers the first bad canary: a panic in the handler
+      would be blamed on whatever code the tick interrupted and skip the
+      EOI, so check_pending() panics later from normal context (the
+      executor loop and hlt_loop())
+    - random per boot so a buggy write can't "accidentally" match it
+*/
+
+// fixed until init() swaps in a random one
+static CANARY: AtomicU64 = AtomicU64::new(0xdead_c0de_dead_c0de);
+// address of the canary check_all_from_irq() found overwritten, 0 if none
+static SMASHED: AtomicUsize = AtomicUsize::new(0);
+
+// pick a random canary and guard the IST stacks, must run before
+// any other canary is placed (i.e. before init_heap)
+pub fn init() {
+    CANARY.store(rand::random_u64(), Ordering::Relaxed);
+    unsafe {
+        place_canary(gdt::double_fault_stack_bottom());
+        place_canary(gdt::page_fault_stack_bottom());
+    }
+}
+
+// caller has to guarantee `addr` is mapped, 8-byte aligned and not used for
+// anything else
+pub unsafe fn place_canary(addr: usize) {
+    write_volatile(addr as *mut u64, CANARY.load(Ordering::Relaxed));
+}
+
+fn intact(addr: usize) -> bool {
+    unsafe { read_volatile(addr as *const u64) == CANARY.load(Ordering::Relaxed) }
+}
+
+pub fn check_canary(addr: usize, region: &str) {
+    if !intact(addr) {
+        let value = unsafe { read_volatile(addr as *const u64) };
+        panic!(
+            "STACK SMASHING DETECTED: {} canary at {:#x} overwritten with {:#x}",
+            region, addr, value
+        );
+    }
+}
+
+// every canary the kernel places, with the region it guards
+fn canaries() -> impl Iterator<Item = (usize, &'static str)> {
+    let heap = if heap::is_heap_ready() {
+        let (below, above) = heap::guard_addrs();
+        [Some((below, "heap start")), Some((above, "heap end"))]
+    } else {
+        [None, None]
+    };
+    // IntoIterator::into_iter() since edition 2018's .into_iter() on an array
+    // yields references
+    IntoIterator::into_iter([
+        (gdt::double_fault_stack_bottom(), "double fault stack"),
+        (gdt::page_fault_stack_bottom(), "page fault stack"),
+    ])
+    .chain(IntoIterator::into_iter(heap).flatten())
+}
+
+pub fn check_all() {
+    for (addr, region) in canaries() {
+        check_canary(addr, region);
+    }
+}
+
+// for interrupt handlers: never panics, check_pending() does that
+pub fn check_all_from_irq() {
+    if SMASHED.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    if let Some((addr, _)) = canaries().find(|&(addr, _)| !intact(addr)) {
+        SMASHED.store(addr, Ordering::Relaxed);
+    }
+}
+
+// panics if check_all_from_irq() found an overwritten canary
+pub fn check_pending() {
+    let addr = SMASHED.load(Ordering::Relaxed);
+    if addr != 0 {
+        let region = canaries()
+            .find(|&(canary, _)| canary == addr)
+            .map_or("unknown", |(_, region)| region);
+        check_canary(addr, region);
+    }
+}
+
+// test that the IRQ check only records the overwritten canary
+#[test_case]
+fn test_check_all_from_irq_defers() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    // so the timer can't see the canary while it's trampled
+    without_interrupts(|| {
+        let addr = gdt::page_fault_stack_bottom();
+        unsafe { write_volatile(addr as *mut u64, !CANARY.load(Ordering::Relaxed)) };
+        check_all_from_irq();
+        assert_eq!(SMASHED.swap(0, Ordering::Relaxed), addr);
+        unsafe { place_canary(addr) };
+        check_all_from_irq();
+        assert_eq!(SMASHED.load(Ordering::Relaxed), 0);
+    });
+}
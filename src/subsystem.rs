@@ -0,0 +1,421 @@
+use crate::{gdt, interrupts, klog, serial, stack_guard, syscall, vga_buf, InitError};
+use core::fmt;
+
+/*
+    Subsystems
+
+    - each piece of the kernel that needs setting up at boot implements
+      Subsystem and gets registered, a Registry then brings them all up in
+      an order that respects what each one says it depends_on()
+    - dependencies are by name and only ever have to be declared, the
+      order they're registered in doesn't matter beyond breaking ties
+      (first registered goes first)
+    - the first init() to fail stops everything, the ones already up stay
+      up and shutdown_all() takes them back down in reverse
+    - fixed capacity and no heap, this runs long before init_heap()
+*/
+pub trait Subsystem: Sync {
+    fn name(&self) -> &'static str;
+
+    // names of the subsystems that have to be up before this one
+    fn depends_on(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn init(&self) -> Result<(), InitError>;
+
+    fn shutdown(&self) {}
+}
+
+pub const MAX_SUBSYSTEMS: usize = 16;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    // already MAX_SUBSYSTEMS registered
+    Full,
+    // depends on something that isn't registered, or on itself via a cycle
+    Unresolved(&'static str),
+    Failed(&'static str, InitError),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::Full => write!(f, "more than {} subsystems", MAX_SUBSYSTEMS),
+            RegistryError::Unresolved(name) => {
+                write!(f, "{}: dependencies missing or circular", name)
+            }
+            RegistryError::Failed(name, err) => write!(f, "{}: {}", name, err),
+        }
+    }
+}
+
+pub struct Registry {
+    entries: [Option<&'static dyn Subsystem>; MAX_SUBSYSTEMS],
+    len: usize,
+    // indices into entries in the order they were brought up
+    order: [usize; MAX_SUBSYSTEMS],
+    started: usize,
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Registry {
+            entries: [None; MAX_SUBSYSTEMS],
+            len: 0,
+            order: [0; MAX_SUBSYSTEMS],
+            started: 0,
+        }
+    }
+
+    pub fn register(&mut self, subsystem: &'static dyn Subsystem) -> Result<(), RegistryError> {
+        if self.len == MAX_SUBSYSTEMS {
+            return Err(RegistryError::Full);
+        }
+        self.entries[self.len] = Some(subsystem);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn entry(&self, idx: usize) -> &'static dyn Subsystem {
+        self.entries[idx].expect("registered subsystem missing")
+    }
+
+    fn is_started(&self, name: &str) -> bool {
+        self.order[..self.started]
+            .iter()
+            .any(|&idx| self.entry(idx).name() == name)
+    }
+
+    // bring up everything registered that isn't up yet
+    pub fn init_all(&mut self) -> Result<(), RegistryError> {
+        while self.started < self.len {
+            let ready = (0..self.len).find(|&idx| {
+                let subsystem = self.entry(idx);
+                !self.is_started(subsystem.name())
+                    && subsystem
+                        .depends_on()
+                        .iter()
+                        .all(|dep| self.is_started(dep))
+            });
+            let idx = match ready {
+                Some(idx) => idx,
+                None => {
+                    // first one left over is as good as any to blame
+                    let stuck = (0..self.len)
+                        .map(|idx| self.entry(idx))
+                        .find(|subsystem| !self.is_started(subsystem.name()))
+                        .expect("nothing left to start");
+                    return Err(RegistryError::Unresolved(stuck.name()));
+                }
+            };
+            let subsystem = self.entry(idx);
+            subsystem
+                .init()
+                .map_err(|err| RegistryError::Failed(subsystem.name(), err))?;
+            self.order[self.started] = idx;
+            self.started += 1;
+        }
+        Ok(())
+    }
+
+    // reverse of the order they came up in
+    pub fn shutdown_all(&mut self) {
+        while self.started > 0 {
+            self.started -= 1;
+            self.entry(self.order[self.started]).shutdown();
+        }
+    }
+
+    // names of the subsystems that are up, in the order they came up
+    pub fn started(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.order[..self.started]
+            .iter()
+            .map(move |&idx| self.entry(idx).name())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* THE KERNEL'S OWN SUBSYSTEMS, brought up by crate::init() */
+
+struct Gdt;
+
+impl Subsystem for Gdt {
+    fn name(&self) -> &'static str {
+        "gdt"
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        gdt::init();
+        if !gdt::is_loaded() {
+            return Err(InitError::Gdt);
+        }
+        Ok(())
+    }
+}
+
+struct Idt;
+
+impl Subsystem for Idt {
+    fn name(&self) -> &'static str {
+        "idt"
+    }
+
+    // the IST stacks the handlers use live in the TSS
+    fn depends_on(&self) -> &'static [&'static str] {
+        &["gdt"]
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        interrupts::init();
+        if !interrupts::is_loaded() {
+            return Err(InitError::Idt);
+        }
+        // anything that would turn the next fault into a triple fault
+        #[cfg(feature = "fault_checks")]
+        crate::fault_check::report();
+        Ok(())
+    }
+}
+
+struct Pic;
+
+impl Subsystem for Pic {
+    fn name(&self) -> &'static str {
+        "pic"
+    }
+
+    fn depends_on(&self) -> &'static [&'static str] {
+        &["idt"]
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        unsafe { interrupts::PICS.lock().initialize() };
+        if !interrupts::pics_respond() {
+            return Err(InitError::Pic);
+        }
+        Ok(())
+    }
+}
+
+struct Serial;
+
+impl Subsystem for Serial {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    // its receive interrupt comes through the PIC
+    fn depends_on(&self) -> &'static [&'static str] {
+        &["pic"]
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        serial::init();
+        // unmasked no matter what the mask was before, serial::init() has
+        // just turned on the UART's receive interrupt and SerialStream
+        // never sees a byte without it
+        interrupts::unmask_irq(interrupts::SERIAL1_IRQ);
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        serial::flush();
+    }
+}
+
+struct Console;
+
+impl Subsystem for Console {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        vga_buf::set_theme(vga_buf::Theme::from_boot_args(), true);
+        klog::init();
+        Ok(())
+    }
+}
+
+struct StackGuard;
+
+impl Subsystem for StackGuard {
+    fn name(&self) -> &'static str {
+        "stack_guard"
+    }
+
+    // guards the double fault and page fault stacks in the TSS
+    fn depends_on(&self) -> &'static [&'static str] {
+        &["gdt"]
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        stack_guard::init();
+        Ok(())
+    }
+}
+
+struct Syscall;
+
+impl Subsystem for Syscall {
+    fn name(&self) -> &'static str {
+        "syscall"
+    }
+
+    // IA32_STAR holds selectors into our GDT
+    fn depends_on(&self) -> &'static [&'static str] {
+        &["gdt"]
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        syscall::init();
+        Ok(())
+    }
+}
+
+// in the order init() registers them, adding a subsystem to the boot
+// sequence is adding it here
+pub(crate) static KERNEL_SUBSYSTEMS: [&dyn Subsystem; 7] =
+    [&Gdt, &Idt, &Pic, &Serial, &Console, &StackGuard, &Syscall];
+
+// records the order the test subsystems come up and go down in
+#[cfg(test)]
+static EVENTS: [core::sync::atomic::AtomicUsize; 8] = {
+    use core::sync::atomic::AtomicUsize;
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; 8]
+};
+#[cfg(test)]
+static NEXT_EVENT: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(test)]
+fn log_event(event: usize) {
+    use core::sync::atomic::Ordering;
+    EVENTS[NEXT_EVENT.fetch_add(1, Ordering::SeqCst)].store(event, Ordering::SeqCst);
+}
+
+// everything logged since the last call, zero past the end
+#[cfg(test)]
+fn take_events() -> [usize; 8] {
+    use core::sync::atomic::Ordering;
+    let mut events = [0; 8];
+    let len = NEXT_EVENT.swap(0, Ordering::SeqCst);
+    for (event, logged) in events.iter_mut().zip(EVENTS.iter()).take(len) {
+        *event = logged.swap(0, Ordering::SeqCst);
+    }
+    events
+}
+
+// logs `id` on init and `id + 10` on shutdown
+#[cfg(test)]
+struct TestSubsystem {
+    name: &'static str,
+    id: usize,
+    deps: &'static [&'static str],
+    fails: bool,
+}
+
+#[cfg(test)]
+impl Subsystem for TestSubsystem {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn depends_on(&self) -> &'static [&'static str] {
+        self.deps
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        log_event(self.id);
+        if self.fails {
+            return Err(InitError::Subsystem("test failure"));
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        log_event(self.id + 10);
+    }
+}
+
+#[cfg(test)]
+static TEST_A: TestSubsystem = TestSubsystem {
+    name: "a",
+    id: 1,
+    deps: &[],
+    fails: false,
+};
+#[cfg(test)]
+static TEST_B: TestSubsystem = TestSubsystem {
+    name: "b",
+    id: 2,
+    deps: &["a"],
+    fails: false,
+};
+
+#[test_case]
+fn test_dependency_order() {
+    let mut registry = Registry::new();
+    // registered backwards, b still has to wait for a
+    registry.register(&TEST_B).unwrap();
+    registry.register(&TEST_A).unwrap();
+    registry.init_all().unwrap();
+    {
+        let mut started = registry.started();
+        assert_eq!((started.next(), started.next()), (Some("a"), Some("b")));
+    }
+
+    registry.shutdown_all();
+    assert_eq!(take_events()[..4], [1, 2, 12, 11]);
+    assert_eq!(registry.started().count(), 0);
+}
+
+#[test_case]
+fn test_failed_init_aborts() {
+    static FAILS: TestSubsystem = TestSubsystem {
+        name: "fails",
+        id: 3,
+        deps: &[],
+        fails: true,
+    };
+
+    let mut registry = Registry::new();
+    registry.register(&TEST_A).unwrap();
+    registry.register(&FAILS).unwrap();
+    registry.register(&TEST_B).unwrap();
+    let err = registry.init_all().unwrap_err();
+    assert!(matches!(
+        err,
+        RegistryError::Failed("fails", InitError::Subsystem(_))
+    ));
+    // b never ran, a is still up
+    assert_eq!(take_events()[..3], [1, 3, 0]);
+    assert_eq!(registry.started().count(), 1);
+}
+
+#[test_case]
+fn test_unresolved_dependency() {
+    static LOOPS: TestSubsystem = TestSubsystem {
+        name: "loops",
+        id: 4,
+        deps: &["loops"],
+        fails: false,
+    };
+
+    let mut registry = Registry::new();
+    registry.register(&LOOPS).unwrap();
+    let err = registry.init_all().unwrap_err();
+    assert!(matches!(err, RegistryError::Unresolved("loops")));
+    assert_eq!(take_events()[0], 0);
+}
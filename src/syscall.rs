@@ -0,0 +1,212 @@
+use crate::{cpu, gdt};
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+/*
+    Fast system calls (syscall/sysret)
+
+    - `syscall` jumps straight to IA32_LSTAR at CPL 0, the user rip goes in
+      rcx and rflags in r11, nothing is pushed and the stack isn't switched,
+      `sysretq` puts rip and rflags back and drops to CPL 3
+    - CS/SS both ways come from IA32_STAR rather than the GDT:
+        - syscall: CS = STAR[47:32], SS = that + 8, i.e. kernel code then
+          kernel data
+        - sysret: SS = STAR[63:48] + 8, CS = that + 16, i.e. user data then
+          user code, hence the order in gdt.rs
+    - IA32_FMASK clears IF (and TF, DF, AC) on the way in, the handler runs
+      with interrupts off on its own stack so nothing else can land on it
+    - same convention as Linux: number in rax, arguments in rdi, rsi, rdx,
+      the result back in rax, everything but rcx and r11 preserved
+    - only for ring 3, sysret from a `syscall` made in the kernel would
+      still go to CPL 3
+*/
+
+// returns its first argument + 1
+pub const SYS_PING: u64 = 0;
+// never returns to the user code, enter_user() returns its first argument
+pub const SYS_EXIT: u64 = 1;
+// what rax comes back as for a number nobody handles
+pub const UNKNOWN_SYSCALL: u64 = u64::MAX;
+
+const SYSCALL_STACK_SIZE: usize = 4096 * 5;
+
+// bit 1 of rflags is reserved and always set, IF stays clear so user code
+// can't be interrupted (see enter_user())
+const USER_RFLAGS: u64 = 0x2;
+
+#[repr(C, align(16))]
+struct Stack([u8; SYSCALL_STACK_SIZE]);
+
+static mut SYSCALL_STACK: Stack = Stack([0; SYSCALL_STACK_SIZE]);
+// only touched by the assembly below
+static mut USER_RSP: u64 = 0;
+static mut KERNEL_RSP: u64 = 0;
+
+static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    use x86_64::registers::rflags::RFlags;
+
+    let star =
+        ((gdt::user_data_selector().0 as u64 - 8) << 48) | ((gdt::code_selector().0 as u64) << 32);
+    let fmask = RFlags::INTERRUPT_FLAG
+        | RFlags::TRAP_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::ALIGNMENT_CHECK;
+    unsafe {
+        // all 3 MSRs exist on every x86_64 CPU, and the selectors are the
+        // ones gdt::init() loaded
+        cpu::write_msr(cpu::IA32_STAR, star);
+        cpu::write_msr(
+            cpu::IA32_LSTAR,
+            syscall_entry as extern "C" fn() -> ! as u64,
+        );
+        cpu::write_msr(cpu::IA32_FMASK, fmask.bits());
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+// syscalls dispatched since boot, SYS_EXIT doesn't count
+pub fn handled() -> usize {
+    HANDLED.load(Ordering::Relaxed)
+}
+
+extern "C" fn dispatch(number: u64, arg0: u64, _arg1: u64, _arg2: u64) -> u64 {
+    HANDLED.fetch_add(1, Ordering::Relaxed);
+    match number {
+        SYS_PING => arg0.wrapping_add(1),
+        _ => UNKNOWN_SYSCALL,
+    }
+}
+
+/*
+    what IA32_LSTAR points at
+
+    - SYS_EXIT never goes back to user mode, it unwinds to the kernel stack
+      enter_user() saved and returns from there
+    - anything else: switch to SYSCALL_STACK, save the user rsp, rip,
+      rflags and the caller saved registers the convention preserves, call
+      dispatch(rax, rdi, rsi, rdx) and sysret with its result in rax
+    - 10 pushes (rax included) keep the stack 16 byte aligned for the call
+*/
+#[naked]
+extern "C" fn syscall_entry() -> ! {
+    unsafe {
+        naked_asm!("
+            cmp rax, {exit};
+            je 2f;
+            mov [rip + {user_rsp}], rsp;
+            lea rsp, [rip + {stack} + {stack_size}];
+            push qword ptr [rip + {user_rsp}];
+            push rcx;
+            push r11;
+            push rdi;
+            push rsi;
+            push rdx;
+            push r10;
+            push r8;
+            push r9;
+            push rax;
+            mov rcx, rdx;
+            mov rdx, rsi;
+            mov rsi, rdi;
+            mov rdi, rax;
+            call {dispatch};
+            add rsp, 8;
+            pop r9;
+            pop r8;
+            pop r10;
+            pop rdx;
+            pop rsi;
+            pop rdi;
+            pop r11;
+            pop rcx;
+            pop rsp;
+            sysretq;
+        2:
+            mov rax, rdi;
+            mov rsp, [rip + {kernel_rsp}];
+            popfq;
+            pop r15;
+            pop r14;
+            pop r13;
+            pop r12;
+            pop rbx;
+            pop rbp;
+            ret",
+            exit = const SYS_EXIT,
+            user_rsp = sym USER_RSP,
+            stack = sym SYSCALL_STACK,
+            stack_size = const SYSCALL_STACK_SIZE,
+            dispatch = sym dispatch,
+            kernel_rsp = sym KERNEL_RSP);
+    }
+}
+
+/*
+    run the user code at `entry` on the stack ending at `stack_top` until it
+    makes a SYS_EXIT syscall, returns the code it passed
+
+    - both have to be mapped USER_ACCESSIBLE (and the code without
+      NO_EXECUTE), e.g. by loader::load_elf()
+    - the user code starts with interrupts off and can't turn them on
+      (IOPL 0), so it runs until it exits or faults
+    - not reentrant, there's only one saved kernel stack pointer
+*/
+pub unsafe fn enter_user(entry: VirtAddr, stack_top: VirtAddr) -> u64 {
+    enter_user_asm(
+        entry.as_u64(),
+        stack_top.as_u64(),
+        gdt::user_code_selector().0 as u64,
+        gdt::user_data_selector().0 as u64,
+    )
+}
+
+// saves the callee saved registers and rflags for the SYS_EXIT path in
+// syscall_entry to restore, then iretq's into ring 3
+#[naked]
+unsafe extern "C" fn enter_user_asm(entry: u64, stack_top: u64, cs: u64, ss: u64) -> u64 {
+    naked_asm!("
+        push rbp;
+        push rbx;
+        push r12;
+        push r13;
+        push r14;
+        push r15;
+        pushfq;
+        mov [rip + {kernel_rsp}], rsp;
+        push rcx;
+        push rsi;
+        push {rflags};
+        push rdx;
+        push rdi;
+        iretq",
+        kernel_rsp = sym KERNEL_RSP,
+        rflags = const USER_RFLAGS);
+}
+
+#[test_case]
+fn test_syscall_msrs() {
+    let star = unsafe { cpu::read_msr(cpu::IA32_STAR) };
+    assert_eq!((star >> 32) as u16, gdt::code_selector().0);
+    // sysret adds 16 for CS and 8 for SS
+    assert_eq!((star >> 48) as u16 + 16, gdt::user_code_selector().0);
+    assert_eq!((star >> 48) as u16 + 8, gdt::user_data_selector().0);
+    assert_eq!(gdt::code_selector().0 + 8, gdt::data_selector().0);
+    assert_eq!(
+        unsafe { cpu::read_msr(cpu::IA32_LSTAR) },
+        syscall_entry as extern "C" fn() -> ! as u64
+    );
+    // SCE
+    assert_ne!(cpu::efer() & 1, 0);
+}
+
+#[test_case]
+fn test_dispatch() {
+    let before = handled();
+    assert_eq!(dispatch(SYS_PING, 41, 0, 0), 42);
+    assert_eq!(dispatch(0xdead, 0, 0, 0), UNKNOWN_SYSCALL);
+    assert_eq!(handled(), before + 2);
+}
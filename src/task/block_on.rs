@@ -0,0 +1,52 @@
+use alloc::{sync::Arc, task::Wake};
+use core::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/*
+    run a single future to completion on the current stack, without an Exec
+
+    - the waker only sets a flag, if it wasn't set after a poll the CPU
+      `hlt`s until the next interrupt instead of spinning, then polls again
+        - an interrupt that has nothing to do with this future just causes
+          one extra poll
+    - like Exec::run() this leaves interrupts enabled, it can't sleep
+      otherwise
+*/
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+    let woken = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        // same as Exec::sleep_if_idle(), a wake up from an interrupt
+        // handler can't slip in between the check and the `hlt`
+        interrupts::disable();
+        if woken.0.swap(false, Ordering::SeqCst) {
+            interrupts::enable();
+        } else {
+            enable_and_hlt();
+        }
+    }
+}
+
+struct FlagWaker(AtomicBool);
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
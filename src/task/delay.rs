@@ -0,0 +1,46 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/*
+    wait until the tick counter has moved on by `n` timer ticks
+
+    - no wake list or deadline queue behind it, the future just wakes
+      itself every time it's polled and checks the counter again, so the
+      task gets polled on every pass of the executor until it's done
+        - fine for coarse delays with few waiters, it keeps the executor
+          from ever going to sleep while one is pending though
+    - the start point is taken when delay_ticks() is called, not on the
+      first poll
+*/
+pub fn delay_ticks(n: u64) -> DelayTicks {
+    delay_ticks_with(n, crate::interrupts::ticks)
+}
+
+// same as delay_ticks() but reading ticks from `clock`, e.g. a counter a
+// test advances by hand
+pub fn delay_ticks_with(n: u64, clock: fn() -> u64) -> DelayTicks {
+    DelayTicks {
+        until: clock().saturating_add(n),
+        clock,
+    }
+}
+
+pub struct DelayTicks {
+    until: u64,
+    clock: fn() -> u64,
+}
+
+impl Future for DelayTicks {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if (self.clock)() >= self.until {
+            return Poll::Ready(());
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,75 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+/*
+    One-shot notification ("boot done", "device ready", ...)
+
+    - notify() just sets a flag and wakes the waiter, no locks and no
+      allocation so it's fine to call from an interrupt handler
+    - wait() finishes once notify() has been called, straight away if that
+      already happened, and stays that way (there's no reset)
+    - backed by a single AtomicWaker so it's meant for one waiting task at
+      a time, with several only the one that polled last gets woken
+    - const new() so it can live in a static shared with a handler
+*/
+pub struct Event {
+    set: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Event {
+            set: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    pub fn notify(&self) {
+        self.set.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    pub fn wait(&self) -> Wait<'_> {
+        Wait { event: self }
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Wait<'a> {
+    event: &'a Event,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // fast path, avoid registering the waker if already notified
+        if self.event.is_set() {
+            return Poll::Ready(());
+        }
+
+        self.event.waker.register(cx.waker());
+        // notify() may have run between the check and register()
+        if self.event.is_set() {
+            self.event.waker.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
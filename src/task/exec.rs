@@ -0,0 +1,217 @@
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+/*
+    Executor
+
+    - tasks: every spawned task, keyed by its ID so a wake up only has to
+      look up the task that was woken rather than polling all of them
+    - task_queue: IDs of tasks that are ready to be polled, shared with the
+      wakers (hence the Arc) so they can push from an interrupt handler
+    - waker_cache: reuse each task's waker across polls instead of creating
+      a new one every time
+    - spawn_queue: tasks handed over through a Spawner, moved into `tasks`
+      at the start of every step()
+*/
+pub struct Exec {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+    spawn_queue: Arc<ArrayQueue<Task>>,
+}
+
+impl Exec {
+    pub fn new() -> Self {
+        Exec {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(100)),
+            waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(ArrayQueue::new(100)),
+        }
+    }
+
+    // a handle that can spawn onto this executor from inside its own tasks,
+    // which can't get at the Exec itself while run() has it borrowed
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        // TaskId::new() never hands out the same ID twice so this can't
+        // happen unless the ID generator itself is broken
+        let prev = self.tasks.insert(task.id, task);
+        debug_assert!(prev.is_none(), "task with same ID already in tasks");
+        self.task_queue
+            .push(task_id)
+            .expect("ERROR: task queue full");
+    }
+
+    /*
+        poll every task that was ready when this was called, returns how
+        many were polled
+
+        - only the IDs already in the queue are taken, a task that wakes
+          itself while being polled gets picked up by the next step() rather
+          than keeping this one going forever
+        - lets tests drive the executor one batch at a time instead of
+          going into run()
+    */
+    pub fn step(&mut self) -> usize {
+        while let Some(task) = self.spawn_queue.pop() {
+            self.spawn(task);
+        }
+
+        // destructure self to avoid borrow checker errors with the closure
+        // borrowing all of self
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+            ..
+        } = self;
+
+        let mut polled = 0;
+        for _ in 0..task_queue.len() {
+            let task_id = match task_queue.pop() {
+                Some(task_id) => task_id,
+                None => break,
+            };
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task already finished
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            polled += 1;
+            // for TaskLocal::with()
+            super::local::set_current_task(Some(task_id));
+            let poll = task.poll(&mut context);
+            super::local::set_current_task(None);
+            match poll {
+                Poll::Ready(()) => {
+                    // task done so remove it and its cached waker
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+        polled
+    }
+
+    /*
+        drop a task without running it to completion, returns false if it
+        already finished (or was never spawned here)
+
+        - if its ID is still sitting in task_queue it's skipped like any
+          other finished task
+    */
+    pub fn cancel(&mut self, task_id: TaskId) -> bool {
+        self.waker_cache.remove(&task_id);
+        self.tasks.remove(&task_id).is_some()
+    }
+
+    // whether any spawned task hasn't finished yet
+    pub fn has_pending(&self) -> bool {
+        !self.tasks.is_empty() || !self.spawn_queue.is_empty()
+    }
+
+    /*
+        step() until a step polls nothing, i.e. every task has either
+        finished or is parked waiting on a wake up that hasn't come
+
+        - parked tasks stay in place, a later wake up puts them back in the
+          queue for the next step() or run_until_idle()
+        - never halts, so tests can drive the executor to a known state and
+          check it instead of handing over to run()
+        - doesn't return while a task keeps waking itself
+    */
+    pub fn run_until_idle(&mut self) {
+        while self.step() > 0 {}
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.step();
+            crate::stack_guard::check_pending();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // disable interrupts before checking the queue so a wake up from an
+        // interrupt handler can't slip in between the check and the `hlt`
+        interrupts::disable();
+        if self.task_queue.is_empty() && self.spawn_queue.is_empty() {
+            // `sti; hlt` atomically so we don't miss the next interrupt
+            enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Exec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+    Spawner
+
+    - cheap to clone and move into a task, every clone feeds the same Exec
+    - the task only starts once the executor's next step() picks it up
+*/
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, task: Task) {
+        if self.spawn_queue.push(task).is_err() {
+            panic!("ERROR: spawn queue full");
+        }
+    }
+}
+
+// waking a task just pushes its ID back onto the executor's task_queue
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue
+            .push(self.task_id)
+            .expect("ERROR: task queue full");
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,467 @@
+use crate::{log_warn, print, println, serial_print};
+use alloc::string::String;
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1,
+};
+
+/*
+    Scancode queue
+
+    - filled by the keyboard interrupt handler and drained by the async
+      print_keypresses task so the handler does as little work as possible
+    - OnceCell rather than lazy_static since the queue must be allocated on
+      the heap and that can't happen inside the interrupt handler
+    - ArrayQueue is lock-free so pushing from the handler can't deadlock
+*/
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+// scancodes the queue holds before add_scancode() starts dropping them
+pub const SCANCODE_QUEUE_CAPACITY: usize = 100;
+// wakes the task waiting on the ScancodeStream when a new scancode arrives
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// scancodes add_scancode() had nowhere to put
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// which scancode add_scancode() gives up on when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullPolicy {
+    // keep what's queued and drop the incoming scancode (default)
+    DropNewest,
+    // make room by dropping the oldest queued one, so the most recent
+    // keystrokes survive a stall
+    DropOldest,
+}
+
+static DROP_OLDEST: AtomicBool = AtomicBool::new(false);
+
+pub fn set_full_policy(policy: FullPolicy) {
+    DROP_OLDEST.store(policy == FullPolicy::DropOldest, Ordering::Relaxed);
+}
+
+pub fn full_policy() -> FullPolicy {
+    if DROP_OLDEST.load(Ordering::Relaxed) {
+        FullPolicy::DropOldest
+    } else {
+        FullPolicy::DropNewest
+    }
+}
+
+// called by the keyboard interrupt handler
+// must not block or allocate, which also rules out printing: println! takes
+// the WRITER lock and the code we interrupted may be holding it, so drops are
+// only counted here and print_keypresses reports them
+pub fn add_scancode(scancode: u8) {
+    crate::debug_assert_in_interrupt!();
+    let queue = match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => queue,
+        // not set up yet
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    match full_policy() {
+        FullPolicy::DropNewest => {
+            if queue.push(scancode).is_err() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        // force_push() swaps out the oldest one in a single lock-free step,
+        // the new scancode always makes it in
+        FullPolicy::DropOldest => {
+            if queue.force_push(scancode).is_some() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    WAKER.wake();
+}
+
+// total number of scancodes dropped since boot
+pub fn dropped_scancodes() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// throw away everything queued, returns how many there were
+pub fn drain_scancodes() -> usize {
+    let mut drained = 0;
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        while queue.pop().is_some() {
+            drained += 1;
+        }
+    }
+    drained
+}
+
+pub struct ScancodeStream {
+    _private: (), // prevents construction outside of new()
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_CAPACITY))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = match SCANCODE_QUEUE.try_get() {
+            Ok(queue) => queue,
+            // polled before new() set up the queue, nothing can have been
+            // queued yet so just wait like for an empty queue
+            Err(_) => {
+                WAKER.register(cx.waker());
+                return Poll::Pending;
+            }
+        };
+
+        // fast path so we don't register a waker when we don't have to
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // register the waker *before* checking the queue again so a scancode
+        // pushed in between the two checks can't be missed
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/*
+    Auto-repeat filter
+
+    - a held key makes the keyboard send its make code over and over, each
+      one decodes to another key press
+    - with the filter on, a repeat of the key that's held down only gets
+      through if at least `min_interval_ticks` timer ticks have passed since
+      the last one of it that did, u64::MAX drops every repeat
+    - a different key or a release then press again always gets through
+    - off by default, the setting is shared by every KeyStream
+*/
+static REPEAT_FILTER: AtomicBool = AtomicBool::new(false);
+static REPEAT_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_repeat_filter(enabled: bool, min_interval_ticks: u64) {
+    REPEAT_INTERVAL.store(min_interval_ticks, Ordering::Relaxed);
+    REPEAT_FILTER.store(enabled, Ordering::Relaxed);
+}
+
+// the current setting, None while it's off
+pub fn repeat_filter() -> Option<u64> {
+    REPEAT_FILTER
+        .load(Ordering::Relaxed)
+        .then(|| REPEAT_INTERVAL.load(Ordering::Relaxed))
+}
+
+// translates the raw scancodes into key presses, from the keyboard
+// interrupt by default or any other scancode stream (e.g. canned input)
+pub struct KeyStream<S = ScancodeStream> {
+    scancodes: S,
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    // for the repeat filter: the key held down, when it last got through,
+    // and where the time comes from
+    held: Option<KeyCode>,
+    last_passed: u64,
+    clock: fn() -> u64,
+}
+
+impl KeyStream {
+    // can only be created once since it owns the ScancodeStream
+    pub fn new() -> Self {
+        KeyStream::with_scancodes(ScancodeStream::new())
+    }
+}
+
+impl<S: Stream<Item = u8> + Unpin> KeyStream<S> {
+    pub fn with_scancodes(scancodes: S) -> Self {
+        KeyStream::with_scancodes_and_clock(scancodes, crate::interrupts::ticks)
+    }
+
+    // same as with_scancodes() but the repeat filter reads ticks from
+    // `clock`, e.g. a counter a test advances by hand
+    pub fn with_scancodes_and_clock(scancodes: S, clock: fn() -> u64) -> Self {
+        KeyStream {
+            scancodes,
+            keyboard: Keyboard::new(
+                ScancodeSet1::new(),
+                layouts::Us104Key,
+                HandleControl::Ignore,
+            ),
+            held: None,
+            last_passed: 0,
+            clock,
+        }
+    }
+
+    // whether `event` is an auto-repeat the filter drops
+    fn is_filtered_repeat(&mut self, event: &KeyEvent) -> bool {
+        match event.state {
+            KeyState::Down => {
+                let now = (self.clock)();
+                if let Some(interval) = repeat_filter() {
+                    if self.held == Some(event.code)
+                        && now.saturating_sub(self.last_passed) < interval
+                    {
+                        return true;
+                    }
+                }
+                self.held = Some(event.code);
+                self.last_passed = now;
+                false
+            }
+            KeyState::Up => {
+                if self.held == Some(event.code) {
+                    self.held = None;
+                }
+                false
+            }
+            KeyState::SingleShot => false,
+        }
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Stream<Item = u8> + Unpin> Stream for KeyStream<S> {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        let this = self.get_mut();
+        // a key press can take several scancodes (and key releases don't
+        // produce a key at all) so keep going until we get one or run dry
+        loop {
+            match this.scancodes.poll_next_unpin(cx) {
+                Poll::Ready(Some(scancode)) => {
+                    if let Ok(Some(key_event)) = this.keyboard.add_byte(scancode) {
+                        if this.is_filtered_repeat(&key_event) {
+                            continue;
+                        }
+                        if let Some(key) = this.keyboard.process_keyevent(key_event) {
+                            return Poll::Ready(Some(key));
+                        }
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/*
+    cursor movement and editing keys, for line editors and scrollback
+
+    - these are the 0xe0 prefixed scancodes, pc_keyboard already decodes
+      the prefix and hands them out as DecodedKey::RawKey
+    - except Delete, the US layout turns that into Unicode DEL (0x7f)
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NavKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Delete,
+}
+
+impl NavKey {
+    pub fn from_key(key: DecodedKey) -> Option<NavKey> {
+        let nav = match key {
+            DecodedKey::RawKey(KeyCode::ArrowUp) => NavKey::Up,
+            DecodedKey::RawKey(KeyCode::ArrowDown) => NavKey::Down,
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => NavKey::Left,
+            DecodedKey::RawKey(KeyCode::ArrowRight) => NavKey::Right,
+            DecodedKey::RawKey(KeyCode::Home) => NavKey::Home,
+            DecodedKey::RawKey(KeyCode::End) => NavKey::End,
+            DecodedKey::RawKey(KeyCode::PageUp) => NavKey::PageUp,
+            DecodedKey::RawKey(KeyCode::PageDown) => NavKey::PageDown,
+            DecodedKey::RawKey(KeyCode::Delete) | DecodedKey::Unicode('\u{7f}') => NavKey::Delete,
+            _ => return None,
+        };
+        Some(nav)
+    }
+}
+
+// read, translate, and display each scancode as it comes in
+pub async fn print_keypresses() {
+    let mut keys = KeyStream::new();
+    let mut reported = dropped_scancodes();
+
+    while let Some(key) = keys.next().await {
+        let dropped = dropped_scancodes();
+        if dropped != reported {
+            log_warn!(
+                "keyboard queue full; dropped {} scancodes",
+                dropped - reported
+            );
+            reported = dropped;
+        }
+        if let Some(nav) = NavKey::from_key(key) {
+            move_cursor(nav);
+            continue;
+        }
+        match key {
+            DecodedKey::Unicode(character) => print!("{character}"),
+            DecodedKey::RawKey(key) => serial_print!("{:?}", key), // redirect output here to serial so it doesn't crowd the screen
+        }
+    }
+}
+
+// Left/Right/Home/End move along the current line, the rest have nothing
+// to act on yet
+fn move_cursor(nav: NavKey) {
+    crate::vga_buf::move_column(|col, width| match nav {
+        NavKey::Left => Some(col.saturating_sub(1).min(width.checked_sub(1)?)),
+        NavKey::Right => Some((col + 1).min(width.checked_sub(1)?)),
+        NavKey::Home => Some(0),
+        NavKey::End => width.checked_sub(1),
+        _ => None,
+    });
+}
+
+pub(crate) const BACKSPACE: char = '\u{8}';
+
+/*
+    anything read_line() can take its input from
+
+    - a stream of already decoded keys, so the line editing doesn't care
+      whether they came from the PS/2 keyboard (KeyStream) or a terminal
+      on COM1 (serial::SerialKeys)
+*/
+pub trait InputSource: Stream<Item = DecodedKey> + Unpin {}
+
+impl<S: Stream<Item = u8> + Unpin> InputSource for KeyStream<S> {}
+
+/*
+    read a line of input, echoing it to the screen as it's typed
+
+    - returns once Enter is pressed (the newline isn't included)
+    - backspace erases the last character both from the line and the
+      screen, on an empty line it does nothing so it can't eat a prompt
+    - lines longer than the screen just wrap onto the next row
+*/
+pub async fn read_line<S: InputSource>(keys: &mut S) -> String {
+    let mut line = String::new();
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::Unicode('\n') => {
+                println!();
+                break;
+            }
+            DecodedKey::Unicode(BACKSPACE) => {
+                if line.pop().is_some() {
+                    crate::vga_buf::backspace();
+                }
+            }
+            DecodedKey::Unicode(character) => {
+                line.push(character);
+                print!("{character}");
+            }
+            DecodedKey::RawKey(_) => {}
+        }
+    }
+    line
+}
+
+#[test_case]
+fn test_extended_scancode_nav_key() {
+    use futures_util::{stream, FutureExt};
+
+    // ArrowUp press then release, both behind the 0xe0 prefix
+    let scancodes = stream::iter([0xe0, 0x48, 0xe0, 0xc8].iter().copied());
+    let mut keys = KeyStream::with_scancodes(scancodes);
+    let key = keys
+        .next()
+        .now_or_never()
+        .flatten()
+        .expect("no key decoded");
+    assert_eq!(NavKey::from_key(key), Some(NavKey::Up));
+    // the release doesn't make a key
+    assert_eq!(keys.next().now_or_never(), Some(None));
+    assert_eq!(NavKey::from_key(DecodedKey::Unicode('a')), None);
+}
+
+// test that holding a key only lets a repeat through every few ticks
+#[test_case]
+fn test_repeat_filter() {
+    use futures_util::{stream, FutureExt};
+
+    static MOCK_TICKS: AtomicU64 = AtomicU64::new(0);
+    // one tick passes per key press
+    fn mock_ticks() -> u64 {
+        MOCK_TICKS.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // 'a' pressed and held for 9 repeats, released, then pressed again
+    let mut scancodes = [0x1e; 12];
+    scancodes[10] = 0x9e;
+    let scancodes = stream::iter(scancodes.iter().copied());
+    let mut keys = KeyStream::with_scancodes_and_clock(scancodes, mock_ticks);
+
+    set_repeat_filter(true, 4);
+    let mut passed = 0;
+    while let Some(Some(key)) = keys.next().now_or_never() {
+        assert_eq!(key, DecodedKey::Unicode('a'));
+        passed += 1;
+    }
+    set_repeat_filter(false, 0);
+    // ticks 0, 4 and 8 of the held key, then the fresh press
+    assert_eq!(passed, 4);
+    assert_eq!(repeat_filter(), None);
+}
+
+// test that polling before the queue exists waits instead of panicking,
+// lib tests never call ScancodeStream::new() so the queue is still unset
+#[test_case]
+fn test_poll_uninitialized_queue() {
+    use futures_util::task::noop_waker_ref;
+
+    assert!(SCANCODE_QUEUE.try_get().is_err());
+    let mut stream = ScancodeStream { _private: () };
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+    assert!(WAKER.take().is_some());
+
+    // a scancode that arrives now is dropped, not printed
+    let dropped = dropped_scancodes();
+    crate::interrupts::run_as_handler(|| add_scancode(0x1e));
+    assert_eq!(dropped_scancodes(), dropped + 1);
+}
@@ -0,0 +1,95 @@
+use super::TaskId;
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/*
+    Task-local storage
+
+    - a TaskLocal is declared once with task_local! and every task sees its
+      own copy, created from the initializer the first time that task calls
+      with() and dropped once the task is
+    - the values all live in STORE keyed by (task, TaskLocal), a TaskLocal
+      is a static so its address is a unique key for it
+    - Exec sets CURRENT around every poll, that's how with() knows whose
+      value to hand out, calling it outside a task (e.g. in block_on())
+      panics
+    - needs the heap
+*/
+static STORE: Mutex<BTreeMap<(TaskId, usize), Box<dyn Any + Send>>> = Mutex::new(BTreeMap::new());
+
+// ID of the task being polled right now, NO_TASK between polls
+static CURRENT: AtomicU64 = AtomicU64::new(NO_TASK);
+const NO_TASK: u64 = u64::MAX;
+
+pub fn current_task() -> Option<TaskId> {
+    match CURRENT.load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(TaskId(id)),
+    }
+}
+
+pub(crate) fn set_current_task(task_id: Option<TaskId>) {
+    CURRENT.store(task_id.map_or(NO_TASK, |id| id.0), Ordering::Relaxed);
+}
+
+// drop every value `task_id` has, called when the task itself is dropped
+pub(crate) fn remove_task(task_id: TaskId) {
+    loop {
+        // each value is dropped with the lock released, its Drop might use
+        // a TaskLocal itself
+        let value = {
+            let mut store = STORE.lock();
+            let key = match store.range((task_id, 0)..=(task_id, usize::MAX)).next() {
+                Some((&key, _)) => key,
+                None => return,
+            };
+            store.remove(&key)
+        };
+        drop(value);
+    }
+}
+
+pub struct TaskLocal<T: Send + 'static> {
+    init: fn() -> T,
+}
+
+impl<T: Send + 'static> TaskLocal<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        TaskLocal { init }
+    }
+
+    /*
+        run `f` on the current task's value
+
+        - the value is taken out of STORE while `f` runs so `f` can use
+          other TaskLocals, but using this same one again from inside `f`
+          gets a fresh value that is then overwritten
+    */
+    pub fn with<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> R {
+        let task_id = current_task().expect("TaskLocal::with called outside of a task");
+        let key = (task_id, self as *const Self as usize);
+
+        let value = STORE.lock().remove(&key);
+        let mut value = match value {
+            Some(value) => value,
+            None => Box::new((self.init)()),
+        };
+        let result = f(value
+            .downcast_mut::<T>()
+            .expect("task local stored with the wrong type"));
+        STORE.lock().insert(key, value);
+        result
+    }
+}
+
+// declares a static TaskLocal, e.g. `task_local!(static DEPTH: usize = 0);`
+#[macro_export]
+macro_rules! task_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task::local::TaskLocal<$t> =
+            $crate::task::local::TaskLocal::new(|| $init);
+    };
+}
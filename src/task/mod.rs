@@ -0,0 +1,96 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+pub mod block_on;
+pub mod delay;
+pub mod event;
+pub mod exec;
+pub mod keyboard;
+pub mod local;
+pub mod select;
+pub mod serial;
+pub mod simple_exec;
+
+pub use block_on::block_on;
+pub use delay::delay_ticks;
+pub use event::Event;
+pub use local::TaskLocal;
+// so it can be used as task::task_local! too
+pub use crate::task_local;
+
+/*
+    Task
+
+    - wraps a pinned, heap allocated, dynamically dispatched future
+        - Pin<Box<...>> makes sure the future can't be moved in memory
+          since async blocks can be self-referential
+        - the Output is () since tasks are only run for their side effects
+    - on_drop runs when the task is dropped, i.e. when the executor removes
+      it after it finishes or is cancelled, its task locals go right after
+        - it's an FnOnce taken out of the Option so it can only ever run
+          once, and it runs before the future itself is dropped
+*/
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    on_drop: Option<Box<dyn FnOnce()>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+            on_drop: None,
+        }
+    }
+
+    pub fn new_with_cleanup(
+        future: impl Future<Output = ()> + 'static,
+        cleanup: impl FnOnce() + 'static,
+    ) -> Task {
+        let mut task = Task::new(future);
+        task.on_drop = Some(Box::new(cleanup));
+        task
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.on_drop.take() {
+            cleanup();
+        }
+        local::remove_task(self.id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /*
+        IDs come from a single global counter that only ever moves forward
+        with fetch_add, so every call returns a value no other call has seen
+
+        - Relaxed ordering is enough since we only need each ID to be unique,
+          not ordered relative to any other memory operation
+        - fetch_add wraps on overflow, so an ID could only repeat after 2^64
+          spawns, at one spawn per nanosecond that is still ~584 years away
+    */
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
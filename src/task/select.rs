@@ -0,0 +1,56 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// which of the two futures passed to select2() finished first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/*
+    wait for whichever of `a` and `b` finishes first
+
+    - both are polled with the same context so the task gets woken by
+      either one
+    - if both are ready on the same poll `a` wins (Left), so the result
+      doesn't depend on timing
+    - the loser is dropped along with the Select2, to wait on two streams
+      pass in `a.next()` and `b.next()`, dropping a `next()` that isn't
+      ready doesn't lose an item
+    - both have to be Unpin, anything that isn't (e.g. an async block) can
+      be wrapped in Box::pin() first
+*/
+pub fn select2<A, B>(a: A, b: B) -> Select2<A, B>
+where
+    A: Future + Unpin,
+    B: Future + Unpin,
+{
+    Select2 { a, b }
+}
+
+pub struct Select2<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> Future for Select2<A, B>
+where
+    A: Future + Unpin,
+    B: Future + Unpin,
+{
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(value) = Pin::new(&mut self.a).poll(cx) {
+            return Poll::Ready(Either::Left(value));
+        }
+        if let Poll::Ready(value) = Pin::new(&mut self.b).poll(cx) {
+            return Poll::Ready(Either::Right(value));
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,162 @@
+use super::keyboard::{InputSource, BACKSPACE};
+use crate::log_warn;
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use pc_keyboard::DecodedKey;
+
+/*
+    Serial input queue
+
+    - same setup as the keyboard's scancode queue: the COM1 interrupt
+      handler pushes each received byte and a SerialStream pops them
+*/
+static SERIAL_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// bytes add_byte() had no room for
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// called by the serial interrupt handler
+// must not block or allocate, and can't print for the same reason as
+// keyboard::add_scancode(), drops are counted and SerialStream reports them
+pub fn add_byte(byte: u8) {
+    crate::debug_assert_in_interrupt!();
+    if let Ok(queue) = SERIAL_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            WAKER.wake();
+        }
+    }
+    // nobody has asked for serial input yet so there's nothing to do
+}
+
+// total number of serial input bytes dropped since boot
+pub fn dropped_bytes() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+pub struct SerialStream {
+    // dropped_bytes() as of the last warning, also prevents construction
+    // outside of new()
+    reported: usize,
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        SERIAL_QUEUE
+            .try_init_once(|| ArrayQueue::new(256))
+            .expect("SerialStream::new should only be called once");
+        SerialStream {
+            reported: dropped_bytes(),
+        }
+    }
+}
+
+impl Default for SerialStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SERIAL_QUEUE
+            .try_get()
+            .expect("ERROR: SERIAL_QUEUE uninitialized");
+
+        // running in a task here, so printing is fine
+        let this = self.get_mut();
+        let dropped = dropped_bytes();
+        if dropped != this.reported {
+            log_warn!(
+                "serial input queue full; dropped {} bytes",
+                dropped - this.reported
+            );
+            this.reported = dropped;
+        }
+
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/*
+    turns the raw bytes from a serial terminal into the keys read_line()
+    expects, so it behaves the same as with the keyboard
+
+    - DEL (0x7f, what most terminals send for backspace) and BS (0x08) are
+      both a backspace
+    - CR, LF and CRLF each end the line once, the LF of a CRLF is dropped
+    - other printable ASCII and tab pass through, any other control or
+      non-ASCII byte is dropped
+*/
+pub struct SerialKeys<S = SerialStream> {
+    bytes: S,
+    // last byte was a CR, so an LF right after it isn't another Enter
+    after_cr: bool,
+}
+
+impl<S: Stream<Item = u8> + Unpin> SerialKeys<S> {
+    pub fn new(bytes: S) -> Self {
+        SerialKeys {
+            bytes,
+            after_cr: false,
+        }
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<DecodedKey> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        let key = match byte {
+            0x7f | 0x08 => BACKSPACE,
+            b'\r' => '\n',
+            b'\n' if after_cr => return None,
+            b'\n' | b'\t' | 0x20..=0x7e => byte as char,
+            _ => return None,
+        };
+        Some(DecodedKey::Unicode(key))
+    }
+}
+
+impl<S: Stream<Item = u8> + Unpin> Stream for SerialKeys<S> {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        let this = self.get_mut();
+        // like KeyStream, skip bytes that don't make a key
+        loop {
+            match this.bytes.poll_next_unpin(cx) {
+                Poll::Ready(Some(byte)) => {
+                    if let Some(key) = this.decode(byte) {
+                        return Poll::Ready(Some(key));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: Stream<Item = u8> + Unpin> InputSource for SerialKeys<S> {}
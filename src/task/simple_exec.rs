@@ -0,0 +1,141 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+/*
+    StaticTask
+
+    - a future stored inline instead of in a Pin<Box<...>>, so making one
+      never touches the heap
+    - it has to be pinned before it can be polled, for a fixed set of tasks
+      that's usually core::pin::pin!() on the stack of whatever calls
+      SimpleExec::run()
+    - remembers when it finished so the future is never polled again
+*/
+pub struct StaticTask<F> {
+    future: F,
+    done: bool,
+}
+
+impl<F: Future<Output = ()>> StaticTask<F> {
+    pub const fn new(future: F) -> Self {
+        StaticTask {
+            future,
+            done: false,
+        }
+    }
+}
+
+// what SimpleExec needs from a task, lets it hold StaticTasks of different
+// future types in one array as trait objects
+pub trait StaticRun {
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()>;
+    fn is_done(&self) -> bool;
+}
+
+impl<F: Future<Output = ()>> StaticRun for StaticTask<F> {
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        // the future is never moved out of self, `done` isn't structurally
+        // pinned so it can be set through a plain &mut
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.done {
+            return Poll::Ready(());
+        }
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let poll = future.poll(context);
+        this.done = poll.is_ready();
+        poll
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/*
+    SimpleExec
+
+    - runs a fixed list of tasks known up front with no heap allocation at
+      all: the tasks are borrowed, not owned, and the waker is static
+    - every step() polls every unfinished task, no per-task ready queue, so
+      it's only worth it for a handful of tasks
+    - the waker only sets WOKEN (like block_on()), run() sleeps until the
+      next interrupt if nothing was woken during the last step
+        - WOKEN is shared by every SimpleExec, a wake up meant for another
+          one just causes an extra step
+*/
+pub struct SimpleExec<'a, const N: usize> {
+    tasks: [Pin<&'a mut dyn StaticRun>; N],
+}
+
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+impl<'a, const N: usize> SimpleExec<'a, N> {
+    pub fn new(tasks: [Pin<&'a mut dyn StaticRun>; N]) -> Self {
+        SimpleExec { tasks }
+    }
+
+    // poll every unfinished task once, returns how many were polled
+    pub fn step(&mut self) -> usize {
+        let waker = static_waker();
+        let mut context = Context::from_waker(&waker);
+
+        let mut polled = 0;
+        for task in self.tasks.iter_mut().filter(|task| !task.is_done()) {
+            let _ = task.as_mut().poll(&mut context);
+            polled += 1;
+        }
+        polled
+    }
+
+    // whether any task hasn't finished yet
+    pub fn has_pending(&self) -> bool {
+        self.tasks.iter().any(|task| !task.is_done())
+    }
+
+    // step until every task has finished
+    pub fn run(&mut self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        loop {
+            WOKEN.store(false, Ordering::SeqCst);
+            self.step();
+            if !self.has_pending() {
+                return;
+            }
+            // same as Exec::sleep_if_idle(), a wake up from an interrupt
+            // handler can't slip in between the check and the `hlt`
+            interrupts::disable();
+            if WOKEN.load(Ordering::SeqCst) {
+                interrupts::enable();
+            } else {
+                enable_and_hlt();
+            }
+        }
+    }
+}
+
+// a waker with no data, every clone is the same static RawWaker
+fn static_waker() -> Waker {
+    unsafe { Waker::from_raw(raw_waker()) }
+}
+
+fn raw_waker() -> RawWaker {
+    RawWaker::new(ptr::null(), &VTABLE)
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop_waker);
+
+fn clone(_: *const ()) -> RawWaker {
+    raw_waker()
+}
+
+fn wake(_: *const ()) {
+    WOKEN.store(true, Ordering::SeqCst);
+}
+
+fn drop_waker(_: *const ()) {}
@@ -0,0 +1,208 @@
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/*
+    Time on top of the timer tick counter
+
+    - Instant is a tick count (interrupts::ticks()), Duration a number of
+      nanoseconds, both plain u64s so there's no floating point anywhere
+    - ticks are converted with the PIT's input clock and divisor, a tick is
+      divisor / PIT_HZ seconds
+        - nothing reprograms the PIT yet so the divisor is the BIOS default
+          of 65536 (~18.2 Hz, ~54.9 ms per tick), whatever changes it has to
+          call set_pit_divisor() too
+    - the resolution is one tick, an Instant + Duration rounds up to the
+      next whole tick so waiting for it never cuts the Duration short
+*/
+
+// the PIT's input clock
+pub const PIT_HZ: u64 = 1_193_182;
+const DEFAULT_PIT_DIVISOR: u64 = 65536;
+
+static PIT_DIVISOR: AtomicU64 = AtomicU64::new(DEFAULT_PIT_DIVISOR);
+
+pub fn set_pit_divisor(divisor: u64) {
+    PIT_DIVISOR.store(divisor, Ordering::Relaxed);
+}
+
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Duration {
+    nanos: u64,
+}
+
+impl Duration {
+    pub const ZERO: Duration = Duration { nanos: 0 };
+
+    pub const fn from_nanos(nanos: u64) -> Duration {
+        Duration { nanos }
+    }
+
+    // saturates at u64::MAX nanoseconds (~584 years)
+    pub const fn from_millis(millis: u64) -> Duration {
+        Duration {
+            nanos: millis.saturating_mul(NANOS_PER_MILLI),
+        }
+    }
+
+    pub const fn from_secs(secs: u64) -> Duration {
+        Duration {
+            nanos: secs.saturating_mul(NANOS_PER_SEC),
+        }
+    }
+
+    pub const fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    // rounded down
+    pub const fn as_millis(&self) -> u64 {
+        self.nanos / NANOS_PER_MILLI
+    }
+
+    pub const fn as_secs(&self) -> u64 {
+        self.nanos / NANOS_PER_SEC
+    }
+
+    pub fn checked_sub(self, rhs: Duration) -> Option<Duration> {
+        self.nanos.checked_sub(rhs.nanos).map(Duration::from_nanos)
+    }
+
+    pub fn saturating_sub(self, rhs: Duration) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(rhs.nanos))
+    }
+
+    // how long `ticks` timer ticks are at the current PIT divisor
+    pub fn from_ticks(ticks: u64) -> Duration {
+        let nanos = ticks as u128 * divisor() as u128 * NANOS_PER_SEC as u128 / PIT_HZ as u128;
+        Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+
+    // number of whole ticks that cover this duration, rounded up
+    pub fn as_ticks(&self) -> u64 {
+        let per_tick = divisor() as u128 * NANOS_PER_SEC as u128;
+        let ticks = (self.nanos as u128 * PIT_HZ as u128 + per_tick - 1) / per_tick;
+        ticks.min(u64::MAX as u128) as u64
+    }
+}
+
+fn divisor() -> u64 {
+    // a divisor of 0 means 65536 to the PIT itself
+    match PIT_DIVISOR.load(Ordering::Relaxed) {
+        0 => DEFAULT_PIT_DIVISOR,
+        divisor => divisor,
+    }
+}
+
+// saturating, a Duration that overflows u64 nanoseconds is centuries long
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_add(rhs.nanos))
+    }
+}
+
+// panics if rhs is longer, like core::time::Duration
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting durations")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant::from_ticks(crate::interrupts::ticks())
+    }
+
+    pub const fn from_ticks(ticks: u64) -> Instant {
+        Instant { ticks }
+    }
+
+    pub const fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // zero if `earlier` is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_ticks(self.ticks.saturating_sub(earlier.ticks))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant::from_ticks(self.ticks.saturating_add(rhs.as_ticks()))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[test_case]
+fn test_duration_arithmetic() {
+    let a = Duration::from_millis(1500);
+    assert_eq!(a.as_secs(), 1);
+    assert_eq!(a.as_millis(), 1500);
+    assert_eq!((a + Duration::from_millis(500)).as_secs(), 2);
+    assert_eq!((a - Duration::from_secs(1)).as_millis(), 500);
+    assert_eq!(Duration::from_millis(1).checked_sub(a), None);
+    assert_eq!(Duration::from_millis(1).saturating_sub(a), Duration::ZERO);
+    assert_eq!(Duration::from_millis(u64::MAX).as_nanos(), u64::MAX);
+    assert_eq!(
+        Duration::from_nanos(u64::MAX) + Duration::from_nanos(1),
+        Duration::from_nanos(u64::MAX)
+    );
+}
+
+// at the default divisor a tick is 65536 / 1193182 s = 54925439.x ns
+#[test_case]
+fn test_tick_conversion() {
+    assert_eq!(Duration::from_ticks(1).as_nanos(), 54_925_439);
+    assert_eq!(Duration::from_ticks(1000).as_millis(), 54_925);
+    assert_eq!(Duration::ZERO.as_ticks(), 0);
+    assert_eq!(Duration::from_ticks(3).as_ticks(), 3);
+    // anything past a whole tick needs another one
+    assert_eq!(Duration::from_millis(55).as_ticks(), 2);
+    assert_eq!(
+        Instant::from_ticks(10) + Duration::from_secs(1),
+        Instant::from_ticks(29)
+    );
+    assert_eq!(
+        Instant::from_ticks(7) - Instant::from_ticks(10),
+        Duration::ZERO
+    );
+}
+
+// measure across 3 real timer ticks
+#[test_case]
+fn test_elapsed_over_ticks() {
+    let start = Instant::now();
+    while crate::interrupts::ticks() < start.ticks() + 3 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_ticks(3));
+    assert!(elapsed < Duration::from_ticks(5));
+    assert!(elapsed.as_millis() >= 164);
+}
@@ -0,0 +1,302 @@
+/*
+    Address alignment helpers
+
+    - `align` must always be a power of 2, that way align - 1 is a mask of
+      all the low bits that have to be zero in an aligned address
+        - e.g. align = 8 -> mask = 0b111
+    - debug builds assert this, release builds just return garbage
+*/
+
+// round `addr` up to the next multiple of `align`
+// will overflow if `addr` is within `align` of usize::MAX, use
+// checked_align_up() when that is possible
+pub fn align_up(addr: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two(), "align must be a power of 2");
+    (addr + align - 1) & !(align - 1)
+}
+
+// same as align_up() but returns None rather than overflowing
+pub fn checked_align_up(addr: usize, align: usize) -> Option<usize> {
+    debug_assert!(align.is_power_of_two(), "align must be a power of 2");
+    addr.checked_add(align - 1).map(|addr| addr & !(align - 1))
+}
+
+// round `addr` down to the previous multiple of `align`, can't overflow
+pub fn align_down(addr: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two(), "align must be a power of 2");
+    addr & !(align - 1)
+}
+
+/*
+    Number formatting without core::fmt
+
+    - for paths that can't allocate and want as little code as possible
+      (very early boot, out of memory), everything else should just use
+      write!/println!
+    - the digits are written to the end of `buf` and the returned &str
+      borrows that part of it
+    - `buf` has to fit the number: DEC_LEN bytes always do for decimal,
+      HEX_LEN for hex (lowercase, no 0x prefix), too short a buffer panics
+*/
+
+pub const DEC_LEN: usize = 20;
+pub const HEX_LEN: usize = 16;
+
+pub fn u64_to_dec(n: u64, buf: &mut [u8]) -> &str {
+    u64_to_base(n, 10, buf)
+}
+
+pub fn u64_to_hex(n: u64, buf: &mut [u8]) -> &str {
+    u64_to_base(n, 16, buf)
+}
+
+fn u64_to_base(mut n: u64, base: u64, buf: &mut [u8]) -> &str {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = DIGITS[(n % base) as usize];
+        n /= base;
+        if n == 0 {
+            break;
+        }
+    }
+    // only ever ASCII digits
+    unsafe { core::str::from_utf8_unchecked(&buf[start..]) }
+}
+
+/*
+    Bounded polling for device handshakes
+
+    - poll_until() checks `ready` up to `max_spins` times and gives up with
+      Timeout rather than hanging the boot on a device that never answers
+    - a `pause` between checks keeps the spin cheap, poll_until_with() takes
+      any other wait instead, e.g. io_wait() for old ISA hardware that needs
+      a moment between reads
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+pub fn poll_until(ready: impl FnMut() -> bool, max_spins: usize) -> Result<(), Timeout> {
+    poll_until_with(ready, max_spins, core::hint::spin_loop)
+}
+
+pub fn poll_until_with(
+    mut ready: impl FnMut() -> bool,
+    max_spins: usize,
+    mut wait: impl FnMut(),
+) -> Result<(), Timeout> {
+    for _ in 0..max_spins {
+        if ready() {
+            return Ok(());
+        }
+        wait();
+    }
+    Err(Timeout)
+}
+
+// roughly 1µs, a write to the POST diagnostic port nothing listens on
+pub fn io_wait() {
+    use x86_64::instructions::port::Port;
+
+    let mut port: Port<u8> = Port::new(0x80);
+    unsafe { port.write(0) };
+}
+
+/*
+    Checksums for catching corrupted data (e.g. blocks read back from disk)
+
+    - crc32(): the usual CRC-32 (IEEE, as in zip/ethernet), table driven
+      with the table built at compile time
+    - fletcher16(): cheaper but weaker, fine for small in-memory records
+    - neither allocates, neither is any good against deliberate tampering
+*/
+
+// reversed 0x04c11db7, the CRC is computed least significant bit first
+const CRC32_POLY: u32 = 0xedb8_8320;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize]
+    })
+}
+
+// the two running sums mod 255, the second one in the high byte
+pub fn fletcher16(data: &[u8]) -> u16 {
+    let (sum1, sum2) = data.iter().fold((0u16, 0u16), |(sum1, sum2), &byte| {
+        let sum1 = (sum1 + byte as u16) % 255;
+        (sum1, (sum2 + sum1) % 255)
+    });
+    (sum2 << 8) | sum1
+}
+
+// formats into a fixed size buffer, lib tests have no heap for to_string()
+#[cfg(test)]
+pub(crate) struct StackBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+#[cfg(test)]
+impl<const N: usize> StackBuf<N> {
+    pub(crate) fn new() -> Self {
+        StackBuf {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+impl<const N: usize> core::fmt::Write for StackBuf<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_align_to_one() {
+    assert_eq!(align_up(0x1234, 1), 0x1234);
+    assert_eq!(align_down(0x1234, 1), 0x1234);
+}
+
+#[test_case]
+fn test_align_large_power_of_two() {
+    let align = 1 << 21; // 2 MiB
+    assert_eq!(align_up(1, align), align);
+    assert_eq!(align_up(align + 1, align), 2 * align);
+    assert_eq!(align_down(2 * align - 1, align), align);
+    assert_eq!(align_down(align - 1, align), 0);
+}
+
+#[test_case]
+fn test_align_already_aligned() {
+    assert_eq!(align_up(0x4000, 0x1000), 0x4000);
+    assert_eq!(align_down(0x4000, 0x1000), 0x4000);
+    assert_eq!(align_up(0, 8), 0);
+}
+
+#[test_case]
+fn test_checked_align_up_overflow() {
+    assert_eq!(checked_align_up(usize::MAX, 8), None);
+    assert_eq!(checked_align_up(usize::MAX - 6, 8), None);
+    assert_eq!(checked_align_up(usize::MAX - 8, 8), Some(usize::MAX - 7));
+    assert_eq!(checked_align_up(usize::MAX - 7, 8), Some(usize::MAX - 7));
+    assert_eq!(checked_align_up(0x11, 8), Some(0x18));
+}
+
+#[test_case]
+fn test_u64_to_dec() {
+    let mut buf = [0; DEC_LEN];
+    assert_eq!(u64_to_dec(0, &mut buf), "0");
+    assert_eq!(u64_to_dec(7, &mut buf), "7");
+    assert_eq!(u64_to_dec(4096, &mut buf), "4096");
+    assert_eq!(u64_to_dec(1_000_000, &mut buf), "1000000");
+    assert_eq!(u64_to_dec(u64::MAX, &mut buf), "18446744073709551615");
+}
+
+#[test_case]
+fn test_u64_to_hex() {
+    let mut buf = [0; HEX_LEN];
+    assert_eq!(u64_to_hex(0, &mut buf), "0");
+    assert_eq!(u64_to_hex(0xa, &mut buf), "a");
+    assert_eq!(u64_to_hex(0xdead_beef, &mut buf), "deadbeef");
+    assert_eq!(u64_to_hex(0x1000, &mut buf), "1000");
+    assert_eq!(u64_to_hex(u64::MAX, &mut buf), "ffffffffffffffff");
+}
+
+#[test_case]
+fn test_u64_to_str_larger_buffer() {
+    // only the tail of the buffer is used
+    let mut buf = [b'x'; 32];
+    assert_eq!(u64_to_dec(42, &mut buf), "42");
+    assert_eq!(buf[29], b'x');
+}
+
+#[test_case]
+fn test_poll_until_times_out() {
+    let mut checks = 0;
+    let result = poll_until(
+        || {
+            checks += 1;
+            false
+        },
+        1000,
+    );
+    assert_eq!(result, Err(Timeout));
+    assert_eq!(checks, 1000);
+}
+
+#[test_case]
+fn test_poll_until_stops_when_ready() {
+    let mut checks = 0;
+    let mut waits = 0;
+    let result = poll_until_with(
+        || {
+            checks += 1;
+            checks == 3
+        },
+        1000,
+        || waits += 1,
+    );
+    assert_eq!(result, Ok(()));
+    assert_eq!((checks, waits), (3, 2));
+}
+
+#[test_case]
+fn test_crc32_vectors() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(
+        crc32(b"The quick brown fox jumps over the lazy dog"),
+        0x414f_a339
+    );
+    // a single flipped bit changes it
+    assert_ne!(crc32(b"123456788"), 0xcbf4_3926);
+}
+
+#[test_case]
+fn test_fletcher16_vectors() {
+    assert_eq!(fletcher16(b""), 0);
+    assert_eq!(fletcher16(b"abcde"), 0xc8f0);
+    assert_eq!(fletcher16(b"abcdef"), 0x2057);
+    assert_eq!(fletcher16(b"abcdefgh"), 0x0627);
+}
@@ -1,7 +1,16 @@
+use crate::mmio::{PortRegister, RegisterAccess};
+use alloc::string::String;
 use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
 
 #[allow(dead_code)]
 // derive traits to enable copy semantics and make it printable + comparable
@@ -33,33 +42,248 @@ pub enum Color {
    which is only available for structs with single non-zero member
 */
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
+/*
+    Palette of common combinations, const so they can be used anywhere a
+    compile time color is needed (statics, themes, log levels)
+*/
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const DEFAULT: ColorCode = ColorCode::new(Color::White, Color::Black);
+    pub const ERROR: ColorCode = ColorCode::new(Color::White, Color::Red);
+    pub const WARNING: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+    pub const SUCCESS: ColorCode = ColorCode::new(Color::Green, Color::Black);
+    pub const INFO: ColorCode = ColorCode::new(Color::LightCyan, Color::Black);
+}
+
+impl ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /*
+        set or clear bit 7 of the attribute, what it does depends on the
+        attribute controller (see Writer::set_blink_enabled()):
+        - blink enabled: the character blinks, background is bits 4-6 only
+          so just the first 8 colors
+        - blink disabled: it's the top bit of the background, so bit 7 +
+          Color::Black is DarkGray, Blue is LightBlue, ...
+    */
+    pub const fn with_blink(self, blink: bool) -> ColorCode {
+        ColorCode((self.0 & 0x7f) | (blink as u8) << 7)
+    }
+
+    pub fn foreground(self) -> Color {
+        Color::from_nibble(self.0 & 0x0f)
+    }
+
+    // all 4 high bits, i.e. bit 7 is read as the bright background bit even
+    // if the attribute controller has it set to blink
+    pub fn background(self) -> Color {
+        Color::from_nibble(self.0 >> 4)
+    }
+
+    // keep the foreground (low nibble), swap in a new background
+    const fn with_background(self, background: Color) -> ColorCode {
+        ColorCode((background as u8) << 4 | (self.0 & 0x0f))
+    }
+}
+
+impl Color {
+    fn from_nibble(nibble: u8) -> Color {
+        use Color::*;
+        const COLORS: [Color; 16] = [
+            Black, Blue, Green, Cyan, Red, Magenta, Brown, LightGray, DarkGray, LightBlue,
+            LightGreen, LightCyan, LightRed, Pink, Yellow, White,
+        ];
+        COLORS[(nibble & 0x0f) as usize]
+    }
+
+    // lowercase name with no separators, e.g. "lightgray"
+    pub fn from_name(name: &str) -> Option<Color> {
+        use Color::*;
+        let color = match name {
+            "black" => Black,
+            "blue" => Blue,
+            "green" => Green,
+            "cyan" => Cyan,
+            "red" => Red,
+            "magenta" => Magenta,
+            "brown" => Brown,
+            "lightgray" => LightGray,
+            "darkgray" => DarkGray,
+            "lightblue" => LightBlue,
+            "lightgreen" => LightGreen,
+            "lightcyan" => LightCyan,
+            "lightred" => LightRed,
+            "pink" => Pink,
+            "yellow" => Yellow,
+            "white" => White,
+            _ => return None,
+        };
+        Some(color)
+    }
+}
+
+/*
+    Default colors for everything the Writer prints
+
+    - set from the `vga` boot argument as `vga=<fg>/<bg>` (color names as in
+      Color::from_name), anything else (e.g. a mode like 80x50) is ignored
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub default_fg: Color,
+    pub default_bg: Color,
+}
+
+impl Theme {
+    pub const DEFAULT: Theme = Theme {
+        default_fg: Color::White,
+        default_bg: Color::Black,
+    };
+
+    pub fn parse(s: &str) -> Option<Theme> {
+        let (fg, bg) = s.split_once('/')?;
+        Some(Theme {
+            default_fg: Color::from_name(fg)?,
+            default_bg: Color::from_name(bg)?,
+        })
+    }
+
+    // the theme asked for on the command line, or DEFAULT
+    pub fn from_boot_args() -> Theme {
+        crate::boot_args::get("vga")
+            .and_then(Theme::parse)
+            .unwrap_or(Theme::DEFAULT)
+    }
+
+    fn color_code(&self) -> ColorCode {
+        ColorCode::new(self.default_fg, self.default_bg)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::DEFAULT
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 // use repr(C) to guarantee struct fields are laid out like C structs and guarantees correct ordering
 // default Rust ordering does not guarantee struct field order
 #[repr(C)]
-struct ScreenChar {
+pub struct ScreenChar {
     ascii_character: u8,
     color_code: ColorCode,
 }
 
+impl ScreenChar {
+    pub fn new(ascii_character: u8, color_code: ColorCode) -> ScreenChar {
+        ScreenChar {
+            ascii_character,
+            color_code,
+        }
+    }
+
+    // the raw code page 437 byte
+    pub fn ascii(&self) -> u8 {
+        self.ascii_character
+    }
+
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    // the character in this cell, the replacement byte 0xfe that stands in
+    // for anything that isn't printable ASCII comes back as the ■ it shows as
+    pub fn as_char(&self) -> char {
+        match self.ascii_character {
+            0xfe => '\u{25a0}',
+            byte => char::from(byte),
+        }
+    }
+}
+
 // define height and width of 2D VGA buffer
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+// the row math (live_row(), scrolling, the status line) subtracts up to 2
+// rows and 1 column, catch dimensions that would underflow at compile time
+#[allow(clippy::assertions_on_constants)]
+const _: () = assert!(BUFFER_HEIGHT >= 2 && BUFFER_WIDTH >= 1);
+
+// physical address of the text mode buffer
+pub const VGA_PHYS: u64 = 0xb8000;
 
 // create buffer struct to represent VGA buffer in our module
 #[repr(transparent)]
-struct Buffer {
+pub struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+#[derive(Debug)]
+pub enum BufferMapError {
+    // not 2 byte aligned for ScreenChar
+    Misaligned,
+    // something other than `phys` is already mapped where the buffer goes
+    WrongFrame,
+    Map(MapToError<Size4KiB>),
+}
+
+impl Buffer {
+    /*
+        the text buffer at physical address `phys`, reached through the
+        bootloader's physical memory mapping (phys_offset() + phys)
+
+        - for once paging is up, WRITER itself is built before that and
+          relies on 0xb8000 being identity mapped
+        - every page the buffer covers is checked to really map to the
+          matching frame, a missing page gets mapped to it (uncached, it's
+          device memory)
+
+        unsafe since the result is a &'static mut: the caller has to guarantee
+        it's the only one, i.e. this is called once per buffer and the result
+        replaces whatever WRITER was using for the same memory
+    */
+    pub unsafe fn from_phys(
+        phys: PhysAddr,
+        mapper: &mut OffsetPageTable,
+        frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<&'static mut Buffer, BufferMapError> {
+        if !phys.is_aligned(core::mem::align_of::<ScreenChar>() as u64) {
+            return Err(BufferMapError::Misaligned);
+        }
+
+        let virt = mapper.phys_offset() + phys.as_u64();
+        let size = core::mem::size_of::<Buffer>() as u64;
+        let first = Page::<Size4KiB>::containing_address(virt);
+        let last = Page::<Size4KiB>::containing_address(virt + (size - 1));
+        for page in Page::range_inclusive(first, last) {
+            // phys_offset() is page aligned so pages and frames line up
+            let frame = PhysFrame::<Size4KiB>::containing_address(
+                phys + (page.start_address() - first.start_address()),
+            );
+            match mapper.translate_addr(page.start_address()) {
+                Some(addr) if addr == frame.start_address() => {}
+                Some(_) => return Err(BufferMapError::WrongFrame),
+                None => {
+                    let flags = PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::NO_CACHE;
+                    unsafe {
+                        mapper
+                            .map_to(page, frame, flags, frame_alloc)
+                            .map_err(BufferMapError::Map)?
+                            .flush();
+                    }
+                }
+            }
+        }
+        Ok(unsafe { &mut *virt.as_mut_ptr::<Buffer>() })
+    }
+}
+
 /*
    create global VGA Writer interface for outside modules to call without
    creating their own Writer instance
@@ -68,24 +292,94 @@ struct Buffer {
    at compile time
 */
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_pos: 0,
-        color_code: ColorCode::new(Color::White, Color::Black),
-        buf: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: Mutex<Writer> =
+        Mutex::new(Writer::new(Theme::DEFAULT.color_code(), unsafe {
+            &mut *(VGA_PHYS as *mut Buffer)
+        },));
 }
 
-// create a Writer type abstraction to allow us to more easily write to the
-// VGA buffer and track position
+// where write_line_aligned() puts text within a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+impl Alignment {
+    // first column of `len` characters (at most BUFFER_WIDTH) lined up this
+    // way, an odd amount of padding puts the extra blank on the right
+    fn start_column(self, len: usize) -> usize {
+        match self {
+            Alignment::Left => 0,
+            Alignment::Center => (BUFFER_WIDTH - len) / 2,
+            Alignment::Right => BUFFER_WIDTH - len,
+        }
+    }
+}
+
+/*
+    Writer type abstraction to allow us to more easily write to the VGA
+    buffer and track position
+
+    Double buffering:
+    - every write goes to `shadow`, a plain copy of the screen in regular
+      memory, so scrolling is just a memmove rather than 24*80 volatile
+      reads + writes
+    - flush() then goes over only the rows that changed (`dirty`) and
+      writes just the cells that differ from `painted`, the last thing
+      flushed to the real VGA buffer, so e.g. a status update that only
+      changes a clock does a handful of volatile writes instead of 80
+        - anything writing to the VGA buffer behind the Writer's back stays
+          on screen until that cell changes in the shadow
+    - the fmt::Write impl (print!/println!) flushes after every call, anyone
+      using write_byte()/write_string() directly has to call flush()
+    - println! has to work before init_heap() so the Writer itself never
+      allocates, anything heap backed added to it must check
+      heap::is_heap_ready() first and skip that path if it's false
+*/
 pub struct Writer {
     column_pos: usize,
     color_code: ColorCode,
     // ensure the compiler knows the lifetime of the buffer is for the length
     // of the whole program (kernel) runtime with 'static
     buf: &'static mut Buffer,
+    shadow: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    // what buf holds as of the last flush, kept in regular memory so
+    // comparing against it doesn't need volatile reads
+    painted: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    // rows of shadow that haven't been copied to buf yet, empty if none
+    dirty: Range<usize>,
+    // bottom row reserved for set_status(), text goes on the row above it
+    status_line: bool,
 }
 
 impl Writer {
+    // start the shadow off as a copy of whatever is on screen (e.g. the
+    // bootloader's messages) so the first flush doesn't wipe it
+    fn new(color_code: ColorCode, buf: &'static mut Buffer) -> Writer {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        };
+        let mut shadow = [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT];
+        for (row, shadow_row) in shadow.iter_mut().enumerate() {
+            for (col, cell) in shadow_row.iter_mut().enumerate() {
+                *cell = buf.chars[row][col].read();
+            }
+        }
+
+        Writer {
+            column_pos: 0,
+            color_code,
+            buf,
+            shadow,
+            painted: shadow,
+            dirty: 0..0,
+            status_line: false,
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
@@ -94,38 +388,66 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.live_row();
                 let col = self.column_pos;
 
                 let color_code = self.color_code;
-                self.buf.chars[row][col].write(ScreenChar {
+                self.shadow[row][col] = ScreenChar {
                     ascii_character: byte,
                     color_code,
-                });
+                };
+                self.mark_dirty(row..row + 1);
                 self.column_pos += 1;
             }
         }
     }
 
+    /*
+        two ways to write a run of bytes:
+
+        - write_string(): for text, any char outside printable ASCII (and
+          '\n') becomes a single 0xfe (■) since the VGA buffer is code page
+          437 not UTF-8, e.g. 'é' would otherwise show up as 2 unrelated
+          glyphs
+            - one cell per char, so backspace() erases a whole one
+            - print!/println!/write! all go through this one
+        - write_bytes_raw(): for content that is already CP437 (box drawing,
+          block art, ...), every byte is put on screen as-is, only b'\n'
+          still starts a new line
+    */
+    pub fn write_bytes_raw(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
+        for ch in s.chars() {
+            match ch {
                 // check if printable ASCII or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                ' '..='~' | '\n' => self.write_byte(ch as u8),
                 // outside of printable ASCII range
                 _ => self.write_byte(0xfe),
             }
         }
     }
 
-    fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let char = self.buf.chars[row][col].read();
-                self.buf.chars[row - 1][col].write(char);
-            }
+    // the row text is written to, the one above the status line if enabled
+    fn live_row(&self) -> usize {
+        if self.status_line {
+            BUFFER_HEIGHT - 2
+        } else {
+            BUFFER_HEIGHT - 1
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
+    }
+
+    fn new_line(&mut self) {
+        // shift every row up to the live row by one, every one of them
+        // changes while the status line (if any) stays put
+        let live = self.live_row();
+        self.shadow.copy_within(1..=live, 0);
+        self.mark_dirty(0..live + 1);
+        self.clear_row(live);
         self.column_pos = 0;
     }
 
@@ -135,9 +457,285 @@ impl Writer {
             color_code: self.color_code,
         };
 
-        for col in 0..BUFFER_WIDTH {
-            self.buf.chars[row][col].write(blank);
+        self.shadow[row] = [blank; BUFFER_WIDTH];
+        self.mark_dirty(row..row + 1);
+    }
+
+    /*
+        erase the character before the cursor
+
+        - if the cursor is at the start of the row the character is at the
+          end of the row above (the line wrapped), so scroll everything back
+          down by one row to bring it back to the bottom row and erase it
+          from there
+            - whatever was on the top row is lost, it had already scrolled
+              off screen before
+    */
+    pub fn backspace(&mut self) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+
+        let live = self.live_row();
+        if self.column_pos == 0 {
+            self.shadow.copy_within(..live, 1);
+            self.shadow[0] = [blank; BUFFER_WIDTH];
+            self.mark_dirty(0..live + 1);
+            self.column_pos = BUFFER_WIDTH;
+        }
+
+        self.column_pos -= 1;
+        self.shadow[live][self.column_pos] = blank;
+        self.mark_dirty(live..live + 1);
+        self.flush();
+    }
+
+    // where on the live row the next character goes
+    pub fn column(&self) -> usize {
+        self.column_pos
+    }
+
+    // move within the live row (e.g. Home/End in a line editor), what's
+    // already on the row stays put and gets overwritten by the next write
+    pub fn set_column(&mut self, col: usize) {
+        assert!(col < BUFFER_WIDTH, "column {} is off the screen", col);
+        self.column_pos = col;
+    }
+
+    /*
+        reserve the bottom row for set_status(), print!/println! then only
+        ever scroll the rows above it
+
+        - enabling shifts the screen up a row so the line being written
+          moves onto the new live row (BUFFER_HEIGHT - 2) with the cursor
+          where it was, and the status row starts out blank
+        - disabling shifts it back down so the live line is on the bottom
+          row again, the status text is dropped and the top row is lost
+    */
+    pub fn set_status_line_enabled(&mut self, enabled: bool) {
+        if enabled == self.status_line {
+            return;
+        }
+
+        if enabled {
+            self.shadow.copy_within(1.., 0);
+            self.clear_row(BUFFER_HEIGHT - 1);
+        } else {
+            self.shadow.copy_within(..BUFFER_HEIGHT - 1, 1);
+            self.clear_row(0);
+        }
+        self.status_line = enabled;
+        self.mark_dirty(0..BUFFER_HEIGHT);
+        self.flush();
+    }
+
+    pub fn status_line_enabled(&self) -> bool {
+        self.status_line
+    }
+
+    /*
+        replace the status row with `status` in the current colors, cut off
+        at BUFFER_WIDTH and with non-ASCII replaced like write_string()
+
+        - does nothing unless set_status_line_enabled(true) was called
+    */
+    pub fn set_status(&mut self, status: &str) {
+        if !self.status_line {
+            return;
+        }
+
+        self.write_row_aligned(BUFFER_HEIGHT - 1, status, Alignment::Left);
+    }
+
+    /*
+        replace the live row with `s` lined up against `align`, cut off at
+        BUFFER_WIDTH and with non-ASCII replaced like write_string()
+
+        - the cursor ends up right after the text, the next write carries on
+          from there (or starts a new line if the text reached the edge)
+    */
+    pub fn write_line_aligned(&mut self, s: &str, align: Alignment) {
+        let row = self.live_row();
+        self.column_pos = self.write_row_aligned(row, s, align);
+    }
+
+    // same as write_line_aligned() but for any row, the cursor doesn't
+    // move, returns the column just past the text
+    pub fn write_row_aligned(&mut self, row: usize, s: &str, align: Alignment) -> usize {
+        assert!(row < BUFFER_HEIGHT, "row {} is off the screen", row);
+
+        // one cell per char, same as write_string()
+        let len = s.chars().count().min(BUFFER_WIDTH);
+        let start = align.start_column(len);
+        self.clear_row(row);
+        for (col, ch) in (start..).zip(s.chars().take(len)) {
+            let ascii_character = match ch {
+                ' '..='~' => ch as u8,
+                _ => 0xfe,
+            };
+            self.shadow[row][col] = ScreenChar {
+                ascii_character,
+                color_code: self.color_code,
+            };
+        }
+        self.flush();
+        start + len
+    }
+
+    fn mark_dirty(&mut self, rows: Range<usize>) {
+        if self.dirty.is_empty() {
+            self.dirty = rows;
+        } else {
+            self.dirty = self.dirty.start.min(rows.start)..self.dirty.end.max(rows.end);
+        }
+    }
+
+    // colors for everything printed from now on, nothing on screen changes
+    pub fn set_color(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
+    /*
+        choose between blinking text and bright backgrounds for attribute
+        bit 7 (see ColorCode::with_blink()), for the whole screen
+
+        Attribute controller access:
+        - a single port (0x3c0) takes both the register index and the data,
+          a flip-flop decides which one the next write is
+        - reading Input Status #1 (0x3da) resets the flip-flop to "index"
+        - the index write keeps bit 5 (PAS) set, with it clear the
+          controller releases the palette and the screen goes blank
+        - then 0x3c1 reads the selected register and the next write to 0x3c0
+          is its new value, which flips the flip-flop back to "index"
+        - the blink enable bit is bit 3 of the Attribute Mode Control
+          register (index 0x10)
+    */
+    pub fn set_blink_enabled(&mut self, enabled: bool) {
+        use bit_field::BitField;
+
+        let mut mode = read_attr_mode();
+        mode.set_bit(3, enabled);
+        let (mut status, mut attr_addr, _) = attr_ports();
+        status.read();
+        attr_addr.write(ATTR_MODE_CONTROL | ATTR_PAS);
+        attr_addr.write(mode);
+    }
+
+    pub fn blink_enabled(&self) -> bool {
+        use bit_field::BitField;
+        read_attr_mode().get_bit(3)
+    }
+
+    /*
+        make `theme` the colors for everything printed from now on
+
+        - with `repaint` every cell already on screen is recolored too, the
+          glyphs are left alone and only the attribute byte is rewritten
+            - cells in the old default colors take on the new ones
+            - anything else (e.g. highlighted text) keeps its foreground and
+              just gets the new background
+    */
+    pub fn set_theme(&mut self, theme: Theme, repaint: bool) {
+        let old = self.color_code;
+        self.color_code = theme.color_code();
+        if !repaint {
+            return;
+        }
+
+        // get the screen up to date first so buf and shadow agree
+        self.flush();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let mut cell = self.buf.chars[row][col].read();
+                cell.color_code = if cell.color_code == old {
+                    self.color_code
+                } else {
+                    cell.color_code.with_background(theme.default_bg)
+                };
+                self.buf.chars[row][col].write(cell);
+                self.shadow[row][col] = cell;
+                self.painted[row][col] = cell;
+            }
+        }
+    }
+
+    /*
+        copy of what is actually on screen, read back from the VGA buffer
+        (not the shadow) so unflushed writes don't show up in it
+    */
+    pub fn snapshot(&self) -> [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] {
+        let mut snap = self.shadow;
+        for (row, snap_row) in snap.iter_mut().enumerate() {
+            for (col, cell) in snap_row.iter_mut().enumerate() {
+                *cell = self.buf.chars[row][col].read();
+            }
         }
+        snap
+    }
+
+    // point the writer at a different buffer (e.g. one from
+    // Buffer::from_phys()), the screen contents come along with it
+    pub fn set_buffer(&mut self, buf: &'static mut Buffer) {
+        self.buf = buf;
+        // nothing is known to be on the new buffer, write every cell
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.buf.chars[row][col].write(self.shadow[row][col]);
+            }
+        }
+        self.painted = self.shadow;
+        self.dirty = 0..0;
+    }
+
+    // virtual address the writer is currently writing the screen through
+    pub fn buffer_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(&*self.buf)
+    }
+
+    /*
+        one cell of the screen, for code outside this module (e.g.
+        integration tests) that shouldn't depend on how the buffer is laid
+        out, panics if row/col are off the screen like the array would
+
+        - cell() reads the VGA buffer itself, like snapshot()
+        - set_cell() writes both the shadow and the VGA buffer so it shows
+          up straight away and a later flush doesn't undo it
+    */
+    pub fn cell(&self, row: usize, col: usize) -> ScreenChar {
+        self.buf.chars[row][col].read()
+    }
+
+    pub fn set_cell(&mut self, row: usize, col: usize, cell: ScreenChar) {
+        self.shadow[row][col] = cell;
+        self.painted[row][col] = cell;
+        self.buf.chars[row][col].write(cell);
+    }
+
+    // each row on screen as text, top to bottom, with trailing blanks
+    // trimmed off (allocates, so only usable after heap::init_heap())
+    pub fn rows_text(&self) -> impl Iterator<Item = String> {
+        let snap = self.snapshot();
+        (0..BUFFER_HEIGHT).map(move |row| {
+            let mut text: String = snap[row].iter().map(ScreenChar::as_char).collect();
+            text.truncate(text.trim_end_matches(' ').len());
+            text
+        })
+    }
+
+    // copy the cells of the dirty rows that changed since the last flush
+    // out to the VGA buffer
+    pub fn flush(&mut self) {
+        for row in self.dirty.clone() {
+            for col in 0..BUFFER_WIDTH {
+                let cell = self.shadow[row][col];
+                if cell != self.painted[row][col] {
+                    self.buf.chars[row][col].write(cell);
+                    self.painted[row][col] = cell;
+                }
+            }
+        }
+        self.dirty = 0..0;
     }
 }
 
@@ -146,10 +744,35 @@ impl Writer {
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
+        self.flush();
         Ok(())
     }
 }
 
+const ATTR_MODE_CONTROL: u8 = 0x10;
+const ATTR_PAS: u8 = 0x20;
+
+// (input status #1, attribute address/data write, attribute data read)
+fn attr_ports() -> (PortRegister<u8>, PortRegister<u8>, PortRegister<u8>) {
+    unsafe {
+        (
+            PortRegister::new(0x3da),
+            PortRegister::new(0x3c0),
+            PortRegister::new(0x3c1),
+        )
+    }
+}
+
+fn read_attr_mode() -> u8 {
+    let (mut status, mut attr_addr, mut attr_read) = attr_ports();
+    status.read();
+    attr_addr.write(ATTR_MODE_CONTROL | ATTR_PAS);
+    let mode = attr_read.read();
+    // leave the flip-flop back in "index" state
+    status.read();
+    mode
+}
+
 /*
    define print macros for the entire crate so they can interact
    with the VGA buffer through those macros instead of using the
@@ -167,6 +790,128 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+pub fn set_theme(theme: Theme, repaint: bool) {
+    use x86_64::instructions::interrupts;
+
+    if !vga_present() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_theme(theme, repaint);
+    });
+}
+
+/*
+    Whether there's a VGA text buffer at 0xb8000 at all
+
+    - a UEFI boot usually leaves the screen in a graphics mode, writing to
+      0xb8000 then just scribbles over whatever memory is there
+    - cleared by BootEnvironment::init_console() when there's no text mode,
+      print!/println! then go to the framebuffer console instead and WRITER
+      is never touched (or even created)
+*/
+static VGA_PRESENT: AtomicBool = AtomicBool::new(true);
+
+pub fn set_vga_present(present: bool) {
+    VGA_PRESENT.store(present, Ordering::SeqCst);
+}
+
+pub fn vga_present() -> bool {
+    VGA_PRESENT.load(Ordering::Relaxed)
+}
+
+// erase the character before the cursor on whichever screen print! goes to
+pub fn backspace() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if !vga_present() {
+            if let Some(console) = crate::framebuffer::console().lock().as_mut() {
+                console.backspace();
+            }
+            return;
+        }
+        WRITER.lock().backspace();
+    });
+}
+
+/*
+    move within the live row of whichever screen print! goes to
+
+    - `f` gets the current column and the row's width and returns the column
+      to move to, or None to stay put
+*/
+pub fn move_column(f: impl FnOnce(usize, usize) -> Option<usize>) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if !vga_present() {
+            if let Some(console) = crate::framebuffer::console().lock().as_mut() {
+                if let Some(col) = f(console.column(), console.columns()) {
+                    console.set_column(col);
+                }
+            }
+            return;
+        }
+        let mut writer = WRITER.lock();
+        if let Some(col) = f(writer.column(), BUFFER_WIDTH) {
+            writer.set_column(col);
+        }
+    });
+}
+
+/*
+    Output capture for tests
+
+    - while capture() is running print!/println! append to a String
+      instead of going to the screen, which it returns at the end
+    - there's only one CPU and no threads, so anything printed in the
+      meantime is captured, except:
+        - from interrupt handlers, which shouldn't allocate
+        - once a panic has started, so the message isn't lost with f()
+        - while CAPTURE is already locked, e.g. a Debug impl or the
+          allocator printing in the middle of growing the String, that
+          would otherwise spin on CAPTURE forever
+      those go to the screen as usual
+    - nested captures each get their own output, the outer one continues
+      once the inner one is done
+    - needs the heap
+*/
+static CAPTURE: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn capture(f: impl FnOnce()) -> String {
+    use x86_64::instructions::interrupts;
+
+    let outer = interrupts::without_interrupts(|| CAPTURE.lock().replace(String::new()));
+    f();
+    interrupts::without_interrupts(|| {
+        let mut capture = CAPTURE.lock();
+        let captured = capture.take().unwrap_or_default();
+        *capture = outer;
+        captured
+    })
+}
+
+// true if `args` went into the active capture (see capture() for when it
+// doesn't)
+fn capture_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    if crate::panicking() || crate::interrupts::in_handler() {
+        return false;
+    }
+    match CAPTURE.try_lock() {
+        Some(mut capture) => match capture.as_mut() {
+            Some(captured) => {
+                let _ = captured.write_fmt(args);
+                true
+            }
+            None => false,
+        },
+        None => false,
+    }
+}
+
 // use doc(hidden) to hide function from generated documentation
 // as it is a private implementation detail
 #[doc(hidden)]
@@ -177,10 +922,96 @@ pub fn _print(args: fmt::Arguments) {
     // ensures that no interrupt can occur while the mutex is locked
     // helps prevent deadlocks
     interrupts::without_interrupts(|| {
+        if capture_print(args) {
+            return;
+        }
+        if !vga_present() {
+            // dropped if there's no framebuffer console either
+            if let Some(console) = crate::framebuffer::console().lock().as_mut() {
+                console.write_fmt(args).unwrap();
+            }
+            return;
+        }
         WRITER.lock().write_fmt(args).unwrap();
     });
 }
 
+/*
+    print!/println! for exception handlers
+
+    - a fault can land while the code it interrupted holds WRITER (e.g. a
+      bad pointer dereferenced by a Debug impl in the middle of a println!),
+      locking it again from the handler would spin forever
+    - so every lock on the way to the screen is only try_lock()ed, if one
+      is held the message goes to serial instead (counted by
+      diverted_prints()) and if SERIAL1 is held (or still being set up) too
+      it's lost
+    - only meant for fault reporting, anywhere else silently moving output
+      to serial would just be confusing
+*/
+#[macro_export]
+macro_rules! try_print {
+    ($($arg:tt)*) => ($crate::vga_buf::_try_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! try_println {
+    () => ($crate::try_print!("\n"));
+    ($($arg:tt)*) => ($crate::try_print!("{}\n", format_args!($($arg)*)));
+}
+
+static DIVERTED: AtomicUsize = AtomicUsize::new(0);
+
+// how many try_print!s went to serial because the screen was locked
+pub fn diverted_prints() -> usize {
+    DIVERTED.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _try_print(args: fmt::Arguments) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if !try_print_screen(args)
+            && crate::serial::serial1().map_or(false, |serial| try_write(serial, args))
+        {
+            DIVERTED.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+// same path as _print(), false if any lock on it was held
+fn try_print_screen(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    if capture_print(args) {
+        return true;
+    }
+    if !vga_present() {
+        return match crate::framebuffer::console().try_lock() {
+            Some(mut console) => {
+                if let Some(console) = console.as_mut() {
+                    let _ = console.write_fmt(args);
+                }
+                true
+            }
+            None => false,
+        };
+    }
+    try_write(&WRITER, args)
+}
+
+// write to whatever `lock` guards unless someone already holds it
+fn try_write<W: fmt::Write>(lock: &Mutex<W>, args: fmt::Arguments) -> bool {
+    match lock.try_lock() {
+        Some(mut out) => {
+            let _ = out.write_fmt(args);
+            true
+        }
+        None => false,
+    }
+}
+
 // test println! runs
 #[test_case]
 fn test_println_simple() {
@@ -204,11 +1035,364 @@ fn test_println_output() {
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
         writeln!(writer, "\n{}", s).expect("writeln! failed");
+        let screen = writer.snapshot();
         // use enumerate to get both the position in the str: i and the character: c
         for (i, c) in s.chars().enumerate() {
             // check line above in buffer as println! will move the string up a row after printing
-            let screen_char = writer.buf.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
+            assert_eq!(screen[BUFFER_HEIGHT - 2][i].as_char(), c);
         }
     })
 }
+
+// test that printing while CAPTURE is locked (e.g. from inside the capture's
+// own write) falls through to the screen rather than spinning on it
+#[test_case]
+fn test_capture_print_while_locked() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut capture = CAPTURE.lock();
+        // String::new() doesn't allocate, lib tests have no heap
+        let outer = capture.replace(String::new());
+        assert!(!capture_print(format_args!("not captured")));
+        *capture = outer;
+    })
+}
+
+// test that a flush only writes the cells that changed, a cell changed on
+// the VGA buffer directly is left alone unless the shadow changes too
+#[test_case]
+fn test_flush_writes_changed_cells_only() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\ntest_flush_writes_changed_cells_only");
+        writer.flush();
+        let row = writer.live_row();
+        let marker = ScreenChar::new(b'#', writer.color_code);
+        for col in 0..BUFFER_WIDTH {
+            writer.buf.chars[row][col].write(marker);
+        }
+
+        let changed = ScreenChar::new(b'!', writer.color_code);
+        writer.shadow[row][3] = changed;
+        writer.shadow[row][7] = changed;
+        writer.mark_dirty(row..row + 1);
+        writer.flush();
+
+        for col in 0..BUFFER_WIDTH {
+            let expected = if col == 3 || col == 7 {
+                changed
+            } else {
+                marker
+            };
+            assert_eq!(writer.buf.chars[row][col].read(), expected);
+        }
+        // put back what the shadow has so the screen is consistent again
+        let shadow = writer.shadow;
+        for col in 0..BUFFER_WIDTH {
+            writer.set_cell(row, col, shadow[row][col]);
+        }
+    })
+}
+
+// test that the VGA buffer matches the shadow buffer after a flush
+#[test_case]
+fn test_flush_matches_shadow() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for i in 0..5 {
+            writer.write_string("test_flush_matches_shadow line");
+            writer.write_byte(b'0' + i);
+            writer.write_byte(b'\n');
+        }
+        assert!(!writer.dirty.is_empty());
+        writer.flush();
+        assert!(writer.dirty.is_empty());
+
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(writer.buf.chars[row][col].read(), writer.shadow[row][col]);
+            }
+        }
+        // last line written ends up just above the (blank) bottom row
+        let screen_char = writer.buf.chars[BUFFER_HEIGHT - 2][30].read();
+        assert_eq!(screen_char.ascii_character, b'4');
+    })
+}
+
+// test that a backspace at the start of a row un-wraps the previous row
+#[test_case]
+fn test_backspace_unwraps() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        for _ in 0..BUFFER_WIDTH {
+            writer.write_byte(b'x');
+        }
+        writer.write_byte(b'y');
+        assert_eq!(writer.column_pos, 1);
+
+        writer.backspace();
+        assert_eq!(writer.column_pos, 0);
+        writer.backspace();
+        assert_eq!(writer.column_pos, BUFFER_WIDTH - 1);
+        let row = &writer.shadow[BUFFER_HEIGHT - 1];
+        assert_eq!(row[BUFFER_WIDTH - 1].ascii_character, b' ');
+        assert_eq!(row[BUFFER_WIDTH - 2].ascii_character, b'x');
+        writer.write_byte(b'\n');
+    })
+}
+
+// test that one backspace takes back all of a non-ASCII char
+#[test_case]
+fn test_backspace_non_ascii() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        writer.write_string("a\u{e9}");
+        assert_eq!(writer.column_pos, 2);
+
+        writer.backspace();
+        assert_eq!(writer.column_pos, 1);
+        let row = &writer.shadow[BUFFER_HEIGHT - 1];
+        assert_eq!(row[0].ascii_character, b'a');
+        assert_eq!(row[1].ascii_character, b' ');
+        writer.write_byte(b'\n');
+    })
+}
+
+// test that a character lands where set_column() put the cursor
+#[test_case]
+fn test_set_column() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        writer.write_string("abcdef");
+        assert_eq!(writer.column(), 6);
+
+        writer.set_column(2);
+        assert_eq!(writer.column(), 2);
+        writer.write_byte(b'X');
+        assert_eq!(writer.column(), 3);
+        let row = &writer.shadow[writer.live_row()];
+        assert_eq!(row[1].ascii_character, b'b');
+        assert_eq!(row[2].ascii_character, b'X');
+        assert_eq!(row[3].ascii_character, b'd');
+
+        writer.set_column(BUFFER_WIDTH - 1);
+        writer.write_byte(b'Y');
+        let live = writer.live_row();
+        assert_eq!(writer.shadow[live][BUFFER_WIDTH - 1].ascii_character, b'Y');
+        writer.write_byte(b'\n');
+    })
+}
+
+// test that aligned lines start at the right column and get truncated
+#[test_case]
+fn test_write_line_aligned() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        let live = writer.live_row();
+
+        writer.write_line_aligned("hi", Alignment::Center);
+        assert_eq!(writer.cell(live, 38).ascii_character, b' ');
+        assert_eq!(writer.cell(live, 39).ascii_character, b'h');
+        assert_eq!(writer.cell(live, 40).ascii_character, b'i');
+        assert_eq!(writer.column(), 41);
+
+        writer.write_line_aligned("hi", Alignment::Right);
+        assert_eq!(writer.cell(live, 39).ascii_character, b' ');
+        assert_eq!(writer.cell(live, BUFFER_WIDTH - 2).ascii_character, b'h');
+        assert_eq!(writer.cell(live, BUFFER_WIDTH - 1).ascii_character, b'i');
+
+        let long = [b'x'; BUFFER_WIDTH + 10];
+        let long = core::str::from_utf8(&long).unwrap();
+        writer.write_line_aligned(long, Alignment::Center);
+        assert_eq!(writer.cell(live, 0).ascii_character, b'x');
+        assert_eq!(writer.cell(live, BUFFER_WIDTH - 1).ascii_character, b'x');
+        assert_eq!(writer.column(), BUFFER_WIDTH);
+        writer.write_byte(b'\n');
+    })
+}
+
+// test that snapshot only sees flushed output and finds a string where it
+// was written
+#[test_case]
+fn test_snapshot_position() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        writer.flush();
+        writer.write_string("    test_snapshot_position");
+        let before = writer.snapshot();
+        assert_eq!(before[BUFFER_HEIGHT - 1][4].as_char(), ' ');
+
+        writer.flush();
+        let after = writer.snapshot();
+        for (i, c) in "test_snapshot_position".chars().enumerate() {
+            assert_eq!(after[BUFFER_HEIGHT - 1][4 + i].as_char(), c);
+        }
+        writer.write_byte(b'\n');
+    })
+}
+
+#[test_case]
+fn test_theme_parse() {
+    assert_eq!(
+        Theme::parse("yellow/blue"),
+        Some(Theme {
+            default_fg: Color::Yellow,
+            default_bg: Color::Blue,
+        })
+    );
+    assert_eq!(Theme::parse("80x50"), None);
+    assert_eq!(Theme::parse("white/nope"), None);
+}
+
+// test that set_theme recolors what's on screen and what comes after it
+#[test_case]
+fn test_set_theme_repaints() {
+    use x86_64::instructions::interrupts;
+    let theme = Theme {
+        default_fg: Color::Yellow,
+        default_bg: Color::Blue,
+    };
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let prev = writer.color_code;
+        writer.write_byte(b'\n');
+        writer.write_string("old");
+        writer.color_code = ColorCode::new(Color::Red, Color::Black);
+        writer.write_byte(b'!');
+        writer.color_code = prev;
+
+        writer.set_theme(theme, true);
+        writer.write_string("new");
+        writer.flush();
+
+        let row = writer.snapshot()[BUFFER_HEIGHT - 1];
+        let themed = ColorCode::new(Color::Yellow, Color::Blue);
+        for (i, c) in "old!new".chars().enumerate() {
+            assert_eq!(row[i].as_char(), c);
+        }
+        assert_eq!(row[0].color_code, themed);
+        // highlighted cell keeps its foreground
+        assert_eq!(row[3].color_code, ColorCode::new(Color::Red, Color::Blue));
+        assert_eq!(row[4].color_code, themed);
+        writer.write_byte(b'\n');
+    });
+    set_theme(Theme::DEFAULT, true);
+}
+
+// test that only write_string substitutes non-ASCII bytes
+#[test_case]
+fn test_write_bytes_raw_verbatim() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        // 0xc9 is the double line top left corner, 0xcd the horizontal
+        writer.write_bytes_raw(&[0xc9, 0xcd]);
+        // '╔' is 3 bytes of UTF-8 but gets a single replacement cell
+        writer.write_string("\u{2554}");
+        writer.flush();
+
+        let row = writer.snapshot()[BUFFER_HEIGHT - 1];
+        assert_eq!(row[0].ascii_character, 0xc9);
+        assert_eq!(row[1].ascii_character, 0xcd);
+        assert_eq!(row[2].ascii_character, 0xfe);
+        assert_eq!(row[2].as_char(), '\u{25a0}');
+        assert_eq!(row[3].ascii_character, b' ');
+        writer.write_byte(b'\n');
+    })
+}
+
+#[test_case]
+fn test_with_blink() {
+    let code = ColorCode::new(Color::White, Color::Blue);
+    assert_eq!(code.with_blink(true).0, 0x9f);
+    assert_eq!(code.with_blink(true).with_blink(false), code);
+    // bright background Blue (1) + bit 7 is LightBlue (9)
+    assert_eq!(
+        ColorCode::new(Color::White, Color::LightBlue),
+        code.with_blink(true)
+    );
+}
+
+#[test_case]
+fn test_set_blink_enabled() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let before = writer.blink_enabled();
+        writer.set_blink_enabled(!before);
+        assert_eq!(writer.blink_enabled(), !before);
+        writer.set_blink_enabled(before);
+        assert_eq!(writer.blink_enabled(), before);
+    })
+}
+
+// test that scrolling with the status line enabled never touches it
+#[test_case]
+fn test_status_line_survives_scroll() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        writer.write_string("live");
+        writer.set_status_line_enabled(true);
+        // cursor stays put on the line that moved up
+        writer.write_string("!");
+        writer.flush();
+        let row = writer.snapshot()[BUFFER_HEIGHT - 2];
+        for (i, c) in "live!".chars().enumerate() {
+            assert_eq!(row[i].as_char(), c);
+        }
+
+        writer.set_status("status: ok");
+        for _ in 0..BUFFER_HEIGHT + 5 {
+            writer.write_string("scrolling\n");
+        }
+        writer.write_string("last");
+        writer.flush();
+
+        let screen = writer.snapshot();
+        for (i, c) in "status: ok".chars().enumerate() {
+            assert_eq!(screen[BUFFER_HEIGHT - 1][i].as_char(), c);
+        }
+        for (i, c) in "last".chars().enumerate() {
+            assert_eq!(screen[BUFFER_HEIGHT - 2][i].as_char(), c);
+        }
+        assert_eq!(screen[BUFFER_HEIGHT - 3][0].as_char(), 's');
+
+        writer.set_status_line_enabled(false);
+        let row = writer.snapshot()[BUFFER_HEIGHT - 1];
+        for (i, c) in "last".chars().enumerate() {
+            assert_eq!(row[i].as_char(), c);
+        }
+        writer.write_byte(b'\n');
+    })
+}
+
+// test that the palette encodes to the attribute bytes the VGA hardware expects
+#[test_case]
+fn test_palette_attribute_bytes() {
+    const PALETTE: [(ColorCode, u8); 5] = [
+        (ColorCode::DEFAULT, 0x0f),
+        (ColorCode::ERROR, 0x4f),
+        (ColorCode::WARNING, 0x0e),
+        (ColorCode::SUCCESS, 0x02),
+        (ColorCode::INFO, 0x0b),
+    ];
+    for &(code, byte) in PALETTE.iter() {
+        assert_eq!(code.0, byte);
+    }
+    assert_eq!(ColorCode::ERROR.with_blink(true).0, 0xcf);
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use os_practice::interrupts::{self, ExceptionVector};
+use os_practice::{exit_qemu, serial_print, serial_println, QEMUExitCode};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n\nError: {}\n", info);
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// the catch-all handler calls this right before it would halt
+fn on_unhandled(vector: u8, err_code: Option<u64>) {
+    if vector == ExceptionVector::AlignmentCheck.as_u8() && err_code == Some(0) {
+        serial_println!("[ok]");
+        exit_qemu(QEMUExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: vector {} code {:?}\n", vector, err_code);
+        exit_qemu(QEMUExitCode::Failure);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    os_practice::init().expect("kernel initialization failed");
+    interrupts::set_unhandled_exception_hook(on_unhandled);
+    serial_println!("Running 1 tests:");
+    test_alignment_check();
+    serial_println!("[alignment check handler returned]");
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+/*
+    a real #AC needs a misaligned access from ring 3, which the kernel can't
+    get to yet, and `int 17` doesn't push an error code, so deliver it the
+    way the CPU would: align the stack, push ss, rsp, rflags, cs, rip and
+    the error code (always 0 for #AC) and jump to the IDT entry's handler
+*/
+fn test_alignment_check() {
+    serial_print!("alignment_check_test::test_alignment_check...\t");
+    let handler = interrupts::IDT.handler_addr(ExceptionVector::AlignmentCheck.as_usize());
+    unsafe {
+        asm!(
+            "mov rax, rsp",
+            "and rsp, -16",
+            "push 0",
+            "push rax",
+            "pushfq",
+            "mov rax, cs",
+            "push rax",
+            "lea rax, [rip]",
+            "push rax",
+            "push 0",
+            "jmp rsi",
+            in("rsi") handler,
+            options(noreturn),
+        );
+    }
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::heap::tracked::{kalloc, kbox, recent_sites, Site};
+#[test_case]
+fn tracked_sites_recorded() {
+    use alloc::alloc::{dealloc, Layout};
+
+    let line = line!() + 1;
+    let boxed = kbox([0u64; 4]);
+    assert_eq!(
+        recent_sites()[0],
+        Some(Site {
+            file: file!(),
+            line,
+            size: 32,
+        })
+    );
+
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let line = line!() + 1;
+    let ptr = unsafe { kalloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { dealloc(ptr, layout) };
+
+    // newest first, the kbox() is still there behind it
+    let recent = recent_sites();
+    assert_eq!(
+        recent[0].map(|site| (site.line, site.size)),
+        Some((line, 100))
+    );
+    assert_eq!(recent[1].map(|site| site.size), Some(32));
+    drop(boxed);
+}
+
+#[test_case]
+fn ring_keeps_newest() {
+    use os_practice::heap::tracked::SITES_LEN;
+
+    for i in 0..SITES_LEN + 3 {
+        drop(kbox(i));
+    }
+    let recent = recent_sites();
+    assert!(recent.iter().all(|site| site.is_some()));
+    assert!(recent.iter().all(|site| site.unwrap().file == file!()));
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use alloc::{string::String, vec::Vec};
+use core::arch::asm;
+use os_practice::vga_buf::WRITER;
+#[test_case]
+fn breakpoint_prints_registers() {
+    use x86_64::instructions::interrupts;
+
+    let r12_after: u64;
+    unsafe {
+        asm!(
+            "int3",
+            in("rax") 0xaaaa_0000_0000_aaaa_u64,
+            inout("r12") 0x1212_1212_1212_1212_u64 => r12_after,
+            in("r15") 0x0f0f_0f0f_0f0f_0f0f_u64,
+        );
+    }
+    // made it back with registers intact
+    assert_eq!(r12_after, 0x1212_1212_1212_1212);
+
+    let rows: Vec<String> = interrupts::without_interrupts(|| WRITER.lock().rows_text().collect());
+    let printed = |reg: &str| rows.iter().any(|row| row.contains(reg));
+    assert!(printed("EXCEPTION: BREAKPOINT (INT3)"));
+    assert!(printed("rax=aaaa00000000aaaa"));
+    assert!(printed("r12=1212121212121212"));
+    assert!(printed("r15=0f0f0f0f0f0f0f0f"));
+}
+
+// the handler would spin on WRITER forever if it still used println!, with
+// the lock held its report has to go to serial instead
+#[test_case]
+fn breakpoint_with_writer_locked() {
+    use os_practice::vga_buf::diverted_prints;
+    use x86_64::instructions::interrupts;
+
+    let before = diverted_prints();
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        let screen = writer.snapshot();
+        interrupts::int3();
+        assert!(writer.snapshot() == screen);
+    });
+    assert_eq!(diverted_prints(), before + 1);
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+// capture() builds a String so it needs the heap
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::vga_buf::{capture, WRITER};
+use os_practice::{print, println};
+use x86_64::instructions::interrupts::without_interrupts;
+
+fn say_hello() {
+    print!("hello");
+}
+
+#[test_case]
+fn captures_print() {
+    let before = without_interrupts(|| WRITER.lock().snapshot());
+    assert_eq!(capture(say_hello), "hello");
+    // nothing reached the screen
+    assert!(without_interrupts(|| WRITER.lock().snapshot()) == before);
+}
+
+#[test_case]
+fn nested_capture() {
+    let outer = capture(|| {
+        println!("outer {}", 1);
+        let inner = capture(|| println!("inner"));
+        assert_eq!(inner, "inner\n");
+        print!("outer again");
+    });
+    assert_eq!(outer, "outer 1\nouter again");
+}
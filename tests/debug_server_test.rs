@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use spin::{Mutex, Once};
+use x86_64::structures::paging::OffsetPageTable;
+
+entry_point!(kern_main);
+
+// kept around so the test cases can check mappings
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+    MAPPER.call_once(|| Mutex::new(mapper));
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, format, string::String};
+use os_practice::debug_server::handle_command;
+#[test_case]
+fn read_heap_bytes() {
+    let data = Box::new([0xde_u8, 0xad, 0xbe, 0xef]);
+    let addr = data.as_ptr() as u64;
+    let mapper = MAPPER.wait().unwrap().lock();
+
+    let mut reply = String::new();
+    handle_command(&format!("R {:#x} 4", addr), &*mapper, &mut reply).unwrap();
+    assert_eq!(reply, "OK deadbeef\n");
+}
+
+#[test_case]
+fn write_heap_bytes() {
+    let mut data = Box::new([0u8; 2]);
+    let addr = data.as_mut_ptr() as u64;
+    let mapper = MAPPER.wait().unwrap().lock();
+
+    let mut reply = String::new();
+    handle_command(&format!("W {:x} 1234", addr), &*mapper, &mut reply).unwrap();
+    assert_eq!(reply, "OK\n");
+    assert_eq!(*data, [0x12, 0x34]);
+}
+
+#[test_case]
+fn unmapped_read_is_rejected() {
+    let mapper = MAPPER.wait().unwrap().lock();
+
+    let mut reply = String::new();
+    // the first page is never mapped
+    handle_command("R 0x0 8", &*mapper, &mut reply).unwrap();
+    assert_eq!(reply, "ERR unmapped\n");
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os_practice::{exit_qemu, serial_print, serial_println, QEMUExitCode};
+
+const EXPECTED: &str = "DOUBLE FREE DETECTED";
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::expected_panic_handler(info, EXPECTED)
+}
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    detects_double_free();
+    serial_println!("[double free not detected]");
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+fn detects_double_free() {
+    use alloc::alloc::{dealloc, Layout};
+    use alloc::boxed::Box;
+    serial_print!("double_free_test::detects_double_free...\t");
+
+    let ptr = Box::into_raw(Box::new(42u64)) as *mut u8;
+    let layout = Layout::new::<u64>();
+    unsafe {
+        // the first free is fine
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+// deliberately the wrong way round: the IDT gets loaded while the
+// bootloader's GDT is still in place and no TSS is loaded, the GDT only
+// comes later (second test case)
+fn kern_main(_boot_info: &'static BootInfo) -> ! {
+    os_practice::interrupts::init();
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::fault_check::{problems, report, Problem};
+#[test_case]
+fn idt_before_gdt_reported() {
+    let has = |problem| problems().any(|p| p == problem);
+    assert!(has(Problem::GdtNotLoaded));
+    assert!(has(Problem::TssNotLoaded));
+    assert!(!has(Problem::IdtNotLoaded));
+    assert!(!has(Problem::DoubleFaultNoIst));
+    assert!(report() >= 2);
+}
+
+#[test_case]
+fn gdt_loaded_late() {
+    os_practice::gdt::init();
+    let has = |problem| problems().any(|p| p == problem);
+    assert!(!has(Problem::GdtNotLoaded));
+    assert!(!has(Problem::TssNotLoaded));
+    assert!(!has(Problem::NoDoubleFaultStack));
+}
@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use lazy_static::lazy_static;
+
+// two small USABLE regions with a hole in between, small enough that the
+// frame cache easily fits on the heap
+lazy_static! {
+    static ref MAP: MemoryMap = {
+        let mut map = MemoryMap::new();
+        map.add_region(MemoryRegion {
+            range: FrameRange::new(0x10_0000, 0x12_0000),
+            region_type: MemoryRegionType::Usable,
+        });
+        map.add_region(MemoryRegion {
+            range: FrameRange::new(0x12_0000, 0x20_0000),
+            region_type: MemoryRegionType::InUse,
+        });
+        map.add_region(MemoryRegion {
+            range: FrameRange::new(0x20_0000, 0x22_0000),
+            region_type: MemoryRegionType::Usable,
+        });
+        map
+    };
+}
+
+use os_practice::mem::BootInfoFrameAllocator;
+use x86_64::structures::paging::FrameAllocator;
+#[test_case]
+fn cached_frames_match_uncached() {
+    let mut uncached = unsafe { BootInfoFrameAllocator::init(&MAP) };
+    let mut cached = unsafe { BootInfoFrameAllocator::init(&MAP) };
+
+    // cache part way through to make sure `next` carries over
+    for _ in 0..3 {
+        assert_eq!(cached.allocate_frame(), uncached.allocate_frame());
+    }
+    assert!(cached.cache_frames());
+    assert_eq!(cached.frames_available(), uncached.frames_available());
+
+    let mut prev = None;
+    while let Some(frame) = uncached.allocate_frame() {
+        assert_eq!(cached.allocate_frame(), Some(frame));
+        if let Some(prev) = prev {
+            assert!(frame > prev);
+        }
+        prev = Some(frame);
+    }
+    assert_eq!(cached.allocate_frame(), None);
+    assert_eq!(cached.frames_available(), 0);
+    assert_eq!(cached.frames_allocated(), uncached.frames_allocated());
+}
+
+use os_practice::mem::alloc_contiguous;
+use x86_64::PhysAddr;
+#[test_case]
+fn contiguous_frames() {
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&MAP) };
+    let first = alloc_contiguous(4, &mut frame_alloc).unwrap();
+    assert_eq!(first.start_address(), PhysAddr::new(0x10_0000));
+    assert_eq!(frame_alloc.frames_allocated(), 4);
+    // the next single frame comes right after the run
+    assert_eq!(frame_alloc.allocate_frame(), Some(first + 4));
+
+    // only 27 frames are left in the first region, so a run of 28 has to
+    // skip them and come out of the second one
+    let second = alloc_contiguous(28, &mut frame_alloc).unwrap();
+    assert_eq!(second.start_address(), PhysAddr::new(0x20_0000));
+    assert_eq!(frame_alloc.frames_allocated(), 32 + 28);
+
+    // 4 left, not enough
+    assert_eq!(alloc_contiguous(5, &mut frame_alloc), None);
+    assert_eq!(frame_alloc.failed_allocations(), 1);
+    assert!(alloc_contiguous(4, &mut frame_alloc).is_some());
+}
+
+use alloc::string::ToString;
+use os_practice::{check_memory, heap, mem::usable_frame_count};
+#[test_case]
+fn insufficient_memory_message() {
+    // 32 frames in each USABLE region, the hole doesn't count
+    assert_eq!(usable_frame_count((&*MAP).into()), 64);
+
+    let mut tiny = MemoryMap::new();
+    tiny.add_region(MemoryRegion {
+        range: FrameRange::new(0x10_0000, 0x10_2000),
+        region_type: MemoryRegionType::Usable,
+    });
+    tiny.add_region(MemoryRegion {
+        range: FrameRange::new(0x10_2000, 0x20_0000),
+        region_type: MemoryRegionType::Reserved,
+    });
+    let msg = check_memory((&tiny).into()).unwrap_err().to_string();
+    assert_eq!(
+        msg,
+        alloc::format!(
+            "INSUFFICIENT MEMORY: need {} frames, have 2",
+            heap::frames_needed()
+        )
+    );
+}
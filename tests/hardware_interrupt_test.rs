@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+// init() enables interrupts, and the heap is needed for the scancode queue
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+// proves the PIC delivers IRQ0 and the handler runs and sends its EOI, if the
+// EOI was missing the counter would stop after the first tick
+#[test_case]
+fn timer_ticks_advance() {
+    use os_practice::interrupts::ticks;
+
+    let start = ticks();
+    // each `hlt` sleeps until the next interrupt, so a handful is plenty
+    for _ in 0..100 {
+        if ticks() >= start + 2 {
+            return;
+        }
+        x86_64::instructions::hlt();
+    }
+    panic!("timer did not tick: {} -> {}", start, ticks());
+}
+
+#[test_case]
+fn scancode_reaches_stream() {
+    use core::task::{Context, Poll};
+    use futures_util::{stream::StreamExt, task::noop_waker_ref};
+    use os_practice::interrupts::run_as_handler;
+    use os_practice::task::keyboard::{add_scancode, ScancodeStream};
+
+    let mut stream = ScancodeStream::new();
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Pending);
+
+    // same path the keyboard interrupt handler takes
+    run_as_handler(|| add_scancode(0x1e));
+    assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Ready(Some(0x1e)));
+    assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Pending);
+}
+
+// runs after scancode_reaches_stream so the queue is already set up, the
+// WRITER lock stands in for a println! the interrupt landed in the middle of
+#[test_case]
+fn full_queue_with_writer_locked() {
+    use os_practice::interrupts::run_as_handler;
+    use os_practice::task::keyboard::{
+        add_scancode, drain_scancodes, dropped_scancodes, SCANCODE_QUEUE_CAPACITY,
+    };
+    use os_practice::vga_buf::WRITER;
+
+    let before = dropped_scancodes();
+    run_as_handler(|| {
+        // a key press could have landed in the queue since
+        drain_scancodes();
+        let _writer = WRITER.lock();
+        // one more than the queue holds
+        for _ in 0..=SCANCODE_QUEUE_CAPACITY {
+            add_scancode(0x1e);
+        }
+    });
+    assert_eq!(dropped_scancodes(), before + 1);
+    drain_scancodes();
+}
@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+// nothing but the heap, so nothing else holds on to an allocation that
+// would keep set_backend() from switching
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, vec::Vec};
+use os_practice::heap::{
+    allocated_bytes, backend, secure_mode, set_backend, set_secure_mode, Backend, BackendError,
+    HEAP_SIZE,
+};
+
+// a small and a large allocation, then lots that only fit if freed memory
+// gets reused
+fn exercise_heap() {
+    let small = Box::new(41u64);
+    let large: Vec<u64> = (0..1000).collect();
+    assert_eq!(*small + 1, 42);
+    assert_eq!(large.iter().sum::<u64>(), 999 * 1000 / 2);
+    drop(small);
+    drop(large);
+
+    for i in 0..HEAP_SIZE / 512 {
+        let mut v: Vec<u8> = Vec::with_capacity(1024);
+        v.push(i as u8);
+        assert_eq!(core::hint::black_box(&v)[0], i as u8);
+    }
+    assert_eq!(allocated_bytes(), 0);
+}
+
+#[test_case]
+fn allocations_work_under_each_backend() {
+    for &kind in &[Backend::FixedBlock, Backend::Bump, Backend::LinkedList] {
+        assert_eq!(set_backend(kind), Ok(()));
+        assert_eq!(backend(), kind);
+        exercise_heap();
+    }
+}
+
+#[test_case]
+fn switch_refused_with_live_allocation() {
+    let held = Box::new([0u8; 64]);
+    assert_eq!(
+        set_backend(Backend::Bump),
+        Err(BackendError::InUse {
+            allocated: allocated_bytes()
+        })
+    );
+    assert_eq!(backend(), Backend::LinkedList);
+    drop(held);
+    assert_eq!(set_backend(Backend::LinkedList), Ok(()));
+}
+
+// a switch must not quietly turn secure mode off
+#[test_case]
+fn switch_keeps_secure_mode() {
+    set_secure_mode(true);
+    assert_eq!(set_backend(Backend::LinkedList), Ok(()));
+    assert!(secure_mode());
+    assert_eq!(set_backend(Backend::Bump), Err(BackendError::NotSecure));
+    assert_eq!(backend(), Backend::LinkedList);
+    assert!(secure_mode());
+    set_secure_mode(false);
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
 // import test_runner from lib.rs
 #![test_runner(os_practice::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -15,7 +16,11 @@ entry_point!(kern_main);
 fn kern_main(boot_info: &'static BootInfo) -> ! {
     use x86_64::VirtAddr;
 
-    os_practice::init();
+    os_practice::init().expect("kernel initialization failed");
+    // printing can't depend on the heap
+    assert!(!os_practice::heap::is_heap_ready());
+    os_practice::println!("heap_test output before init_heap");
+
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
     let mut frame_alloc =
@@ -59,3 +64,154 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+use alloc::vec::Vec;
+use os_practice::rand::RngState;
+#[test_case]
+fn fragmented_frees_coalesce() {
+    // fixed seed so a failure always reproduces the same way
+    let mut rng = RngState::new(0x1192);
+    let mut blocks: Vec<(*mut u8, Layout)> = Vec::with_capacity(512);
+
+    // fill the heap with variable sized blocks until it runs out
+    while blocks.len() < blocks.capacity() {
+        let size = rng.random_range(64, 1024) as usize;
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            break;
+        }
+        blocks.push((ptr, layout));
+    }
+    assert!(
+        blocks.len() < blocks.capacity(),
+        "heap didn't fill up, the test needs more blocks"
+    );
+
+    // free in a scrambled order (Fisher-Yates), never LIFO
+    for i in (1..blocks.len()).rev() {
+        let j = rng.random_range(0, i as u64 + 1) as usize;
+        blocks.swap(i, j);
+    }
+    for &(ptr, layout) in blocks.iter() {
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+    }
+
+    // far bigger than any block that was freed, only fits if the freed
+    // blocks were merged back together
+    let big = Layout::from_size_align(HEAP_SIZE / 2, 8).unwrap();
+    let ptr = unsafe { alloc(big) };
+    assert!(!ptr.is_null(), "freed blocks weren't coalesced");
+    unsafe { alloc::alloc::dealloc(ptr, big) };
+}
+
+use os_practice::println;
+#[test_case]
+fn print_after_heap_init() {
+    assert!(os_practice::heap::is_heap_ready());
+    println!("print_after_heap_init output");
+}
+
+use os_practice::vga_buf::{BUFFER_HEIGHT, WRITER};
+#[test_case]
+fn screen_rows_text() {
+    use x86_64::instructions::interrupts;
+    println!("screen_rows_text output");
+    interrupts::without_interrupts(|| {
+        let rows: alloc::vec::Vec<_> = WRITER.lock().rows_text().collect();
+        assert_eq!(rows.len(), BUFFER_HEIGHT);
+        assert_eq!(rows[BUFFER_HEIGHT - 2], "screen_rows_text output");
+        assert_eq!(rows[BUFFER_HEIGHT - 1], "");
+    });
+}
+
+use alloc::alloc::{alloc, Layout};
+#[test_case]
+fn huge_layout_returns_null() {
+    // valid on its own but padding it to the allocator's alignment overflows
+    let layout = Layout::from_size_align(isize::MAX as usize - 2, 1).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(ptr.is_null());
+}
+
+use os_practice::heap::Heap;
+#[test_case]
+fn second_heap_region() {
+    // u64s so the region is 8 byte aligned for the allocator's list nodes
+    static mut POOL: [u64; 512] = [0; 512];
+
+    let start = core::ptr::addr_of_mut!(POOL) as usize;
+    let pool = unsafe { Heap::new(start, 4096) };
+    let in_pool = |addr: usize| (start..start + 4096).contains(&addr);
+
+    let mut local = alloc::vec::Vec::new_in(&pool);
+    local.extend(0..100u32);
+    let global = Box::new(7u32);
+    assert!(in_pool(local.as_ptr() as usize));
+    assert!(!in_pool(&*global as *const u32 as usize));
+    assert_eq!(local.iter().sum::<u32>(), 4950);
+
+    // the pool runs out without touching the global heap
+    assert!(pool
+        .alloc_in(Layout::from_size_align(8192, 8).unwrap())
+        .is_null());
+    drop(local);
+    let ptr = pool.alloc_in(Layout::from_size_align(2048, 8).unwrap());
+    assert!(in_pool(ptr as usize));
+    unsafe { pool.dealloc_in(ptr, Layout::from_size_align(2048, 8).unwrap()) };
+}
+
+use os_practice::heap::{linked_list::POISON, set_secure_mode};
+#[test_case]
+fn secure_mode_zeroes_freed_memory() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    // size + next pointer written by the allocator once the region is freed
+    let header = 2 * core::mem::size_of::<usize>();
+
+    set_secure_mode(true);
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    let region = unsafe { core::slice::from_raw_parts_mut(ptr, 64) };
+    assert!(region.iter().all(|&b| b == POISON));
+    region.fill(0x5a);
+
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+    set_secure_mode(false);
+
+    // nothing has been allocated since, so the region is either merged into
+    // the free region before it or starts a free list node of its own, and
+    // only that node's header can be non-zero
+    let freed = unsafe { core::slice::from_raw_parts(ptr as *const u8, 64) };
+    let node_size = unsafe { (ptr as *const usize).read() };
+    let skip = if node_size == 0 {
+        0
+    } else {
+        assert!(node_size >= 64, "free list node size");
+        header
+    };
+    assert!(freed[skip..].iter().all(|&b| b == 0));
+}
+
+use os_practice::heap::{allocated_bytes, peak_usage, reset_peak};
+#[test_case]
+fn peak_usage_tracks_maximum() {
+    reset_peak();
+    let base = allocated_bytes();
+    assert_eq!(peak_usage(), base);
+
+    let a = Box::new([1u8; 1000]);
+    let b = Box::new([2u8; 500]);
+    assert_eq!(a[999] + b[499], 3);
+    drop(a);
+    drop(b);
+    let c = Box::new([3u8; 200]);
+
+    // 500 is padded to 504, both were live at once
+    assert_eq!(peak_usage(), base + 1000 + 504);
+    assert_eq!(allocated_bytes(), base + 200);
+    drop(c);
+    assert_eq!(allocated_bytes(), base);
+
+    reset_peak();
+    assert_eq!(peak_usage(), base);
+}
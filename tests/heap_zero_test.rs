@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use os_practice::heap::guard_addrs;
+use os_practice::mem::BootInfoFrameAllocator;
+use spin::{Mutex, Once};
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+entry_point!(kern_main);
+
+const STALE: u8 = 0xa5;
+
+// fills every frame it hands out with STALE first, so a frame that doesn't
+// get zeroed is easy to tell apart from one that happened to be clean
+struct DirtyFrames {
+    inner: BootInfoFrameAllocator,
+    phys_offset: VirtAddr,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for DirtyFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.inner.allocate_frame()?;
+        let virt = self.phys_offset + frame.start_address().as_u64();
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), STALE, 4096) };
+        Some(frame)
+    }
+}
+
+static MEM: Once<Mutex<(OffsetPageTable<'static>, DirtyFrames)>> = Once::new();
+// checked before anything has had a chance to allocate
+static HEAP_WAS_ZEROED: AtomicBool = AtomicBool::new(false);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    os_practice::init().expect("kernel initialization failed");
+    let phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_offset) };
+    let mut frame_alloc = DirtyFrames {
+        inner: unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) },
+        phys_offset,
+    };
+    os_practice::heap::init_heap_zeroed(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    // skip the 8 byte canaries and the free list node at the start of the heap
+    let (below, above) = guard_addrs();
+    let start = below + 8 + 2 * core::mem::size_of::<usize>();
+    let heap = unsafe { core::slice::from_raw_parts(start as *const u8, above - start) };
+    HEAP_WAS_ZEROED.store(heap.iter().all(|&b| b == 0), Ordering::SeqCst);
+    MEM.call_once(|| Mutex::new((mapper, frame_alloc)));
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+#[test_case]
+fn heap_starts_zeroed() {
+    assert!(HEAP_WAS_ZEROED.load(Ordering::SeqCst));
+}
+
+use os_practice::mem::map_range;
+use x86_64::structures::paging::PageTableFlags;
+#[test_case]
+fn map_range_zero_option() {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let read = |addr: u64| unsafe { core::slice::from_raw_parts(addr as *const u8, 8192) };
+
+    // without the option the stale contents show through
+    let stale = 0x5555_1000_0000;
+    map_range(
+        VirtAddr::new(stale),
+        8192,
+        flags,
+        false,
+        mapper,
+        frame_alloc,
+    )
+    .unwrap();
+    assert!(read(stale).iter().all(|&b| b == STALE));
+
+    let zeroed = 0x5555_1001_0000;
+    map_range(
+        VirtAddr::new(zeroed),
+        8192,
+        flags,
+        true,
+        mapper,
+        frame_alloc,
+    )
+    .unwrap();
+    assert!(read(zeroed).iter().all(|&b| b == 0));
+}
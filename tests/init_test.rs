@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Once;
+use x86_64::VirtAddr;
+
+entry_point!(kern_main);
+
+static PHYS_MEM_OFFSET: Once<VirtAddr> = Once::new();
+
+// no heap here, the test sets it up (and fails to) itself
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    os_practice::init().expect("kernel initialization failed");
+    PHYS_MEM_OFFSET.call_once(|| VirtAddr::new(boot_info.physical_memory_offset));
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+#[test_case]
+fn subsystems_loaded() {
+    assert!(os_practice::gdt::is_loaded());
+    assert!(os_practice::interrupts::is_loaded());
+    assert!(os_practice::interrupts::pics_respond());
+}
+
+use os_practice::{mem::EmptyFrameAllocator, InitError};
+use x86_64::structures::paging::mapper::MapToError;
+#[test_case]
+fn heap_init_without_frames() {
+    let mut mapper = unsafe { os_practice::mem::init(*PHYS_MEM_OFFSET.wait().unwrap()) };
+    let err = os_practice::init_heap(&mut mapper, &mut EmptyFrameAllocator).unwrap_err();
+    assert!(matches!(
+        err,
+        InitError::Heap(MapToError::FrameAllocationFailed)
+    ));
+    assert!(!os_practice::heap::is_heap_ready());
+}
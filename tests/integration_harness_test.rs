@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+// entry point, init(), heap and panic handler
+os_practice::integration_test!(heap);
+
+#[test_case]
+fn kernel_is_initialized() {
+    assert!(os_practice::interrupts::is_loaded());
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn heap_is_ready() {
+    use alloc::vec::Vec;
+
+    assert!(os_practice::heap::is_heap_ready());
+    let nums: Vec<u32> = (0..10).collect();
+    assert_eq!(nums.iter().sum::<u32>(), 45);
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use os_practice::{exit_qemu, kassert_eq, serial_print, serial_println, QEMUExitCode};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n\nError: {}\n", info);
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+const EXPECTED_PREFIX: &str = "KASSERT FAILED at tests/kassert_test.rs:";
+const EXPECTED_SUFFIX: &str = ": `1 + 1 == 3`\n  left: 2\n right: 3";
+
+// collects the message without needing the heap
+struct Message {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+fn check_message(msg: &fmt::Arguments) {
+    let mut out = Message {
+        buf: [0; 128],
+        len: 0,
+    };
+    let fits = write!(out, "{}", msg).is_ok();
+    let msg = core::str::from_utf8(&out.buf[..out.len]).unwrap_or("");
+    if fits && msg.starts_with(EXPECTED_PREFIX) && msg.ends_with(EXPECTED_SUFFIX) {
+        serial_println!("[ok]");
+        exit_qemu(QEMUExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nunexpected message: {}", msg);
+        exit_qemu(QEMUExitCode::Failure);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    os_practice::kassert::set_hook(check_message);
+    serial_println!("Running 1 tests:");
+    failing_kassert_eq();
+    serial_println!("[kassert did not fire]");
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+fn failing_kassert_eq() {
+    serial_print!("kassert_test::failing_kassert_eq...\t");
+    kassert_eq!(1 + 1, 3);
+}
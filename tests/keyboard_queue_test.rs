@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+// only the heap, the scancode queue lives there, and no interrupts so a
+// real key press can't end up in the queue
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use core::task::{Context, Poll};
+use futures_util::{stream::StreamExt, task::noop_waker_ref};
+use os_practice::interrupts::run_as_handler;
+use os_practice::task::keyboard::{
+    add_scancode, drain_scancodes, dropped_scancodes, set_full_policy, FullPolicy, ScancodeStream,
+    SCANCODE_QUEUE_CAPACITY,
+};
+
+// the scancodes themselves count up from 0, so the capacity plus a few
+// extra has to fit in one
+const CAPACITY: u8 = SCANCODE_QUEUE_CAPACITY as u8;
+
+// overfill the queue with 0, 1, 2, ... then read back what survived
+fn overfill(stream: &mut ScancodeStream, extra: u8) -> (u8, u8, usize) {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    drain_scancodes();
+    let before = dropped_scancodes();
+    run_as_handler(|| {
+        for scancode in 0..CAPACITY + extra {
+            add_scancode(scancode);
+        }
+    });
+    let dropped = dropped_scancodes() - before;
+
+    let mut first = None;
+    let mut last = 0;
+    let mut count = 0;
+    while let Poll::Ready(Some(scancode)) = stream.poll_next_unpin(&mut cx) {
+        first.get_or_insert(scancode);
+        last = scancode;
+        count += 1;
+    }
+    assert_eq!(count, CAPACITY);
+    (first.unwrap(), last, dropped)
+}
+
+// ScancodeStream::new() only works once per boot, so both policies share it
+#[test_case]
+fn full_queue_policies() {
+    let mut stream = ScancodeStream::new();
+
+    // the first CAPACITY stay, the last 5 never make it in
+    set_full_policy(FullPolicy::DropNewest);
+    assert_eq!(overfill(&mut stream, 5), (0, CAPACITY - 1, 5));
+
+    // the last CAPACITY stay, the first 5 are pushed out
+    set_full_policy(FullPolicy::DropOldest);
+    assert_eq!(overfill(&mut stream, 5), (5, CAPACITY + 4, 5));
+    set_full_policy(FullPolicy::DropNewest);
+}
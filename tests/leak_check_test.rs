@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os_practice::{exit_qemu, serial_print, serial_println, QEMUExitCode};
+
+const EXPECTED: &str = "heap leak";
+
+// a panic from init() or the executor isn't the leak check firing
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::expected_panic_handler(info, EXPECTED)
+}
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    detects_leak();
+    serial_println!("[leak not detected]");
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// a task that forgets the Vec it allocated, the leak check has to panic
+fn detects_leak() {
+    use alloc::vec::Vec;
+    use os_practice::heap::{assert_no_leaks_since, checkpoint};
+    use os_practice::task::{exec::Exec, Task};
+    serial_print!("leak_check_test::detects_leak...\t");
+
+    let start = checkpoint();
+    {
+        let mut exec = Exec::new();
+        exec.spawn(Task::new(async {
+            let nums: Vec<u64> = (0..16).collect();
+            core::mem::forget(nums);
+        }));
+        while exec.has_pending() {
+            exec.step();
+        }
+    }
+    assert_no_leaks_since(start);
+}
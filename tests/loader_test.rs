@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use os_practice::mem::BootInfoFrameAllocator;
+use spin::{Mutex, Once};
+use x86_64::structures::paging::OffsetPageTable;
+
+entry_point!(kern_main);
+
+// the loader needs both to map the program in
+static MEM: Once<Mutex<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Once::new();
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+    MEM.call_once(|| Mutex::new((mapper, frame_alloc)));
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use os_practice::loader::{load_elf, LoadError};
+use x86_64::structures::paging::{
+    mapper::{Translate, TranslateResult},
+    PageTableFlags,
+};
+use x86_64::VirtAddr;
+
+const TEXT: u64 = 0x2000_0000_0000;
+const DATA: u64 = TEXT + 0x1000;
+const CODE: [u8; 4] = [0x90, 0x90, 0xeb, 0xfe]; // nop; nop; jmp $
+const DATA_BYTES: [u8; 4] = [1, 2, 3, 4];
+
+/*
+    a tiny ELF with 2 segments:
+    - TEXT: R+X, just CODE
+    - DATA: R+W, DATA_BYTES followed by 2 pages worth of BSS
+*/
+fn tiny_elf(text: u64) -> Vec<u8> {
+    fn phdr(elf: &mut Vec<u8>, flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64) {
+        elf.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        elf.extend_from_slice(&flags.to_le_bytes());
+        elf.extend_from_slice(&offset.to_le_bytes());
+        elf.extend_from_slice(&vaddr.to_le_bytes());
+        elf.extend_from_slice(&vaddr.to_le_bytes());
+        elf.extend_from_slice(&filesz.to_le_bytes());
+        elf.extend_from_slice(&memsz.to_le_bytes());
+        elf.extend_from_slice(&0x1000u64.to_le_bytes()); // p_align
+    }
+
+    let mut elf = Vec::new();
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&0x3eu16.to_le_bytes()); // x86_64
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&text.to_le_bytes()); // e_entry
+    elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+    elf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+    elf.extend_from_slice(&2u16.to_le_bytes()); // e_phnum
+    elf.extend_from_slice(&[0; 6]);
+    assert_eq!(elf.len(), 64);
+
+    let code_off = 64 + 2 * 56;
+    let data_off = code_off + CODE.len() as u64;
+    phdr(&mut elf, 0b101, code_off, text, 4, 4);
+    phdr(&mut elf, 0b110, data_off, text + 0x1000, 4, 0x2004);
+    elf.extend_from_slice(&CODE);
+    elf.extend_from_slice(&DATA_BYTES);
+    elf
+}
+
+fn flags_at(mapper: &OffsetPageTable, addr: u64) -> PageTableFlags {
+    match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:#x} not mapped", addr),
+    }
+}
+
+#[test_case]
+fn load_tiny_elf() {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+
+    let entry = load_elf(&tiny_elf(TEXT), mapper, frame_alloc).unwrap();
+    assert_eq!(entry, VirtAddr::new(TEXT));
+
+    let text = flags_at(mapper, TEXT);
+    assert!(text.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE));
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+    for page in 0..3 {
+        let data = flags_at(mapper, DATA + page * 0x1000);
+        assert!(data.contains(
+            PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+        ));
+    }
+
+    // file data copied in, the BSS after it zeroed
+    let code = unsafe { core::slice::from_raw_parts(TEXT as *const u8, 4) };
+    assert_eq!(code, CODE);
+    let data = unsafe { core::slice::from_raw_parts(DATA as *const u8, 0x2004) };
+    assert_eq!(data[..4], DATA_BYTES);
+    assert!(data[4..].iter().all(|&b| b == 0));
+}
+
+#[test_case]
+fn reject_bad_elfs() {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+
+    let mut not_elf = tiny_elf(TEXT);
+    not_elf[0] = 0;
+    assert!(matches!(
+        load_elf(&not_elf, mapper, frame_alloc),
+        Err(LoadError::NotElf)
+    ));
+    assert!(matches!(
+        load_elf(&tiny_elf(TEXT)[..100], mapper, frame_alloc),
+        Err(LoadError::Truncated)
+    ));
+    // entry point in the data segment, nothing gets mapped
+    let mut bad_entry = tiny_elf(TEXT + 0x10_0000);
+    bad_entry[0x18..0x20].copy_from_slice(&(TEXT + 0x10_1000).to_le_bytes());
+    assert!(matches!(
+        load_elf(&bad_entry, mapper, frame_alloc),
+        Err(LoadError::BadEntry)
+    ));
+    assert!(!os_practice::mem::is_mapped(
+        VirtAddr::new(TEXT + 0x10_0000),
+        mapper
+    ));
+}
@@ -0,0 +1,212 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use os_practice::mem::BootInfoFrameAllocator;
+use spin::{Mutex, Once};
+use x86_64::structures::paging::OffsetPageTable;
+
+entry_point!(kern_main);
+
+// the test cases map pages themselves
+static MEM: Once<Mutex<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Once::new();
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+    MEM.call_once(|| Mutex::new((mapper, frame_alloc)));
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::heap::HEAP_START;
+use os_practice::mem::{try_map_page, MapPageError};
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+fn page(addr: u64) -> Page {
+    Page::containing_address(VirtAddr::new(addr))
+}
+
+fn vga_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(0xb8000))
+}
+
+#[test_case]
+fn map_already_mapped_page() {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+    assert!(matches!(
+        try_map_page(
+            page(HEAP_START as u64),
+            vga_frame(),
+            FLAGS,
+            mapper,
+            frame_alloc
+        ),
+        Err(MapPageError::AlreadyMapped)
+    ));
+}
+
+#[test_case]
+fn map_handed_out_frame() {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+    let frame = frame_alloc.allocate_frame().unwrap();
+    assert!(matches!(
+        try_map_page(page(0x5555_0000_0000), frame, FLAGS, mapper, frame_alloc),
+        Err(MapPageError::FrameInUse)
+    ));
+    assert!(!os_practice::mem::is_mapped(
+        VirtAddr::new(0x5555_0000_0000),
+        mapper
+    ));
+}
+
+#[test_case]
+fn map_fresh_page() {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+    let addr = 0x5555_0000_1000;
+    try_map_page(page(addr), vga_frame(), FLAGS, mapper, frame_alloc).unwrap();
+    assert!(os_practice::mem::is_mapped(VirtAddr::new(addr), mapper));
+
+    // a second mapping of the same page is refused
+    assert!(matches!(
+        try_map_page(page(addr), vga_frame(), FLAGS, mapper, frame_alloc),
+        Err(MapPageError::AlreadyMapped)
+    ));
+}
+
+#[test_case]
+fn mappings_include_new_pages() {
+    use os_practice::mem::iter_mappings;
+
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+    let writable = page(0x5555_0000_2000);
+    let read_only = page(0x5555_0000_3000);
+    let second_frame = PhysFrame::containing_address(PhysAddr::new(0xb9000));
+    try_map_page(writable, vga_frame(), FLAGS, mapper, frame_alloc).unwrap();
+    try_map_page(
+        read_only,
+        second_frame,
+        PageTableFlags::PRESENT,
+        mapper,
+        frame_alloc,
+    )
+    .unwrap();
+
+    let mut found = 0;
+    let mut last = None;
+    for (addr, frame, flags) in iter_mappings(mapper) {
+        // sorted, and each mapping only once
+        assert!(last < Some(addr), "{:?} after {:?}", addr, last);
+        last = Some(addr);
+
+        // whatever the CPU set on its own doesn't matter
+        let flags = flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+        if addr == writable.start_address() {
+            assert_eq!((frame, flags), (vga_frame(), FLAGS));
+            found += 1;
+        } else if addr == read_only.start_address() {
+            assert_eq!((frame, flags), (second_frame, PageTableFlags::PRESENT));
+            found += 1;
+        }
+    }
+    assert_eq!(found, 2);
+}
+
+use os_practice::vga_buf::{Buffer, VGA_PHYS, WRITER};
+use x86_64::structures::paging::Translate;
+#[test_case]
+fn vga_buffer_from_phys() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+    let vga = PhysAddr::new(VGA_PHYS);
+
+    // the early WRITER relies on the identity mapping
+    let early = without_interrupts(|| WRITER.lock().buffer_addr());
+    assert_eq!(mapper.translate_addr(early), Some(vga));
+
+    // the only other reference is WRITER's, which set_buffer() drops below
+    let buf = unsafe { Buffer::from_phys(vga, mapper, frame_alloc) }.unwrap();
+    let addr = VirtAddr::from_ptr(buf as *const Buffer);
+    assert_eq!(addr, mapper.phys_offset() + VGA_PHYS);
+    assert_eq!(mapper.translate_addr(addr), Some(vga));
+
+    without_interrupts(move || {
+        let mut writer = WRITER.lock();
+        writer.set_buffer(buf);
+        assert_eq!(writer.buffer_addr(), addr);
+        writer.write_string("through the offset mapping\n");
+        writer.flush();
+    });
+}
+
+use os_practice::heap::HEAP_SIZE;
+use os_practice::mem::{translate_verbose, TranslateFault};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+#[test_case]
+fn translate_verbose_reports_where_it_stopped() {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, _) = &mut *mem;
+
+    let heap = VirtAddr::new(HEAP_START as u64 + 0x123);
+    let expected = mapper.translate_addr(heap).unwrap();
+    assert_eq!(translate_verbose(heap.as_u64(), mapper), Ok(expected));
+
+    // the heap's P1 table only covers HEAP_SIZE of its 2 MiB
+    let past_heap = (HEAP_START + HEAP_SIZE) as u64;
+    assert_eq!(
+        translate_verbose(past_heap, mapper),
+        Err(TranslateFault::NotPresentAtLevel(1))
+    );
+    // nothing in this P4 slot at all
+    assert_eq!(
+        translate_verbose(0x7000_0000_0000, mapper),
+        Err(TranslateFault::NotPresentAtLevel(4))
+    );
+    assert_eq!(
+        translate_verbose(0x0000_8000_0000_0000, mapper),
+        Err(TranslateFault::NonCanonical)
+    );
+
+    // the bootloader maps physical memory with huge pages
+    let phys = mapper.phys_offset() + 0x20_1234u64;
+    assert!(matches!(
+        mapper.translate(phys),
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(_) | MappedFrame::Size1GiB(_),
+            ..
+        }
+    ));
+    assert_eq!(
+        translate_verbose(phys.as_u64(), mapper),
+        Err(TranslateFault::HugePage(PhysAddr::new(0x20_1234)))
+    );
+}
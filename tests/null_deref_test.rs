@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use os_practice::{exit_qemu, serial_print, serial_println};
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[panicked but did not page fault]");
+    exit_qemu(os_practice::QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// TEST_IDT's page fault handler checks CR2 and exits QEMU
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    // the page fault handler runs on an IST stack, which needs the TSS
+    os_practice::gdt::init();
+    os_practice::interrupts::init_test();
+    serial_println!("Running 1 tests:");
+    test_null_deref();
+    serial_println!("[did not page fault]");
+    exit_qemu(os_practice::QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+fn test_null_deref() {
+    use os_practice::null_deref;
+    serial_print!("null_deref_test::test_null_deref...\t");
+    // the first page is never mapped
+    null_deref();
+}
@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use os_practice::{serial_print, serial_println, PanicAction, QEMUExitCode};
+
+// only exits QEMU if the configured action is consulted, otherwise the default
+// Halt action hangs until the test times out
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    os_practice::run_panic_action();
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_action::exit_qemu_on_panic...\t");
+    os_practice::set_panic_action(PanicAction::ExitQemu(QEMUExitCode::Success));
+    assert_eq!(
+        os_practice::panic_action(),
+        PanicAction::ExitQemu(QEMUExitCode::Success)
+    );
+    panic!("forced panic");
+}
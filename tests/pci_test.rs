@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::drivers::pci;
+// QEMU's default i440FX machine always has these two
+#[test_case]
+fn finds_host_bridge_and_ide() {
+    let devices = pci::enumerate();
+    assert!(!devices.is_empty());
+    // class 0x06 (bridge), subclass 0x00 (host bridge)
+    assert!(pci::find(0x06, 0x00).is_some(), "no host bridge");
+    // class 0x01 (mass storage), subclass 0x01 (IDE)
+    let ide = pci::find(0x01, 0x01).expect("no IDE controller");
+    assert_ne!(ide.vendor_id, 0xffff);
+}
+
+use os_practice::mmio::{read_port_u16, read_port_u32, read_port_u8, write_port_u32};
+// the host bridge (00:00.0) is an Intel 440FX, vendor 0x8086 device 0x1237,
+// each width of read sees exactly its part of the register
+#[test_case]
+fn config_data_read_widths() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    let (dword, word_lo, word_hi, byte) = without_interrupts(|| unsafe {
+        write_port_u32(0xcf8, 0x8000_0000);
+        (
+            read_port_u32(0xcfc),
+            read_port_u16(0xcfc),
+            read_port_u16(0xcfe),
+            read_port_u8(0xcfd),
+        )
+    });
+    assert_eq!(dword, 0x1237_8086);
+    assert_eq!(word_lo, 0x8086);
+    assert_eq!(word_hi, 0x1237);
+    assert_eq!(byte, 0x80);
+}
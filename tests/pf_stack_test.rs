@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use os_practice::interrupts::{expect_pg_fault, ExpectedPgFault};
+use os_practice::{exit_qemu, serial_print, serial_println};
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[panicked but did not page fault]");
+    exit_qemu(os_practice::QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// TEST_IDT's page fault handler checks it's on the IST stack and exits QEMU,
+// TEST_IDT has no double fault handler so without the IST this triple faults
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    // the TSS with the page fault stack has to be loaded
+    os_practice::gdt::init();
+    os_practice::interrupts::init_test();
+    serial_println!("Running 1 tests:");
+    test_fault_on_exhausted_stack();
+    serial_println!("[did not page fault]");
+    exit_qemu(os_practice::QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+fn test_fault_on_exhausted_stack() {
+    serial_print!("pf_stack_test::test_fault_on_exhausted_stack...\t");
+    expect_pg_fault(ExpectedPgFault::BadStack);
+    // as if the stack had run all the way down into an unmapped page, the
+    // push faults and the CPU can't push the exception frame here either
+    unsafe {
+        core::arch::asm!("mov rsp, 0x1000", "push rax", options(noreturn));
+    }
+}
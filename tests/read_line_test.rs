@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use futures_util::FutureExt;
+use os_practice::interrupts::run_as_handler;
+use os_practice::task::keyboard::{add_scancode, read_line, KeyStream};
+#[test_case]
+fn backspace_edits_line() {
+    let mut keys = KeyStream::new();
+    // scancode set 1 make codes for: a, b, backspace, c, enter
+    run_as_handler(|| {
+        for &scancode in &[0x1e, 0x30, 0x0e, 0x2e, 0x1c] {
+            add_scancode(scancode);
+        }
+    });
+    // everything is already queued so the line is ready on the first poll
+    let line = read_line(&mut keys)
+        .now_or_never()
+        .expect("read_line did not complete");
+    assert_eq!(line, "ac");
+}
+
+use os_practice::task::serial::{add_byte, SerialKeys, SerialStream};
+#[test_case]
+fn serial_backspace_edits_line() {
+    let mut keys = SerialKeys::new(SerialStream::new());
+    // same bytes the COM1 interrupt handler would push for a terminal
+    // sending a, b, DEL, c, CR
+    run_as_handler(|| {
+        for &byte in b"ab\x7fc\r" {
+            add_byte(byte);
+        }
+    });
+    let line = read_line(&mut keys)
+        .now_or_never()
+        .expect("read_line did not complete");
+    assert_eq!(line, "ac");
+}
+
+#[test_case]
+fn serial_crlf_is_one_enter() {
+    let bytes = futures_util::stream::iter(b"x\r\ny\n".iter().copied());
+    let mut keys = SerialKeys::new(bytes);
+    let first = read_line(&mut keys).now_or_never().unwrap();
+    let second = read_line(&mut keys).now_or_never().unwrap();
+    assert_eq!((first.as_str(), second.as_str()), ("x", "y"));
+}
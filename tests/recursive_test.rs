@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use spin::{Mutex, Once};
+use x86_64::structures::paging::OffsetPageTable;
+
+entry_point!(kern_main);
+
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+    MAPPER.call_once(|| Mutex::new(mapper));
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use alloc::boxed::Box;
+use os_practice::mem::{init_recursive, recursive_entry_installed};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+/*
+    the bootloader only maps physical memory at an offset, so point a free
+    P4 entry back at the P4 table to get both mappers over the same tables
+*/
+#[test_case]
+fn recursive_and_offset_agree() {
+    let mut mapper = MAPPER.wait().unwrap().lock();
+    let offset = mapper.phys_offset();
+    let index = (256..512u16)
+        .rev()
+        .find(|&i| mapper.level_4_table()[i as usize].is_unused())
+        .expect("no free P4 entry");
+    assert!(!unsafe { recursive_entry_installed(index, offset) });
+
+    let (lvl4_table_frame, _) = Cr3::read();
+    mapper.level_4_table()[index as usize].set_frame(
+        lvl4_table_frame,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    );
+    os_practice::mem::flush_all();
+    assert!(unsafe { recursive_entry_installed(index, offset) });
+
+    let recursive = unsafe { init_recursive(index) };
+    let on_heap = Box::new(0u64);
+    let addrs = [
+        VirtAddr::from_ptr(&*on_heap),
+        VirtAddr::new(0xb8000),
+        VirtAddr::new(os_practice::hlt_loop as fn() -> ! as usize as u64),
+        VirtAddr::new(0xdead_b000),
+    ];
+    for addr in addrs {
+        assert_eq!(recursive.translate_addr(addr), mapper.translate_addr(addr));
+    }
+    assert!(recursive.translate_addr(addrs[0]).is_some());
+
+    mapper.level_4_table()[index as usize].set_unused();
+    os_practice::mem::flush_all();
+}
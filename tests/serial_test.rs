@@ -0,0 +1,30 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::mmio::read_port_u8;
+use os_practice::serial::{base, loopback_test};
+#[test_case]
+fn uart_loopback() {
+    let modem_ctrl = unsafe { read_port_u8(base() + 4) };
+    assert!(loopback_test());
+    // back out of loopback mode, so the test runner's [ok] still shows up
+    assert_eq!(unsafe { read_port_u8(base() + 4) }, modem_ctrl);
+    assert!(loopback_test());
+}
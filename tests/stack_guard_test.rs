@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use os_practice::{exit_qemu, serial_print, serial_println, QEMUExitCode};
+
+const EXPECTED: &str = "STACK SMASHING DETECTED";
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::expected_panic_handler(info, EXPECTED)
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    detects_smashed_canary();
+    serial_println!("[canary check did not fire]");
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+fn detects_smashed_canary() {
+    use os_practice::stack_guard::{check_canary, place_canary};
+    serial_print!("stack_guard_test::detects_smashed_canary...\t");
+
+    let mut region = [0u64; 4];
+    let addr = region.as_mut_ptr() as usize;
+    unsafe { place_canary(addr) };
+    // intact canary passes
+    check_canary(addr, "test");
+
+    // simulate an overrun trampling it
+    unsafe { core::ptr::write_volatile(&mut region[0], !region[0]) };
+    check_canary(addr, "test");
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use os_practice::mem::BootInfoFrameAllocator;
+use spin::{Mutex, Once};
+use x86_64::structures::paging::OffsetPageTable;
+
+entry_point!(kern_main);
+
+// the test maps the user program in itself
+static MEM: Once<Mutex<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Once::new();
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    MEM.call_once(|| Mutex::new((mapper, frame_alloc)));
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::syscall::{self, enter_user};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+const USER_CODE: u64 = 0x3000_0000_0000;
+const USER_STACK: u64 = USER_CODE + 0x10_000;
+
+/*
+    SYS_PING(41) and exit with (cs << 32) | the result, the CS it reads
+    after the sysret shows it really came back to ring 3
+
+    mov eax, 0 (SYS_PING); mov edi, 41; syscall
+    mov rdi, rax; mov eax, cs; shl rax, 32; or rdi, rax
+    mov eax, 1 (SYS_EXIT); syscall
+    jmp $
+*/
+const PROGRAM: [u8; 33] = [
+    0xb8, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x29, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x89, 0xc7, 0x8c,
+    0xc8, 0x48, 0xc1, 0xe0, 0x20, 0x48, 0x09, 0xc7, 0xb8, 0x01, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xeb,
+    0xfe,
+];
+
+// one user page at `addr` holding `bytes`, written through the physical
+// memory mapping so the page itself can stay read only
+fn map_user_page(addr: u64, bytes: &[u8], flags: PageTableFlags) {
+    let mut mem = MEM.wait().unwrap().lock();
+    let (mapper, frame_alloc) = &mut *mem;
+    let page = Page::containing_address(VirtAddr::new(addr));
+    let frame = frame_alloc.allocate_frame().unwrap();
+    let parent_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let dest: *mut u8 = (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe {
+        core::ptr::write_bytes(dest, 0, 4096);
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), dest, bytes.len());
+        mapper
+            .map_to_with_table_flags(
+                page,
+                frame,
+                flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+                parent_flags,
+                frame_alloc,
+            )
+            .unwrap()
+            .flush();
+    }
+}
+
+#[test_case]
+fn syscall_from_ring3() {
+    map_user_page(USER_CODE, &PROGRAM, PageTableFlags::empty());
+    map_user_page(
+        USER_STACK,
+        &[],
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    );
+
+    let before = syscall::handled();
+    let exit = unsafe { enter_user(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK + 4096)) };
+    // SYS_EXIT isn't counted, so just the ping
+    assert_eq!(syscall::handled(), before + 1);
+    assert_eq!(exit as u32, 42);
+    assert_eq!(
+        (exit >> 32) as u16,
+        os_practice::gdt::user_code_selector().0
+    );
+    assert_eq!((exit >> 32) & 3, 3);
+}
@@ -0,0 +1,369 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kern_main);
+
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os_practice::init().expect("kernel initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc =
+        unsafe { os_practice::mem::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    os_practice::heap::init_heap(&mut mapper, &mut frame_alloc)
+        .expect("Heap initialization failed");
+
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use alloc::collections::BTreeSet;
+use os_practice::task::{exec::Exec, Task};
+#[test_case]
+fn unique_task_ids() {
+    let n = 1000;
+    let mut ids = BTreeSet::new();
+    for _ in 0..n {
+        let task = Task::new(async {});
+        assert!(ids.insert(task.id()), "duplicate task ID: {:?}", task.id());
+    }
+    assert_eq!(ids.len(), n);
+
+    // spawning fresh tasks never trips the duplicate check
+    let mut exec = Exec::new();
+    for _ in 0..50 {
+        exec.spawn(Task::new(async {}));
+    }
+}
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+// returns Pending `n` times, waking itself straight away each time
+struct YieldTimes(usize);
+
+impl Future for YieldTimes {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 == 0 {
+            return Poll::Ready(());
+        }
+        self.0 -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn step_self_waking_task() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut exec = Exec::new();
+    assert!(!exec.has_pending());
+    exec.spawn(Task::new(async {
+        YieldTimes(3).await;
+        DONE.store(true, Ordering::SeqCst);
+    }));
+    assert!(exec.has_pending());
+
+    // one poll per step: 3 yields + the one that finishes
+    let mut steps = 0;
+    while exec.has_pending() {
+        assert!(steps < 10, "task didn't finish within 10 steps");
+        assert_eq!(exec.step(), 1);
+        steps += 1;
+    }
+    assert_eq!(steps, 4);
+    assert!(DONE.load(Ordering::SeqCst));
+    assert_eq!(exec.step(), 0);
+}
+
+use core::sync::atomic::AtomicUsize;
+#[test_case]
+fn cancelled_task_cleans_up_once() {
+    static CLEANUPS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut exec = Exec::new();
+    let task = Task::new_with_cleanup(core::future::pending(), || {
+        CLEANUPS.fetch_add(1, Ordering::SeqCst);
+    });
+    let id = task.id();
+    exec.spawn(task);
+    exec.step();
+    assert_eq!(CLEANUPS.load(Ordering::SeqCst), 0);
+
+    assert!(exec.cancel(id));
+    assert_eq!(CLEANUPS.load(Ordering::SeqCst), 1);
+    assert!(!exec.has_pending());
+
+    // already gone, nothing runs again
+    assert!(!exec.cancel(id));
+    exec.step();
+    assert_eq!(CLEANUPS.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn finished_task_cleans_up_once() {
+    static CLEANUPS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut exec = Exec::new();
+    exec.spawn(Task::new_with_cleanup(YieldTimes(1), || {
+        CLEANUPS.fetch_add(1, Ordering::SeqCst);
+    }));
+    while exec.has_pending() {
+        exec.step();
+    }
+    assert_eq!(CLEANUPS.load(Ordering::SeqCst), 1);
+}
+
+use core::future::{pending, ready};
+use os_practice::task::select::{select2, Either};
+#[test_case]
+fn select2_ready_wins() {
+    use core::sync::atomic::AtomicU8;
+    // 1: left, 2: right, 3: left on a tie
+    static WINNERS: [AtomicU8; 3] = [AtomicU8::new(0), AtomicU8::new(0), AtomicU8::new(0)];
+
+    let mut exec = Exec::new();
+    exec.spawn(Task::new(async {
+        if let Either::Left(1) = select2(ready(1), pending::<u8>()).await {
+            WINNERS[0].store(1, Ordering::SeqCst);
+        }
+    }));
+    exec.spawn(Task::new(async {
+        if let Either::Right(2) = select2(pending::<u8>(), ready(2)).await {
+            WINNERS[1].store(2, Ordering::SeqCst);
+        }
+    }));
+    exec.spawn(Task::new(async {
+        if let Either::Left(3) = select2(ready(3), ready(4)).await {
+            WINNERS[2].store(3, Ordering::SeqCst);
+        }
+    }));
+    exec.step();
+    assert!(!exec.has_pending());
+    for (i, winner) in WINNERS.iter().enumerate() {
+        assert_eq!(winner.load(Ordering::SeqCst) as usize, i + 1);
+    }
+}
+
+use core::sync::atomic::AtomicU64;
+use os_practice::task::delay::delay_ticks_with;
+#[test_case]
+fn delay_ticks_finishes_on_time() {
+    static MOCK_TICKS: AtomicU64 = AtomicU64::new(100);
+    static DONE_AT: AtomicU64 = AtomicU64::new(0);
+    fn mock_ticks() -> u64 {
+        MOCK_TICKS.load(Ordering::SeqCst)
+    }
+
+    let mut exec = Exec::new();
+    exec.spawn(Task::new(async {
+        delay_ticks_with(3, mock_ticks).await;
+        DONE_AT.store(mock_ticks(), Ordering::SeqCst);
+    }));
+
+    // the task keeps itself queued, one tick per step
+    for _ in 0..10 {
+        assert_eq!(exec.step(), 1);
+        if !exec.has_pending() {
+            break;
+        }
+        MOCK_TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+    assert!(!exec.has_pending(), "delay didn't finish within 10 ticks");
+    assert_eq!(DONE_AT.load(Ordering::SeqCst), 103);
+}
+
+use os_practice::task::block_on;
+#[test_case]
+fn block_on_self_waking_future() {
+    let value = block_on(async {
+        YieldTimes(3).await;
+        42
+    });
+    assert_eq!(value, 42);
+}
+
+use os_practice::interrupts::{free_irq, request_irq};
+use os_practice::task::Event;
+#[test_case]
+fn event_notified_from_interrupt() {
+    static READY: Event = Event::new();
+    static WOKE: AtomicBool = AtomicBool::new(false);
+    fn notify_ready() {
+        READY.notify();
+    }
+
+    let mut exec = Exec::new();
+    exec.spawn(Task::new(async {
+        READY.wait().await;
+        WOKE.store(true, Ordering::SeqCst);
+    }));
+    assert_eq!(exec.step(), 1);
+    // parked on the event, nothing queued until it fires
+    assert!(exec.has_pending());
+    assert_eq!(exec.step(), 0);
+
+    // IRQ 5 through the routing table, i.e. notify() runs in the handler
+    request_irq(5, notify_ready).unwrap();
+    unsafe { core::arch::asm!("int 0x25") };
+    free_irq(5);
+
+    assert_eq!(exec.step(), 1);
+    assert!(WOKE.load(Ordering::SeqCst));
+    assert!(!exec.has_pending());
+
+    // already set, a later wait doesn't block
+    os_practice::task::block_on(READY.wait());
+}
+
+#[test_case]
+fn task_spawns_task() {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut exec = Exec::new();
+    let spawner = exec.spawner();
+    exec.spawn(Task::new(async move {
+        spawner.spawn(Task::new(async {
+            COUNT.fetch_add(1, Ordering::SeqCst);
+        }));
+    }));
+
+    // the first step runs the parent, the child only gets picked up by the
+    // next one
+    exec.step();
+    assert_eq!(COUNT.load(Ordering::SeqCst), 0);
+    assert!(exec.has_pending());
+    while exec.has_pending() {
+        exec.step();
+    }
+    assert_eq!(COUNT.load(Ordering::SeqCst), 1);
+}
+
+use core::pin::pin;
+use os_practice::heap::{allocated_bytes, peak_usage, reset_peak};
+use os_practice::task::simple_exec::{SimpleExec, StaticTask};
+#[test_case]
+fn simple_exec_runs_without_heap() {
+    static STEPS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+    reset_peak();
+    let base = allocated_bytes();
+
+    // two different future types in the same executor
+    let first = pin!(StaticTask::new(async {
+        YieldTimes(2).await;
+        STEPS[0].fetch_add(1, Ordering::SeqCst);
+    }));
+    let second = pin!(StaticTask::new(async {
+        for _ in 0..3 {
+            YieldTimes(1).await;
+            STEPS[1].fetch_add(1, Ordering::SeqCst);
+        }
+    }));
+    let mut exec = SimpleExec::new([first, second]);
+    assert!(exec.has_pending());
+    exec.run();
+    assert!(!exec.has_pending());
+    assert_eq!(exec.step(), 0);
+
+    assert_eq!(STEPS[0].load(Ordering::SeqCst), 1);
+    assert_eq!(STEPS[1].load(Ordering::SeqCst), 3);
+    // nothing was allocated, not even for a moment
+    assert_eq!(allocated_bytes(), base);
+    assert_eq!(peak_usage(), base);
+}
+
+use os_practice::task::local::current_task;
+os_practice::task::task_local!(
+    static SLOT: u64 = 0;
+);
+#[test_case]
+fn task_locals_are_per_task() {
+    static SEEN: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+    async fn set_then_read(idx: usize, value: u64) {
+        assert_eq!(SLOT.with(|slot| *slot), 0);
+        SLOT.with(|slot| *slot = value);
+        // the other task sets its own value in the meantime
+        YieldTimes(2).await;
+        SEEN[idx].store(SLOT.with(|slot| *slot), Ordering::SeqCst);
+    }
+
+    let mut exec = Exec::new();
+    exec.spawn(Task::new(set_then_read(0, 11)));
+    exec.spawn(Task::new(set_then_read(1, 22)));
+    while exec.has_pending() {
+        exec.step();
+    }
+    assert_eq!(SEEN[0].load(Ordering::SeqCst), 11);
+    assert_eq!(SEEN[1].load(Ordering::SeqCst), 22);
+    assert_eq!(current_task(), None);
+}
+
+use alloc::vec::Vec;
+use os_practice::heap::{assert_no_leaks_since, checkpoint};
+#[test_case]
+fn task_frees_everything_it_allocates() {
+    static SUM: AtomicUsize = AtomicUsize::new(0);
+
+    let start = checkpoint();
+    {
+        // the executor's own queue and wakers are part of the checked block
+        let mut exec = Exec::new();
+        exec.spawn(Task::new(async {
+            let nums: Vec<usize> = (0..64).collect();
+            YieldTimes(1).await;
+            SUM.store(nums.iter().sum(), Ordering::SeqCst);
+        }));
+        while exec.has_pending() {
+            exec.step();
+        }
+    }
+    assert_eq!(SUM.load(Ordering::SeqCst), 63 * 64 / 2);
+    assert_no_leaks_since(start);
+}
+
+#[test_case]
+fn run_until_idle_finishes_self_waking_task() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut exec = Exec::new();
+    exec.spawn(Task::new(async {
+        YieldTimes(1).await;
+        DONE.store(true, Ordering::SeqCst);
+    }));
+    exec.run_until_idle();
+    assert!(DONE.load(Ordering::SeqCst));
+    assert!(!exec.has_pending());
+}
+
+#[test_case]
+fn run_until_idle_leaves_parked_tasks() {
+    let mut exec = Exec::new();
+    exec.spawn(Task::new(core::future::pending()));
+    exec.spawn(Task::new(YieldTimes(2)));
+    exec.run_until_idle();
+    // only the task that never gets woken is left
+    assert!(exec.has_pending());
+    assert_eq!(exec.step(), 0);
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os_practice::interrupts::{expect_pg_fault, ExpectedPgFault};
+use os_practice::{exit_qemu, serial_print, serial_println, QEMUExitCode};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+entry_point!(kern_main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n\nError: {}\n", info);
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// nothing else maps anything here
+const TEST_PAGE: u64 = 0x5555_0000_0000;
+
+// TEST_IDT's page fault handler checks the error code and exits QEMU
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    use os_practice::mem::BootInfoFrameAllocator;
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+    use x86_64::structures::paging::FrameAllocator;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE));
+    let frame = frame_alloc
+        .allocate_frame()
+        .expect("no frame for the test page");
+    // the frame came straight from frame_alloc and the page is unused
+    unsafe {
+        mapper
+            .map_to(
+                page,
+                frame,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                &mut frame_alloc,
+            )
+            .expect("mapping the test page failed")
+            .flush();
+    }
+    // ring 0 ignores a missing WRITABLE otherwise
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+
+    // the page fault handler runs on an IST stack, which needs the TSS
+    os_practice::gdt::init();
+    os_practice::interrupts::init_test();
+    serial_println!("Running 1 tests:");
+    read_only_after_flush(&mut mapper, page);
+    serial_println!("[did not page fault]");
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+fn read_only_after_flush(mapper: &mut impl Mapper<Size4KiB>, page: Page) {
+    serial_print!("tlb_test::read_only_after_flush...\t");
+    let ptr = TEST_PAGE as *mut u64;
+
+    // pulls the writable translation into the TLB
+    unsafe { core::ptr::write_volatile(ptr, 1) };
+    unsafe {
+        mapper
+            .update_flags(page, PageTableFlags::PRESENT)
+            .expect("updating the test page's flags failed")
+            .ignore();
+    }
+    // a write now may or may not still go through the stale entry, the
+    // architecture allows either, so only the one after the flush is checked
+    os_practice::mem::flush_page(page.start_address());
+    expect_pg_fault(ExpectedPgFault::WriteProtect);
+    unsafe { core::ptr::write_volatile(ptr, 2) };
+}
@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use os_practice::{exit_qemu, serial_print, serial_println};
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[panicked but did not hit ud2]");
+    exit_qemu(os_practice::QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// TEST_IDT's invalid opcode handler checks the instruction and exits QEMU
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    os_practice::interrupts::init_test();
+    serial_println!("Running 1 tests:");
+    test_ud2();
+    serial_println!("[did not raise invalid opcode]");
+    exit_qemu(os_practice::QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+fn test_ud2() {
+    serial_print!("ud2_test::test_ud2...\t");
+    os_practice::invalid_opcode();
+}
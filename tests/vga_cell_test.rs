@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+// import test_runner from lib.rs
+#![test_runner(os_practice::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    os_practice::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_practice::test_panic_handler(info)
+}
+
+use os_practice::vga_buf::{Color, ColorCode, ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[test_case]
+fn set_cell_reads_back() {
+    let code = ColorCode::new(Color::Yellow, Color::Blue);
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let (row, col) = (0, BUFFER_WIDTH - 1);
+        let prev = writer.cell(row, col);
+
+        writer.set_cell(row, col, ScreenChar::new(b'Z', code));
+        let cell = writer.cell(row, col);
+        assert_eq!(cell.ascii(), b'Z');
+        assert_eq!(cell.as_char(), 'Z');
+        assert_eq!(cell.color_code().foreground(), Color::Yellow);
+        assert_eq!(cell.color_code().background(), Color::Blue);
+
+        // a flush of the shadow doesn't put the old cell back
+        writer.write_string("x");
+        writer.flush();
+        assert_eq!(writer.cell(row, col), ScreenChar::new(b'Z', code));
+
+        writer.set_cell(row, col, prev);
+        writer.write_byte(b'\n');
+    });
+}
+
+#[test_case]
+fn cell_sees_printed_text() {
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        writer.write_string("cell");
+        writer.flush();
+        for (i, c) in "cell".bytes().enumerate() {
+            assert_eq!(writer.cell(BUFFER_HEIGHT - 1, i).ascii(), c);
+        }
+        writer.write_byte(b'\n');
+    });
+}
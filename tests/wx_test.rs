@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use os_practice::interrupts::{expect_pg_fault, ExpectedPgFault};
+use os_practice::{exit_qemu, serial_print, serial_println, QEMUExitCode};
+use x86_64::structures::paging::{mapper::TranslateResult, PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(kern_main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n\nError: {}\n", info);
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// TEST_IDT's page fault handler checks the error code and exits QEMU
+fn kern_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { os_practice::mem::init(phys_mem_offset) };
+    let updated = os_practice::mem::protect_kernel_sections(&mut mapper)
+        .expect("Kernel section protection failed");
+    serial_println!("protected {} kernel pages", updated);
+    check_segment_flags(&mapper);
+
+    // the page fault handler runs on an IST stack, which needs the TSS
+    os_practice::gdt::init();
+    os_practice::interrupts::init_test();
+    expect_pg_fault(ExpectedPgFault::WriteProtect);
+    serial_println!("Running 1 tests:");
+    write_to_text();
+    serial_println!("[did not page fault]");
+    exit_qemu(QEMUExitCode::Failure);
+    os_practice::hlt_loop();
+}
+
+// in .data, since it's mutable through a shared reference
+static DATA: AtomicU64 = AtomicU64::new(1);
+
+// every segment got its own flags, not just the first one
+fn check_segment_flags(mapper: &impl Translate) {
+    let flags = |addr: u64| match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:#x} isn't mapped", addr),
+    };
+
+    let text = flags(os_practice::hlt_loop as *const () as u64);
+    assert!(
+        !text.contains(PageTableFlags::WRITABLE),
+        ".text is writable"
+    );
+    assert!(
+        !text.contains(PageTableFlags::NO_EXECUTE),
+        ".text isn't executable"
+    );
+
+    let data = flags(&DATA as *const _ as u64);
+    assert_eq!(DATA.load(Ordering::Relaxed), 1);
+    assert!(
+        data.contains(PageTableFlags::WRITABLE),
+        ".data isn't writable"
+    );
+    assert!(
+        data.contains(PageTableFlags::NO_EXECUTE),
+        ".data is executable"
+    );
+}
+
+fn write_to_text() {
+    serial_print!("wx_test::write_to_text...\t");
+    let text = os_practice::hlt_loop as *const u8 as *mut u8;
+    unsafe { core::ptr::write_volatile(text, 0xcc) };
+}